@@ -0,0 +1,60 @@
+//! Runs arbitrary chapters through scanning and splicing, checking nothing
+//! panics and that everything but the diagrams comes out as it went in.
+//!
+//! Run with `cargo +nightly fuzz run chapters`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mdbook::book::{Book, Chapter};
+use mdbook::BookItem;
+use mdbook_puml::{preview, style, Compiler, Config};
+use std::path::Path;
+
+fuzz_target!(|chapter: &str| {
+    // the outputs are never rendered, so the book needn't exist
+    let compiler = Compiler::new(
+        Path::new("/nonexistent"),
+        Path::new("src"),
+        Config::default(),
+    )
+    .unwrap();
+
+    let mut book = Book::new();
+    book.push_item(Chapter::new(
+        "Chapter",
+        chapter.to_owned(),
+        "a/chapter.md",
+        vec![],
+    ));
+    let targets = compiler.scan(&book);
+    compiler.splice(&mut book, &targets).unwrap();
+    let spliced = match &book.sections[0] {
+        BookItem::Chapter(ch) => ch.content.clone(),
+        _ => unreachable!(),
+    };
+
+    // replacing each diagram with a marker must leave the rest untouched
+    let diagrams = preview::diagrams(chapter);
+    let mut expected = String::new();
+    let mut previous = 0;
+    for diagram in &diagrams {
+        assert!(chapter.is_char_boundary(diagram.start));
+        assert!(chapter.is_char_boundary(diagram.end));
+        expected.push_str(&chapter[previous..diagram.start]);
+        expected.push('\0');
+        previous = diagram.end;
+    }
+    expected.push_str(&chapter[previous..]);
+    let marked = preview::replace_diagrams(chapter, |_| Some("\0".to_owned()));
+    assert_eq!(marked, expected);
+
+    let rendered = diagrams.iter().filter(|d| !d.ignore).count();
+    assert_eq!(targets.len(), rendered);
+    if diagrams.is_empty() {
+        assert_eq!(spliced, chapter);
+    }
+
+    let formatted = style::format_markdown(chapter);
+    assert_eq!(style::format_markdown(&formatted), formatted);
+});
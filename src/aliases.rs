@@ -0,0 +1,153 @@
+//! Short names for include files, from the `aliases` config, so diagrams can
+//! `!include <alias:c4>` rather than repeating the path of a vendored file.
+
+use crate::pure::INCLUDE_DIRECTIVES;
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const PREFIX: &str = "<alias:";
+
+/// The include files of each alias
+#[derive(Debug, Default)]
+pub(crate) struct Aliases(BTreeMap<String, PathBuf>);
+
+impl Aliases {
+    /// Aliases of files, which are relative to `dir` unless absolute
+    pub fn new(aliases: BTreeMap<String, PathBuf>, dir: &Path) -> Self {
+        Aliases(
+            aliases
+                .into_iter()
+                .map(|(alias, file)| (alias, dir.join(file)))
+                .collect(),
+        )
+    }
+
+    /// The alias each line of the source includes, if any, with the rest of its line
+    fn used<'a>(&self, source: &'a str) -> impl Iterator<Item = (&'a str, &'a str, &'a str)> {
+        source.lines().filter_map(|line| {
+            let (directive, arg) = line.trim().split_once(char::is_whitespace)?;
+            if !INCLUDE_DIRECTIVES.contains(&directive) {
+                return None;
+            }
+            let rest = arg.trim().strip_prefix(PREFIX)?;
+            let end = rest.find('>')?;
+            Some((directive, &rest[..end], &rest[end + 1..]))
+        })
+    }
+
+    /// Identifies a diagram's image by the files its aliases point to as well, and their
+    /// contents, so it is rendered again when they are repointed or edited. Diagrams
+    /// without aliases keep their id.
+    pub fn pin(&self, output: Uuid, source: &str) -> Uuid {
+        let mut used = self.used(source).peekable();
+        if used.peek().is_none() {
+            return output;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(output.as_bytes());
+        for (_, alias, _) in used {
+            hasher.write_u8(0xff);
+            if let Some(file) = self.0.get(alias) {
+                hasher.write(file.to_string_lossy().as_bytes());
+                hasher.write_u8(0);
+                // a missing file fails the render, which is reported then
+                if let Ok(contents) = std::fs::read(file) {
+                    hasher.write(&contents);
+                }
+            }
+        }
+
+        let lhs = hasher.finish() as u128;
+        hasher.write_u8(0);
+        let rhs = hasher.finish() as u128;
+        Uuid::from_u128(lhs << 64 | rhs)
+    }
+
+    /// The source with its aliased includes replaced by the files they point to
+    pub fn expand<'a>(&self, source: &'a str) -> Result<Cow<'a, str>> {
+        if self.used(source).next().is_none() {
+            return Ok(Cow::Borrowed(source));
+        }
+
+        let mut expanded = String::with_capacity(source.len());
+        for line in source.split_inclusive('\n') {
+            let (directive, alias, rest) = match self.used(line).next() {
+                Some(used) => used,
+                None => {
+                    expanded.push_str(line);
+                    continue;
+                }
+            };
+            let file = match self.0.get(alias) {
+                Some(file) => file,
+                None => bail!(
+                    "unknown alias `{}`, it needs defining in the `aliases` config",
+                    alias
+                ),
+            };
+            expanded.push_str(&format!("{} {}{}", directive, file.display(), rest));
+            if line.ends_with('\n') {
+                expanded.push('\n');
+            }
+        }
+        Ok(Cow::Owned(expanded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expanded() {
+        let aliases = Aliases::new(
+            BTreeMap::from([
+                ("c4".to_owned(), PathBuf::from("vendor/C4_Container.puml")),
+                ("icons".to_owned(), PathBuf::from("/opt/icons.puml")),
+            ]),
+            Path::new("/book/src"),
+        );
+
+        let source = "@startuml\n  !include <alias:c4>\n!includesub <alias:icons>!SERVER\n!include <aws/common>\n@enduml";
+        assert_eq!(
+            aliases.expand(source).unwrap(),
+            "@startuml\n!include /book/src/vendor/C4_Container.puml\n!includesub /opt/icons.puml!SERVER\n!include <aws/common>\n@enduml"
+        );
+        let plain = "@startuml\n!include <aws/common>\n@enduml\n";
+        assert!(matches!(aliases.expand(plain).unwrap(), Cow::Borrowed(_)));
+        assert!(aliases.expand("!include <alias:c5>\n").is_err());
+
+        let output = Uuid::from_u128(1);
+        assert_eq!(aliases.pin(output, plain), output);
+        let pinned = aliases.pin(output, source);
+        assert_ne!(pinned, output);
+        let repointed = Aliases::new(
+            BTreeMap::from([("c4".to_owned(), PathBuf::from("C4.puml"))]),
+            Path::new("/book/src"),
+        );
+        assert_ne!(repointed.pin(output, source), pinned);
+    }
+
+    #[test]
+    fn edited() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let aliases = Aliases::new(
+            BTreeMap::from([("c4".to_owned(), PathBuf::from("C4.puml"))]),
+            dir.path(),
+        );
+        let source = "@startuml\n!include <alias:c4>\n@enduml\n";
+        let output = Uuid::from_u128(1);
+
+        std::fs::write(dir.path().join("C4.puml"), "!define A\n").unwrap();
+        let pinned = aliases.pin(output, source);
+        assert_eq!(aliases.pin(output, source), pinned);
+        std::fs::write(dir.path().join("C4.puml"), "!define B\n").unwrap();
+        assert_ne!(aliases.pin(output, source), pinned);
+    }
+}
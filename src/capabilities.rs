@@ -0,0 +1,107 @@
+//! What plantuml can render, so unsupported diagrams fail before plantuml is
+//! started, with an error naming the problem rather than whatever plantuml
+//! makes of it. Kinds newer than this release are left to plantuml.
+
+use crate::messages::Messages;
+
+/// The kinds of diagram plantuml knows, as found by `find_kind`.
+/// `@startuml` diagrams are known by the kind they are told apart as.
+const KINDS: &[&str] = &[
+    // @startuml
+    "uml",
+    "sequence",
+    "class",
+    "deployment",
+    "usecase",
+    "state",
+    "component",
+    "object",
+    "activity",
+    // the other @start kinds
+    "board",
+    "chen",
+    "chronology",
+    "creole",
+    "ditaa",
+    "dot",
+    "ebnf",
+    "files",
+    "gantt",
+    "git",
+    "jcckit",
+    "json",
+    "latex",
+    "math",
+    "mindmap",
+    "nwdiag",
+    "regex",
+    "salt",
+    "wbs",
+    "wire",
+    "yaml",
+];
+
+/// Kinds rendered as bitmaps, and the only format they support
+const BITMAP_ONLY: &[&str] = &["ditaa", "jcckit"];
+
+/// Formats plantuml can render everything except bitmap-only kinds to
+const FORMATS: &[&str] = &["svg", "png", "txt"];
+
+/// Kinds that have an ascii art rendering
+const TEXT_KINDS: &[&str] = &["sequence", "uml"];
+
+/// Whether the kind of diagram is one this release knows plantuml has
+pub(crate) fn is_known(kind: &str) -> bool {
+    KINDS.contains(&kind)
+}
+
+/// Checks plantuml can render a kind of diagram to the format, explaining why not.
+/// Unknown kinds are assumed to render to the same formats as most do.
+pub(crate) fn check(kind: &str, format: &str, messages: &Messages) -> Result<(), String> {
+    let supported = if BITMAP_ONLY.contains(&kind) {
+        format == "png"
+    } else if format == "txt" {
+        TEXT_KINDS.contains(&kind)
+    } else {
+        FORMATS.contains(&format)
+    };
+    if !supported {
+        return Err(messages.get(
+            "unsupported-format",
+            &[("kind", &kind), ("format", &format)],
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix() {
+        let en = Messages::default();
+        assert_eq!(check("sequence", "svg", &en), Ok(()));
+        assert_eq!(check("mindmap", "png", &en), Ok(()));
+        assert_eq!(check("sequence", "txt", &en), Ok(()));
+        assert_eq!(check("ditaa", "png", &en), Ok(()));
+        assert_eq!(check("ebnf", "svg", &en), Ok(()));
+        assert_eq!(check("regex", "png", &en), Ok(()));
+        assert_eq!(check("chen", "svg", &en), Ok(()));
+        assert_eq!(
+            check("chen", "txt", &en),
+            Err("plantuml cannot render chen diagrams to txt".to_owned())
+        );
+        assert_eq!(
+            check("ditaa", "svg", &en),
+            Err("plantuml cannot render ditaa diagrams to svg".to_owned())
+        );
+        assert_eq!(
+            check("gantt", "txt", &en),
+            Err("plantuml cannot render gantt diagrams to txt".to_owned())
+        );
+        assert!(!is_known("pie"));
+        assert_eq!(check("pie", "svg", &en), Ok(()));
+        assert!(check("pie", "txt", &en).is_err());
+    }
+}
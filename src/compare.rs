@@ -0,0 +1,73 @@
+//! Visual regression checks of rendered diagrams against a baseline directory.
+//!
+//! Diagrams are compared structurally: each SVG is split into its elements
+//! and the proportion of elements that differ is reported. This catches layout
+//! and styling changes from plantuml version bumps while ignoring formatting noise.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub(crate) const REGRESSIONS: &str = "regressions.json";
+
+/// A rendered image which differs from its baseline
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Regression {
+    /// Path of the image, relative to the book src
+    pub image: PathBuf,
+    /// Path of the baseline image it was compared against
+    pub baseline: PathBuf,
+    /// Proportion of SVG elements that differ, between 0 and 1
+    pub difference: f64,
+}
+
+/// Proportion of elements which are present in only one of the two SVGs
+pub(crate) fn svg_difference(a: &str, b: &str) -> f64 {
+    let mut counts = HashMap::<&str, isize>::new();
+    let mut total = 0;
+    for element in elements(a) {
+        *counts.entry(element).or_default() += 1;
+        total += 1;
+    }
+    for element in elements(b) {
+        *counts.entry(element).or_default() -= 1;
+        total += 1;
+    }
+
+    if total == 0 {
+        return 0.0;
+    }
+    let differing: isize = counts.values().map(|c| c.abs()).sum();
+    differing as f64 / total as f64
+}
+
+fn elements(svg: &str) -> impl Iterator<Item = &str> {
+    svg.split('<')
+        .map(str::trim)
+        .filter(|e| !e.is_empty() && !e.starts_with("!--"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difference() {
+        let a = r#"<svg><rect x="1"/><text>Foo</text></svg>"#;
+        let b = r#"<svg>
+    <rect x="1"/>
+    <text>Bar</text>
+</svg>"#;
+
+        assert_eq!(svg_difference(a, a), 0.0);
+        assert_eq!(
+            svg_difference(
+                a,
+                "<svg><!-- comment --><rect x=\"1\"/><text>Foo</text></svg>"
+            ),
+            0.0
+        );
+        assert_eq!(svg_difference(a, b), 0.2);
+        assert_eq!(svg_difference(a, "<p>"), 1.0);
+    }
+}
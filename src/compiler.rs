@@ -0,0 +1,4198 @@
+use crate::aliases::Aliases;
+use crate::capabilities;
+use crate::compare::{svg_difference, Regression, REGRESSIONS};
+use crate::config::{Batch, Config, Jobs, LayoutEngine, Mode, Policies, Policy, TextFallback};
+use crate::embeds::{self, LineMap};
+use crate::errors::{
+    BackendMissing, ErrorClass, InvalidOutput, LintErrors, RenderError, RenderErrors, Unsupported,
+};
+use crate::gallery::gallery;
+use crate::index::{DiagramIndex, IndexFormat, INDEX_JSON};
+use crate::layout;
+use crate::lint::Lints;
+use crate::manifest::{write_if_changed, write_json, Manifest, CHANGES, MANIFEST};
+use crate::markdown::{
+    add_directive, find_kind, find_name, find_pumls, find_unterminated, replace_pumls,
+    scale_directive, size_directive, slugify, wrap_diagram, Attributes, Puml, PLANTUML_FENCE,
+};
+use crate::messages::Messages;
+use crate::metadata;
+use crate::observer::Observer;
+use crate::pages;
+use crate::preview::{editor_url, Format, PLANTUML_SERVER};
+use crate::probe::{self, DEFAULT_CACHE_DIR, DEFAULT_TTL};
+use crate::pure;
+#[cfg(feature = "rasterize")]
+use crate::rasterize;
+use crate::render_log::RenderLog;
+use crate::report::{self, Severity};
+use crate::resolve;
+use crate::resume::{Progress, RESUME_DIR};
+use crate::stdlib::Stdlib;
+use crate::theme::{self, DIAGRAMS_JS, DIAGRAMS_JSON, HIGHLIGHT_JS};
+use crate::try_for_each_mut;
+use anyhow::{anyhow, bail, Context, Result};
+use mdbook::book::{Book, Chapter};
+use mdbook::preprocess::PreprocessorContext;
+use mdbook::BookItem;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io::Write as _;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+pub(crate) const REL_OUTDIR: &str = "plantuml_images";
+const SVG: &str = "svg";
+const TXT: &str = "txt";
+const PNG: &str = "png";
+const PUML: &str = "puml";
+pub(crate) const PLANTUML: &str = "plantuml";
+/// name of the temporary directory in pure mode
+const PURE_TMPDIR: &str = ".plantuml-tmp";
+/// key of the pragmas that apply to every kind of diagram
+const ALL_KINDS: &str = "all";
+/// directory in the tmpdir that outputs are staged in, before syncing them to the outdir
+const STAGED: &str = "staged";
+/// most directories images can be sharded into
+const MAX_SHARD_DEPTH: usize = 4;
+/// what plantuml reports when graphviz crashes or can't be run, in lowercase
+const GRAPHVIZ_FAILURES: &[&str] = &[
+    "graphvizexception",
+    "dot executable",
+    "cannot run program \"dot",
+    "dot crashed",
+    "core dumped",
+];
+
+/// A plantuml diagram found in a chapter of the book
+#[derive(Debug, PartialEq, Clone)]
+pub struct Target {
+    /// Position of the diagram within the book, in reading order
+    pub index: usize,
+    /// Path of the chapter containing the diagram, relative to the book src
+    pub chapter: PathBuf,
+    /// Byte offset of the start of the fenced block within the chapter
+    pub start: usize,
+    /// Byte offset of the end of the fenced block within the chapter
+    pub end: usize,
+    /// Line the fenced block starts on (1-based)
+    pub line: usize,
+    /// The plantuml source
+    pub input: String,
+    /// The name given by `@start<kind> <name>`
+    pub name: Option<String>,
+    /// Anchor of the figure, derived from the name and unique within the chapter
+    pub id: Option<String>,
+    /// The kind of diagram, eg `sequence` or `mindmap`
+    pub kind: String,
+    /// Tags given with the `tags` attribute of the fenced block
+    pub tags: Vec<String>,
+    /// Alt text given with the `alt` attribute of the fenced block
+    pub alt: Option<String>,
+    /// Set by the `decorative` attribute, for images that are hidden from screen readers
+    pub decorative: bool,
+    /// Set by the `page` attribute, for images shown on a page of their own
+    pub page: bool,
+    /// Size to show the image at in HTML, from the `width` and `height` attributes,
+    /// unless it is scaled into the image instead
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// `scale` directive from the `max-width-px` and `max-height-px` attributes,
+    /// added to the source after its `@start` line
+    pub scale: Option<String>,
+    /// `!pragma` directives for the layout engine and from the config, added with the scale
+    pub pragmas: Vec<String>,
+    /// Hash of the source, used as the image filename
+    pub output: Uuid,
+    /// Image format, as passed to `plantuml -t`
+    pub output_type: &'static str,
+    /// Extension of the image file, if it isn't the format
+    pub extension: Option<String>,
+    /// How many directories, named by leading pairs of the hash, the image is stored under
+    pub shard_depth: usize,
+    /// Where the image came from, once planned
+    pub cache: CacheStatus,
+}
+
+impl Target {
+    /// The source to give plantuml
+    pub(crate) fn source(&self) -> Cow<'_, str> {
+        let source = wrap_diagram(&self.input);
+        match self.directives() {
+            Some(directives) => Cow::Owned(add_directive(&source, &directives)),
+            None => source,
+        }
+    }
+
+    /// The line of the input a line of [`Target::source`] came from, or `None` for
+    /// the lines added to it. Aliases and stdlib includes are rewritten in place,
+    /// so only the wrapping and the directives move the lines.
+    fn input_line(&self, line: usize) -> Option<usize> {
+        let wrapped = matches!(wrap_diagram(&self.input), Cow::Owned(_));
+        let start = match wrapped {
+            true => 1,
+            false => self
+                .input
+                .lines()
+                .position(|line| line.trim_start().starts_with("@start"))
+                .map_or(0, |start| start + 1),
+        };
+        let directives = self.directives().map_or(0, |d| d.lines().count());
+        let line = match line {
+            line if line > start + directives => line - directives,
+            line if line > start => return None,
+            line => line,
+        };
+        match wrapped {
+            true => Some(line - 1).filter(|&line| line > 0 && line <= self.input.lines().count()),
+            false => Some(line),
+        }
+    }
+
+    /// The lines added to the source after its `@start` line, if any
+    fn directives(&self) -> Option<String> {
+        let mut lines: Vec<_> = self
+            .pragmas
+            .iter()
+            .map(|pragma| format!("!pragma {}", pragma))
+            .collect();
+        lines.extend(self.scale.clone());
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    /// The alt text of the image, which defaults to the diagram name.
+    /// Decorative images have none.
+    pub fn alt_text(&self) -> Option<&str> {
+        if self.decorative {
+            return None;
+        }
+        self.alt.as_deref().or(self.name.as_deref())
+    }
+
+    /// Path of the rendered image, relative to the book src
+    pub fn image(&self) -> PathBuf {
+        Path::new(REL_OUTDIR).join(self.filename())
+    }
+
+    fn filename(&self) -> PathBuf {
+        self.shard()
+            .join(self.output.to_string())
+            .with_extension(self.extension())
+    }
+
+    /// The extension of the image file
+    pub fn extension(&self) -> &str {
+        self.extension.as_deref().unwrap_or(self.output_type)
+    }
+
+    /// Path of the exported diagram source, relative to the book src
+    pub fn source_file(&self) -> PathBuf {
+        Path::new(REL_OUTDIR).join(self.source_filename())
+    }
+
+    fn source_filename(&self) -> PathBuf {
+        self.shard()
+            .join(self.output.to_string())
+            .with_extension(PUML)
+    }
+
+    /// The directories the files of the target are stored under, eg `ab/cd`
+    fn shard(&self) -> PathBuf {
+        layout::shard(self.output, self.shard_depth)
+    }
+
+    /// The name plantuml gives the file it renders, without the extension
+    fn output_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.output.to_string(),
+        }
+    }
+
+    /// The image, followed by any extra blocks such as the edit link
+    fn markdown(&self, depth: usize, extras: &[String]) -> String {
+        // traverse up `depth` folders, into the image's path in the book src
+        let src = format!("{}{}", "../".repeat(depth), url_path(&self.image()));
+        let image = if self.decorative || self.width.is_some() || self.height.is_some() {
+            // markdown images can't set a role or size
+            let mut image = format!(
+                r#"<img src="{}" alt="{}""#,
+                src,
+                escape_html(self.alt_text().unwrap_or(""))
+            );
+            if let Some(width) = self.width {
+                image.push_str(&format!(r#" width="{}""#, width));
+            }
+            if let Some(height) = self.height {
+                image.push_str(&format!(r#" height="{}""#, height));
+            }
+            if self.decorative {
+                image.push_str(r#" role="presentation""#);
+            }
+            image.push('>');
+            image
+        } else {
+            format!("![{}]({})", self.alt_text().unwrap_or(""), src)
+        };
+        self.figure(image, extras)
+    }
+
+    /// The ascii art rendering in a code block, for text-only builds
+    fn text_markdown(&self, text: &str, extras: &[String]) -> String {
+        let block = format!("```text\n{}\n```", text.trim_end());
+        self.figure(block, extras)
+    }
+
+    fn figure(&self, mut body: String, extras: &[String]) -> String {
+        for extra in extras {
+            body.push_str("\n\n");
+            body.push_str(extra);
+        }
+
+        match &self.id {
+            // blank lines around the image so it is still parsed as markdown
+            Some(id) => format!("<figure id=\"{}\">\n\n{}\n\n</figure>", id, body),
+            None => body,
+        }
+    }
+}
+
+/// Where the image of a target came from
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum CacheStatus {
+    /// Rendered by this build
+    #[default]
+    Rendered,
+    /// Already in the outdir
+    Cached,
+    /// Copied from the diff base
+    DiffBase,
+    /// The last image that rendered, as the diagram failed to
+    Stale,
+}
+
+impl CacheStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Rendered => "rendered",
+            CacheStatus::Cached => "cached",
+            CacheStatus::DiffBase => "diff base",
+            CacheStatus::Stale => "stale",
+        }
+    }
+}
+
+/// Targets split by whether they still need rendering
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Plan {
+    /// Targets whose images already exist in the outdir
+    pub cached: Vec<Target>,
+    /// Targets which need to be rendered
+    pub to_render: Vec<Target>,
+}
+
+/// A chapter rendered on its own, by [`Compiler::render_chapter`]
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RenderedChapter {
+    /// The chapter with its diagrams replaced by images
+    pub content: String,
+    /// The images the chapter links to, in the outdir
+    pub images: Vec<PathBuf>,
+}
+
+/// Renders the plantuml diagrams of a book.
+///
+/// The work is split into separate phases, so build systems with their own
+/// caching and sandboxing can drive them individually:
+/// [`scan`](Self::scan) the book for diagrams, [`plan`](Self::plan) which are
+/// not yet cached, [`render`](Self::render) those, and finally
+/// [`splice`](Self::splice) the images into the book.
+///
+/// Nothing is written to disk until a diagram needs rendering, so books without
+/// diagrams, or whose diagrams are all cached, don't need a src dir on disk.
+pub struct Compiler {
+    /// the book's src dir, which the tmpdir is created in if it exists
+    src_dir: PathBuf,
+    /// where plantuml is run, created on first use
+    tmpdir: OnceLock<TempDir>,
+    /// where the other render workers run plantuml, beside the tmpdir
+    worker_dirs: Mutex<Vec<TempDir>>,
+    /// held to write into the staging dir, and exclusively to empty it
+    staging: RwLock<()>,
+    /// the chapters with expanded embeds, by path, with the content their lines map from
+    embedded: Mutex<HashMap<PathBuf, (String, LineMap)>>,
+    outdir: PathBuf,
+    /// the plantuml executable to invoke
+    command: String,
+    /// every command in `plantuml-command`, to suggest others when it can't be run
+    commands: Vec<String>,
+    /// `source-link-base` joined with the book's src dir
+    source_link_base: Option<String>,
+    /// the declared includes, if in pure mode
+    pure: Option<Vec<PathBuf>>,
+    /// the files that `<alias:...>` includes read
+    aliases: Aliases,
+    /// the vendored stdlib that `<...>` includes are read from
+    stdlib: Option<Stdlib>,
+    /// whether the stdlib and plantuml version are pinned
+    pinned: bool,
+    /// the image directory and manifest of the build to diff against
+    base: Option<(PathBuf, Manifest)>,
+    /// the baseline image directory and threshold for visual regression checks
+    compare: Option<(PathBuf, f64)>,
+    /// whether to add a gallery chapter
+    gallery: bool,
+    /// where to emit the diagram index
+    index: Vec<IndexFormat>,
+    /// whether to write `diagrams.json` and `diagrams.js` for themes
+    diagrams_json: bool,
+    diagrams_js: bool,
+    /// where to put the ascii art rendering of sequence diagrams
+    text_fallback: Option<TextFallback>,
+    /// whether diagrams are replaced by images or ascii art
+    mode: Mode,
+    /// extension of the image files, if it isn't the format
+    image_extension: Option<String>,
+    /// alt text of images without their own
+    alt_template: Option<String>,
+    /// how many directories the images are sharded into
+    shard_depth: usize,
+    /// the `<metadata>` element added to every SVG image
+    svg_metadata: Option<String>,
+    /// whether to write a PNG of each SVG image
+    png: bool,
+    /// resolution to rasterise SVG images at
+    #[cfg_attr(not(feature = "rasterize"), allow(dead_code))]
+    png_dpi: u32,
+    /// whether failed diagrams only warn
+    lenient: bool,
+    /// what each class of failure does, when not decided by `lenient`
+    policy: Policies,
+    /// whether failed diagrams reuse their previous image in lenient mode
+    stale_fallback: bool,
+    /// whether failed builds leave the outdir untouched
+    transactional: bool,
+    /// whether outputs are staged in the src dir, so interrupted renders can resume
+    resumable: bool,
+    /// most bytes the tmpdir may hold between batches
+    tmp_budget: Option<u64>,
+    /// how long to trust cached probes of the plantuml install
+    probe_ttl: Duration,
+    /// where probes of the plantuml install are cached
+    probe_cache: PathBuf,
+    /// how to group diagrams into plantuml invocations
+    batch: Batch,
+    /// how many plantuml invocations to run at once
+    jobs: usize,
+    /// whether to record render times in the manifest, and render the slowest first
+    timings: bool,
+    /// milliseconds each diagram took to render in this build
+    render_times: Mutex<HashMap<Uuid, u64>>,
+    /// flags appended to every plantuml invocation
+    extra_flags: Vec<String>,
+    /// the lint rules to check diagrams against
+    lint: Option<Lints>,
+    /// whether to copy the diagram sources next to the images
+    export_sources: bool,
+    /// whether to link figures to the online editor
+    online_editor: bool,
+    /// whether to list each chapter's diagrams in a comment at its top
+    summary_comments: bool,
+    /// the language of code blocks showing diagram sources
+    source_language: Option<String>,
+    /// whether to write the highlight.js grammar
+    highlight_js: bool,
+    /// size of SVG images to show on a page of their own
+    page_threshold: Option<u64>,
+    /// width of sequence diagrams to warn about
+    readable_width: Option<u32>,
+    /// whether to scale sequence diagrams down to the readable width
+    scale_to_readable_width: bool,
+    /// whether `width` and `height` attributes are scaled into the images, for this renderer
+    scale_sizes: bool,
+    /// `!pragma` directives for each kind of diagram, or all of them
+    pragmas: HashMap<String, Vec<String>>,
+    /// the layout engine of blocks that don't choose their own
+    layout_engine: LayoutEngine,
+    /// warnings and errors in the book's language
+    messages: Messages,
+    /// notified of the render lifecycle
+    observers: Vec<Box<dyn Observer>>,
+}
+
+impl Compiler {
+    /// Creates a compiler for the book, which writes images into `<src>/plantuml_images`
+    pub fn from_context(ctx: &PreprocessorContext) -> Result<Self> {
+        let config = Config::from_context(ctx)?;
+        let scale_sizes = config.scale_sizes_for.contains(&ctx.renderer);
+        let mut compiler = Self::new(&ctx.root, &ctx.config.book.src, config)?;
+        compiler.scale_sizes = scale_sizes;
+        Ok(compiler)
+    }
+
+    /// Creates a compiler for a book at `root`, with its src dir at `src` relative to that.
+    ///
+    /// Neither need exist, for embedders driving the phases with books that
+    /// only live in memory.
+    pub fn new(root: &Path, src: &Path, config: Config) -> Result<Self> {
+        let root = resolve_path(&std::env::current_dir()?.join(root));
+        let src_dir = resolve_src_dir(&root, src)?;
+        let outdir = src_dir.join(REL_OUTDIR);
+        let commands = config.plantuml_commands();
+        if commands.is_empty() {
+            bail!("plantuml-command is an empty list");
+        }
+        // when none can be found, the first fails to run with an error suggesting others
+        let command = resolve::resolve(&commands, &root).unwrap_or_else(|| commands[0].clone());
+
+        let source_link_base = config
+            .source_link_base
+            .map(|base| source_link_base(&base, src_dir.strip_prefix(&root).ok()));
+
+        if let Some(extension) = &config.image_extension {
+            check_extension(extension)?;
+        }
+        if config.shard_depth > MAX_SHARD_DEPTH {
+            bail!(
+                "shard-depth is {}, but can be at most {}",
+                config.shard_depth,
+                MAX_SHARD_DEPTH
+            );
+        }
+
+        if config.pure {
+            if let Some(alias) = config
+                .aliases
+                .iter()
+                .find(|(_, file)| !config.includes.contains(file))
+            {
+                bail!(
+                    "alias `{}` is not declared in `includes`, which is required in pure mode",
+                    alias.0
+                );
+            }
+            if config.render_log.is_some() {
+                bail!(
+                    "render-log can't be used in pure mode, as its times would make every build differ"
+                );
+            }
+            if config.timings {
+                bail!(
+                    "timings can't be used in pure mode, as the times in manifest.json would make every build differ"
+                );
+            }
+        }
+
+        let aliases = Aliases::new(config.aliases, &src_dir);
+        let observers = match config.render_log {
+            Some(format) => vec![Box::new(RenderLog::new(&outdir, format)) as Box<dyn Observer>],
+            None => vec![],
+        };
+
+        let base = match config.diff_base {
+            Some(dir) => {
+                let dir = root.join(dir);
+                let manifest = Manifest::read(&dir.join(MANIFEST))?;
+                Some((dir, manifest))
+            }
+            None => None,
+        };
+
+        Ok(Compiler {
+            src_dir,
+            tmpdir: OnceLock::new(),
+            worker_dirs: Mutex::default(),
+            staging: RwLock::default(),
+            embedded: Mutex::default(),
+            outdir,
+            command,
+            commands,
+            source_link_base,
+            pure: config.pure.then_some(config.includes),
+            aliases,
+            pinned: config.pinned,
+            stdlib: config
+                .stdlib
+                .map(|dir| Stdlib::new(root.join(dir)))
+                .transpose()?,
+            base,
+            compare: config
+                .compare_baseline
+                .map(|dir| (root.join(dir), config.compare_threshold)),
+            gallery: config.gallery,
+            index: config.index,
+            diagrams_json: config.diagrams_json,
+            diagrams_js: config.diagrams_js,
+            text_fallback: config.text_fallback,
+            mode: config.mode,
+            image_extension: config.image_extension,
+            alt_template: config.alt_template,
+            shard_depth: config.shard_depth,
+            svg_metadata: (!config.svg_metadata.is_empty())
+                .then(|| metadata::element(&config.svg_metadata))
+                .transpose()?,
+            png: config.png,
+            png_dpi: config.png_dpi.unwrap_or(96).max(1),
+            lenient: config.lenient,
+            policy: config.policy,
+            stale_fallback: config.stale_fallback,
+            transactional: config.transactional,
+            resumable: config.resumable,
+            tmp_budget: config.tmp_budget_mb.map(|mb| mb * 1024 * 1024),
+            // in pure mode nothing is written outside of the declared paths, so it isn't cached
+            probe_ttl: if config.pure {
+                Duration::ZERO
+            } else {
+                Duration::from_secs(config.probe_ttl.unwrap_or(DEFAULT_TTL))
+            },
+            probe_cache: root.join(
+                config
+                    .cache_dir
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR)),
+            ),
+            batch: config.batch,
+            jobs: config.jobs.map_or(1, Jobs::count),
+            timings: config.timings,
+            render_times: Mutex::default(),
+            extra_flags: config.extra_flags,
+            lint: config.lint,
+            export_sources: config.export_sources,
+            online_editor: config.online_editor,
+            summary_comments: config.summary_comments,
+            source_language: config.source_language,
+            highlight_js: config.highlight_js,
+            page_threshold: config.page_threshold,
+            readable_width: config.readable_width,
+            scale_to_readable_width: config.scale_to_readable_width,
+            // the renderer is only known from the preprocessor context
+            scale_sizes: false,
+            pragmas: config.pragmas,
+            layout_engine: config.layout_engine,
+            messages: Messages::new(config.language.as_deref(), &config.messages),
+            observers,
+        })
+    }
+
+    /// Registers an observer, to be notified as the book is scanned and rendered
+    pub fn add_observer(&mut self, observer: impl Observer + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// The directory images are written to, `<src>/plantuml_images`
+    pub fn outdir(&self) -> &Path {
+        &self.outdir
+    }
+
+    /// Expands `![[diagram.puml]]` embeds in the chapters into fenced blocks,
+    /// so they are rendered like any other diagram
+    pub fn expand_embeds(&self, book: &mut Book) -> Result<()> {
+        try_for_each_mut(&mut book.sections, &mut |section: &mut BookItem| {
+            if let BookItem::Chapter(ref mut ch) = *section {
+                let source = match &ch.source_path {
+                    Some(source) => source,
+                    None => return Ok(()),
+                };
+                let declared = self.pure.as_deref();
+                let (content, lines) =
+                    embeds::expand(&ch.content, source, &self.src_dir, declared)?;
+                if let Some(path) = &ch.path {
+                    let mut embedded = self.embedded.lock().unwrap();
+                    if lines.is_empty() {
+                        embedded.remove(path);
+                    } else {
+                        embedded.insert(path.clone(), (content.clone(), lines));
+                    }
+                }
+                ch.content = content;
+            }
+            Ok(())
+        })
+    }
+
+    /// Finds every diagram in the book that should be rendered
+    pub fn scan(&self, book: &Book) -> Vec<Target> {
+        let mut targets = vec![];
+        // the metadata is part of the image, so changing it renders them again
+        let flags: Vec<_> = self
+            .extra_flags
+            .iter()
+            .cloned()
+            .chain(self.svg_metadata.clone())
+            .collect();
+        for item in book.iter() {
+            if let BookItem::Chapter(ch) = item {
+                if let Some(path) = &ch.path {
+                    let _span = debug_span!("scan", chapter = %path.display()).entered();
+                    let max_width = self.readable_width.filter(|_| self.scale_to_readable_width);
+                    let found = targets.len();
+                    // the lines of the chapter source, from before any embeds were expanded
+                    let embedded = self.embedded.lock().unwrap();
+                    let lines = match embedded.get(path) {
+                        Some((content, lines)) if *content == ch.content => Some(lines),
+                        _ => None,
+                    };
+                    let options = ScanOptions {
+                        flags: &flags,
+                        max_sequence_width: max_width,
+                        scale_sizes: self.scale_sizes,
+                        pragmas: &self.pragmas,
+                        layout_engine: self.layout_engine,
+                        messages: &self.messages,
+                        lines,
+                    };
+                    targets.extend(scan_chapter(&ch.content, path, &options));
+                    if let Some(template) = &self.alt_template {
+                        for target in &mut targets[found..] {
+                            if target.alt.is_none() && !target.decorative {
+                                target.alt = alt_from_template(template, target, &ch.name);
+                            }
+                        }
+                    }
+                    for start in find_unterminated(&ch.content) {
+                        let line = ch.content[..start].matches('\n').count() + 1;
+                        let line = lines.map_or(line, |lines| lines.original(line));
+                        warn!(
+                            "{}:{}: {}",
+                            path.display(),
+                            line,
+                            self.messages.get("unterminated", &[])
+                        );
+                    }
+                }
+            }
+        }
+        if self.mode == Mode::TextOnly {
+            let messages = &self.messages;
+            targets.retain_mut(
+                |target| match capabilities::check(&target.kind, TXT, messages) {
+                    Ok(()) => {
+                        target.output_type = TXT;
+                        target.extension = None;
+                        true
+                    }
+                    Err(_) => {
+                        warn!(
+                            "{}:{}: {}",
+                            target.chapter.display(),
+                            target.line,
+                            messages.get("no-text-rendering", &[("kind", &target.kind)])
+                        );
+                        false
+                    }
+                },
+            );
+        }
+        for (index, target) in targets.iter_mut().enumerate() {
+            target.index = index;
+            target.output = self.aliases.pin(target.output, &target.input);
+            if let Some(stdlib) = &self.stdlib {
+                target.output = stdlib.pin(target.output, &target.input);
+            }
+            if target.output_type == SVG {
+                target.extension = self.image_extension.clone();
+            }
+            target.shard_depth = self.shard_depth;
+        }
+        for observer in &self.observers {
+            observer.on_scan(&targets);
+        }
+        targets
+    }
+
+    /// Checks the diagrams against the configured lint rules.
+    ///
+    /// Failures are logged, or returned as [`LintErrors`] when the rules are denied.
+    pub fn lint(&self, targets: &[Target]) -> Result<()> {
+        let lints = match &self.lint {
+            Some(lints) => lints,
+            None => return Ok(()),
+        };
+
+        let problems = lints.check(targets);
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if lints.deny {
+            return Err(LintErrors(problems).into());
+        }
+        for problem in &problems {
+            warn!("{}", problem);
+        }
+        Ok(())
+    }
+
+    /// Splits the targets into those already cached in the outdir and those to render
+    pub fn plan(&self, targets: Vec<Target>) -> Plan {
+        let mut plan = Plan::default();
+        let mut queued = HashSet::new();
+        // images sharded to another depth are moved where they belong, rather than rendered again
+        if self.outdir.exists() {
+            if let Err(err) = layout::migrate(&self.outdir, self.shard_depth) {
+                warn!("could not migrate {}: {:#}", self.outdir.display(), err);
+            }
+        }
+        for mut target in targets {
+            if self.outdir.join(target.filename()).exists() {
+                info!(
+                    cache = "outdir",
+                    "{} exists. skipping render", target.output
+                );
+                target.cache = CacheStatus::Cached;
+                self.observers.iter().for_each(|o| o.on_cache_hit(&target));
+                plan.cached.push(target);
+            } else if self.base_image(&target).is_some() {
+                info!(
+                    cache = "diff-base",
+                    "{} exists in the diff base. skipping render", target.output
+                );
+                target.cache = CacheStatus::DiffBase;
+                self.observers.iter().for_each(|o| o.on_cache_hit(&target));
+                plan.cached.push(target);
+            } else if !queued.insert(target.output) {
+                // the same diagram appears multiple times, it only needs one render
+                plan.cached.push(target);
+            } else {
+                plan.to_render.push(target);
+            }
+        }
+        plan
+    }
+
+    /// Renders the planned targets, returning every target that now has an image, in book order.
+    ///
+    /// Every target is attempted, and all failures are reported together as [`RenderErrors`].
+    /// In lenient mode the failures are only logged, and left out of the results.
+    pub fn render(&self, plan: Plan) -> Result<Vec<Target>> {
+        self.render_planned(plan, true)
+    }
+
+    /// Renders the planned targets. Unless the plan covers the whole book,
+    /// the images of diagrams outside of it are left in the outdir.
+    fn render_planned(&self, plan: Plan, whole_book: bool) -> Result<Vec<Target>> {
+        let _span = info_span!(
+            "render",
+            diagrams = plan.to_render.len(),
+            cached = plan.cached.len()
+        )
+        .entered();
+        let mut errors = vec![];
+        let mut failed = HashSet::new();
+        let mut renderable = vec![];
+        for target in &plan.to_render {
+            if !capabilities::is_known(&target.kind) {
+                warn!(
+                    "{}:{}: {}",
+                    target.chapter.display(),
+                    target.line,
+                    self.messages.get("unknown-kind", &[("kind", &target.kind)])
+                );
+            }
+            match capabilities::check(&target.kind, target.output_type, &self.messages) {
+                Ok(()) => renderable.push(target.clone()),
+                Err(message) => {
+                    failed.insert(target.output);
+                    let error = RenderError {
+                        chapter: target.chapter.clone(),
+                        line: target.line,
+                        error: Unsupported(message).into(),
+                    };
+                    self.observers.iter().for_each(|o| o.on_error(&error));
+                    errors.push(error);
+                }
+            }
+        }
+
+        if self.pinned && !renderable.is_empty() {
+            self.check_pinned_version()?;
+        }
+        let progress = if self.resumable && !renderable.is_empty() {
+            Some(self.start_progress(&renderable)?)
+        } else {
+            None
+        };
+        let mut batches = self.batches(&renderable);
+        if self.timings {
+            self.slowest_first(&mut batches);
+        }
+        let workers = self.jobs.min(batches.len());
+        let queue = Mutex::new(batches.into_iter());
+        // what the workers have done, which only one of them updates at a time
+        let done = Mutex::new((&mut errors, &mut failed, progress));
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..workers)
+                .map(|worker| {
+                    let (queue, done) = (&queue, &done);
+                    let span = tracing::Span::current();
+                    scope.spawn(move || -> Result<()> {
+                        let _span = span.enter();
+                        let dir = self.work_dir(worker)?;
+                        loop {
+                            let batch = queue.lock().unwrap().next();
+                            let batch = match batch {
+                                Some(batch) => batch,
+                                None => return Ok(()),
+                            };
+                            let batch_errors = self.render_batch(&batch, &dir);
+
+                            let mut done = done.lock().unwrap();
+                            let (errors, failed, progress) = &mut *done;
+                            for (target, error) in batch_errors {
+                                failed.insert(target.output);
+                                let error = RenderError {
+                                    chapter: target.chapter.clone(),
+                                    line: target.line,
+                                    error,
+                                };
+                                self.observers.iter().for_each(|o| o.on_error(&error));
+                                errors.push(error);
+                            }
+                            self.clean_tmpdir(&dir)?;
+                            if let Some(progress) = progress {
+                                for target in &batch {
+                                    progress
+                                        .finish(target.output, !failed.contains(&target.output));
+                                }
+                                progress.write(&self.src_dir.join(RESUME_DIR))?;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("render worker panicked"))
+        })?;
+        self.worker_dirs.lock().unwrap().clear();
+        // in book order, however the workers finished
+        errors.sort_by_key(|error| {
+            plan.to_render
+                .iter()
+                .position(|t| t.chapter == error.chapter && t.line == error.line)
+        });
+
+        let default = if self.lenient {
+            Policy::Warn
+        } else {
+            Policy::Error
+        };
+        let policy = |error: &RenderError| {
+            self.policy
+                .get(ErrorClass::of(&error.error))
+                .unwrap_or(default)
+        };
+        // logged even when the others fail the build, so none go unreported
+        for error in &errors {
+            match policy(error) {
+                Policy::Error => {}
+                Policy::Ignore => debug!("{}", error),
+                Policy::Warn => warn!("{}", error),
+            }
+        }
+        errors.retain(|error| policy(error) == Policy::Error);
+        if !errors.is_empty() {
+            if !self.transactional {
+                // keep what did render, so the next build doesn't redo it
+                self.sync(None)?;
+            }
+            return Err(RenderErrors(errors).into());
+        }
+
+        for target in &plan.cached {
+            if let (false, Some(image)) = (self.is_rendered(target), self.base_image(target)) {
+                let outfile = self.staged_file(target)?;
+                std::fs::copy(&image, &outfile).with_context(|| {
+                    format!("could not copy {} from the diff base", image.display())
+                })?;
+            }
+        }
+
+        let mut results = plan.cached;
+        results.extend(plan.to_render);
+        results.sort_by_key(|t| t.index);
+        if self.stale_fallback && !failed.is_empty() {
+            self.reuse_stale(&mut results, &failed);
+        }
+        results.retain(|t| !failed.contains(&t.output));
+
+        if self.text_fallback.is_some() {
+            self.render_text(&results);
+        }
+        if self.png {
+            self.render_png(&results);
+        }
+        if self.export_sources {
+            self.export_sources(&results)?;
+        }
+        let keep = results.iter().map(|t| t.output).collect();
+        self.sync(Some(&keep).filter(|_| whole_book))?;
+        if let Some(max) = self.readable_width {
+            self.check_widths(&results, max);
+        }
+
+        Ok(results)
+    }
+
+    /// Fails if plantuml isn't the version that rendered the images of the last build
+    fn check_pinned_version(&self) -> Result<()> {
+        let previous = match Manifest::read(&self.outdir.join(MANIFEST)) {
+            Ok(Manifest {
+                plantuml_version: Some(version),
+                ..
+            }) => version,
+            _ => return Ok(()),
+        };
+        let version = probe::plantuml_version(&self.command, &self.probe_cache, self.probe_ttl)?;
+        if version != previous {
+            bail!(
+                "plantuml is pinned to {:?}, which rendered the last build, but found {:?}. \
+                 Remove {} to upgrade it.",
+                previous,
+                version,
+                self.outdir.join(MANIFEST).display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Records the rendered figures in `manifest.json` in the outdir.
+    /// When diffing against a base build, the changed figures are written to `changes.json`.
+    /// The diagram index and theme metadata are written too, if enabled.
+    pub fn write_manifest(&self, results: &[Target]) -> Result<Manifest> {
+        let mut manifest = Manifest::new(results);
+        if results.is_empty() && !self.outdir.exists() {
+            // the book has never had diagrams, so leave the src dir alone
+            return Ok(manifest);
+        }
+        // plantuml is only started to ask its version if it rendered something,
+        // otherwise the images are from whichever version the last build used
+        let rendered = results.iter().any(|t| t.cache == CacheStatus::Rendered);
+        let previous = if rendered {
+            None
+        } else {
+            Manifest::read(&self.outdir.join(MANIFEST))
+                .ok()
+                .and_then(|previous| previous.plantuml_version)
+        };
+        manifest.plantuml_version = previous.or_else(|| {
+            probe::plantuml_version(&self.command, &self.probe_cache, self.probe_ttl)
+                .map_err(|err| warn!("could not determine the plantuml version: {:#}", err))
+                .ok()
+        });
+        if self.timings {
+            self.add_render_times(&mut manifest);
+        }
+        write_json(&self.outdir.join(MANIFEST), &manifest)?;
+
+        if self.index.contains(&IndexFormat::Json) {
+            write_json(&self.outdir.join(INDEX_JSON), &DiagramIndex::new(results))?;
+        }
+
+        let figures = theme::figures(results);
+        if self.diagrams_json {
+            write_json(&self.outdir.join(DIAGRAMS_JSON), &figures)?;
+        }
+        if self.diagrams_js {
+            let script = theme::script(&figures)?;
+            write_if_changed(&self.outdir.join(DIAGRAMS_JS), script.as_bytes())?;
+        }
+        if self.highlight_js {
+            write_if_changed(
+                &self.outdir.join(HIGHLIGHT_JS),
+                theme::HIGHLIGHT_GRAMMAR.as_bytes(),
+            )?;
+        }
+
+        if let Some((_, base)) = &self.base {
+            let changes = manifest.changes(base);
+            for change in &changes {
+                info!(
+                    "figure changed at {}:{}",
+                    change.chapter.display(),
+                    change.line
+                );
+            }
+            write_json(&self.outdir.join(CHANGES), &changes)?;
+        }
+
+        Ok(manifest)
+    }
+
+    /// Adds the render times of this build to the manifest, keeping the times from the
+    /// previous manifest for the figures that didn't need rendering
+    fn add_render_times(&self, manifest: &mut Manifest) {
+        let times = self.render_times.lock().unwrap();
+        let previous = Manifest::read(&self.outdir.join(MANIFEST)).unwrap_or_default();
+        for figure in &mut manifest.figures {
+            figure.render_ms = times.get(&figure.hash).copied().or_else(|| {
+                previous
+                    .figures
+                    .iter()
+                    .find(|f| f.hash == figure.hash)
+                    .and_then(|f| f.render_ms)
+            });
+        }
+    }
+
+    /// Compares the rendered images against the baseline directory, if configured.
+    /// Images that differ by more than the threshold are written to `regressions.json`.
+    pub fn compare(&self, results: &[Target]) -> Result<Vec<Regression>> {
+        let (baseline, threshold) = match &self.compare {
+            Some(compare) => compare,
+            None => return Ok(vec![]),
+        };
+
+        let mut regressions = vec![];
+        let mut seen = HashSet::new();
+        for target in results.iter().filter(|t| seen.insert(t.output)) {
+            let expected = baseline.join(target.filename());
+            let expected_svg = match std::fs::read_to_string(&expected) {
+                Ok(svg) => svg,
+                Err(_) => continue,
+            };
+            let image = self.outdir.join(target.filename());
+            let svg = std::fs::read_to_string(&image)
+                .with_context(|| format!("could not read {}", image.display()))?;
+
+            let difference = svg_difference(&expected_svg, &svg);
+            if difference > *threshold {
+                warn!(
+                    "{}:{} differs from its baseline by {:.1}%",
+                    target.chapter.display(),
+                    target.line,
+                    difference * 100.0
+                );
+                regressions.push(Regression {
+                    image: target.image(),
+                    baseline: expected,
+                    difference,
+                });
+            }
+        }
+
+        write_json(&self.outdir.join(REGRESSIONS), &regressions)?;
+        Ok(regressions)
+    }
+
+    /// Replaces the diagrams in the book with links to their rendered images,
+    /// and adds the gallery and index chapters if enabled
+    pub fn splice(&self, book: &mut Book, results: &[Target]) -> Result<()> {
+        if self.gallery {
+            let gallery = gallery(book, results);
+            book.push_item(gallery);
+        }
+        if self.index.contains(&IndexFormat::Chapter) {
+            let index = DiagramIndex::new(results).chapter(book);
+            book.push_item(index);
+        }
+        self.splice_chapters(book, results)
+    }
+
+    /// Replaces the diagrams in each chapter of the book
+    fn splice_chapters(&self, book: &mut Book, results: &[Target]) -> Result<()> {
+        let mut by_chapter = HashMap::<&Path, HashMap<usize, &Target>>::new();
+        for target in results {
+            by_chapter
+                .entry(&target.chapter)
+                .or_default()
+                .insert(target.start, target);
+        }
+
+        let empty = HashMap::new();
+        try_for_each_mut(&mut book.sections, &mut |section: &mut BookItem| {
+            if let BookItem::Chapter(ref mut ch) = *section {
+                let path = match &ch.path {
+                    Some(path) => path,
+                    None => return Ok(()),
+                };
+                let _span = debug_span!("splice", chapter = %path.display()).entered();
+                let depth = path.components().count() - 1;
+                let source = ch.source_path.as_deref().unwrap_or(path);
+                let targets = by_chapter.get(path.as_path()).unwrap_or(&empty);
+                ch.content = self.splice_chapter(&ch.content, depth, source, targets);
+                if self.summary_comments && !targets.is_empty() {
+                    ch.content = format!("{}\n\n{}", summary_comment(targets), ch.content);
+                }
+
+                // each large diagram gets a page, in the order they appear
+                let mut paged: Vec<_> = targets.values().filter(|t| self.paged(t)).collect();
+                paged.sort_by_key(|target| target.start);
+                let mut seen = HashSet::new();
+                paged.retain(|target| seen.insert(target.output));
+                let pages: Vec<_> = paged.iter().map(|t| pages::page(t, ch)).collect();
+                ch.sub_items
+                    .extend(pages.into_iter().map(BookItem::Chapter));
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs every phase over a single chapter, such as one open in an editor,
+    /// with the path of its file in the src dir.
+    ///
+    /// The chapter is rendered exactly as it would be in the book, except that the
+    /// gallery and index are left out, and the images of other chapters are kept.
+    pub fn render_chapter(&self, path: &Path, content: &str) -> Result<RenderedChapter> {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("", content.to_owned(), path, vec![]));
+
+        self.expand_embeds(&mut book)?;
+        let targets = self.scan(&book);
+        self.lint(&targets)?;
+        let plan = self.plan(targets);
+        let results = self.render_planned(plan, false)?;
+        self.splice_chapters(&mut book, &results)?;
+
+        let mut seen = HashSet::new();
+        let images = results
+            .iter()
+            .filter(|target| seen.insert(target.output))
+            .map(|target| self.outdir.join(target.filename()))
+            .collect();
+        let content = match book.sections.pop() {
+            Some(BookItem::Chapter(ch)) => ch.content,
+            _ => unreachable!("the book only has the chapter"),
+        };
+        Ok(RenderedChapter { content, images })
+    }
+
+    /// Renders a single diagram, returning the image instead of writing it anywhere.
+    ///
+    /// This is for embedders, such as live previewers, which have no use for the
+    /// outdir and its cache. The source is piped through plantuml, so nothing
+    /// touches the filesystem, except for staging the files of a pinned stdlib.
+    pub fn render_to_vec(&self, source: &str, format: Format) -> Result<Vec<u8>> {
+        if let Some(includes) = &self.pure {
+            pure::check(source, includes)?;
+        }
+
+        let source = self.aliases.expand(source)?;
+        let source = match &self.stdlib {
+            Some(stdlib) => Cow::Owned(stdlib.stage(&source, self.tmpdir()?)?),
+            None => {
+                if self.pinned {
+                    pure::check_pinned(&source)?;
+                }
+                source
+            }
+        };
+        let image = self.pipe(&source, format);
+        #[cfg(feature = "rasterize")]
+        if format == Format::Png {
+            return image.or_else(|err| {
+                debug!(
+                    "plantuml could not render a png, rasterising the svg: {:#}",
+                    err
+                );
+                let svg = self.pipe(&source, Format::Svg).map_err(|_| err)?;
+                rasterize::svg_to_png(&svg, self.png_dpi)
+            });
+        }
+        image
+    }
+
+    /// Renders the source by piping it through plantuml
+    fn pipe(&self, source: &str, format: Format) -> Result<Vec<u8>> {
+        let mut script = format!(
+            "{} -t{} -nometadata -pipe",
+            self.command,
+            format.output_type()
+        );
+        for flag in &self.extra_flags {
+            script.push(' ');
+            script.push_str(flag);
+        }
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| "could not invoke plantuml")?;
+        // written from another thread, so a large image can't fill the stdout
+        // pipe while plantuml is still being fed the source
+        let mut stdin = child.stdin.take().unwrap();
+        let source = wrap_diagram(source).into_owned();
+        let writer = std::thread::spawn(move || stdin.write_all(source.as_bytes()));
+        let output = child.wait_with_output()?;
+        let written = writer.join().expect("plantuml writer panicked");
+
+        if !output.status.success() {
+            return Err(anyhow!("{}", stderr(&output)).context("could not compile plantuml"));
+        }
+        written.with_context(|| "could not write to plantuml")?;
+        if format == Format::Svg && report::is_error_image(&String::from_utf8_lossy(&output.stdout))
+        {
+            bail!("plantuml rendered an error image");
+        }
+        Ok(output.stdout)
+    }
+
+    /// Points the failed targets at the images of their previous versions,
+    /// from the manifest the last build left in the outdir
+    fn reuse_stale(&self, results: &mut [Target], failed: &HashSet<Uuid>) {
+        let previous = match Manifest::read(&self.outdir.join(MANIFEST)) {
+            Ok(previous) => previous,
+            Err(_) => return,
+        };
+        let current = Manifest::new(results);
+
+        for (target, figure) in results.iter_mut().zip(&current.figures) {
+            if !failed.contains(&target.output) {
+                continue;
+            }
+            let stale = current
+                .previous_version(figure, &previous)
+                .filter(|f| !failed.contains(&f.hash))
+                .map(|f| Target {
+                    output: f.hash,
+                    cache: CacheStatus::Stale,
+                    ..target.clone()
+                })
+                .filter(|stale| self.outdir.join(stale.filename()).exists());
+            if let Some(stale) = stale {
+                warn!(
+                    "{}:{}: showing the last image that rendered",
+                    target.chapter.display(),
+                    target.line
+                );
+                *target = stale;
+            }
+        }
+    }
+
+    /// Records the diagrams to render in the resume dir, noting those an
+    /// interrupted render already staged
+    fn start_progress(&self, targets: &[Target]) -> Result<Progress> {
+        let dir = self.src_dir.join(RESUME_DIR);
+        let resumed = Progress::read(&dir).is_some();
+
+        let mut progress = Progress::default();
+        for target in targets {
+            if self.is_rendered(target) {
+                progress.rendered.insert(target.output);
+            } else {
+                progress.pending.insert(target.output);
+            }
+        }
+        if resumed {
+            info!(
+                "resuming an interrupted render, {} of {} diagrams are already rendered",
+                progress.rendered.len(),
+                targets.len()
+            );
+        }
+        progress.write(&dir)?;
+        Ok(progress)
+    }
+
+    /// Warns about sequence diagrams too wide to read, suggesting how to split them
+    fn check_widths(&self, results: &[Target], max: u32) {
+        for target in results.iter().filter(|t| t.kind == "sequence") {
+            let image = self.outdir.join(target.filename());
+            let width = match std::fs::read_to_string(&image)
+                .ok()
+                .and_then(|svg| svg_width(&svg))
+            {
+                Some(width) => width,
+                None => continue,
+            };
+            if width > max {
+                warn!(
+                    "{}:{}: {}",
+                    target.chapter.display(),
+                    target.line,
+                    self.messages
+                        .get("too-wide", &[("width", &width), ("max", &max)])
+                );
+            }
+        }
+    }
+
+    /// Writes the source of each diagram next to its image
+    fn export_sources(&self, results: &[Target]) -> Result<()> {
+        let mut seen = HashSet::new();
+        for target in results.iter().filter(|t| seen.insert(t.output)) {
+            let source = target.source();
+            let file = self.outdir.join(target.source_filename());
+            if let Some(dir) = file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            write_if_changed(&file, source.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Renders the ascii art versions of the sequence diagrams.
+    /// These are only a fallback, so failures don't fail the build.
+    fn render_text(&self, results: &[Target]) {
+        let mut seen = HashSet::new();
+        for target in results {
+            if target.kind != "sequence" || !seen.insert(target.output) {
+                continue;
+            }
+            let text = Target {
+                output_type: TXT,
+                extension: None,
+                ..target.clone()
+            };
+            if self.is_rendered(&text) {
+                continue;
+            }
+            if let Err(err) = self.tmpdir().and_then(|dir| self.compile(&text, dir)) {
+                warn!("could not render text fallback: {:?}", err);
+            }
+        }
+    }
+
+    /// Writes a PNG of each SVG image, rasterising the SVG when it can rather than
+    /// running plantuml again. These are only copies, so failures don't fail the build.
+    fn render_png(&self, results: &[Target]) {
+        let mut seen = HashSet::new();
+        for target in results {
+            if target.output_type != SVG || !seen.insert(target.output) {
+                continue;
+            }
+            let png = Target {
+                output_type: PNG,
+                extension: None,
+                ..target.clone()
+            };
+            if self.is_rendered(&png) {
+                continue;
+            }
+            #[cfg(feature = "rasterize")]
+            match self.rasterize(target, &png) {
+                Ok(()) => continue,
+                Err(err) => debug!(
+                    "could not rasterise {}, rendering it instead: {:#}",
+                    target.output, err
+                ),
+            }
+            if let Err(err) = self.tmpdir().and_then(|dir| self.compile(&png, dir)) {
+                warn!("could not render png: {:?}", err);
+            }
+        }
+    }
+
+    /// Stages a PNG rasterised from the target's SVG
+    #[cfg(feature = "rasterize")]
+    fn rasterize(&self, svg: &Target, png: &Target) -> Result<()> {
+        let image = self
+            .rendered_image(svg)
+            .context("the svg has not been rendered")?;
+        let svg =
+            std::fs::read(&image).with_context(|| format!("could not read {}", image.display()))?;
+        let outfile = self.staged_file(png)?;
+        std::fs::write(&outfile, rasterize::svg_to_png(&svg, self.png_dpi)?)
+            .with_context(|| format!("could not write {}", outfile.display()))
+    }
+
+    /// The ascii art version of the target, wrapped for the page
+    fn text_fallback(&self, target: &Target) -> Option<String> {
+        let placement = self.text_fallback?;
+        let text = Target {
+            output_type: TXT,
+            extension: None,
+            ..target.clone()
+        };
+        let text = std::fs::read_to_string(self.outdir.join(text.filename())).ok()?;
+        let pre = format!(
+            r#"<pre class="plantuml-text-fallback">{}</pre>"#,
+            escape_html(text.trim_end())
+        );
+
+        Some(match placement {
+            TextFallback::Noscript => format!("<noscript>{}</noscript>", pre),
+            TextFallback::Print => format!(
+                r#"<style>@media screen {{ .plantuml-print-only {{ display: none; }} }}</style>
+<div class="plantuml-print-only">{}</div>"#,
+                pre
+            ),
+        })
+    }
+
+    /// The image for this target from the diff base, if it was rendered there
+    fn base_image(&self, target: &Target) -> Option<PathBuf> {
+        let (dir, manifest) = self.base.as_ref()?;
+        let image = dir.join(target.filename());
+        (manifest.contains(target.output) && image.exists()).then_some(image)
+    }
+
+    /// Groups the targets into the plantuml invocations to render them with
+    fn batches<'a>(&self, targets: &'a [Target]) -> Vec<Vec<&'a Target>> {
+        let mut batches: Vec<Vec<&Target>> = vec![];
+        for target in targets {
+            let batch = match (self.batch, batches.last_mut()) {
+                (Batch::Chapter, Some(batch))
+                    // plantuml writes named diagrams to `<name>.svg`, so names must not clash
+                    if batch[0].chapter == target.chapter
+                        && batch.iter().all(|t| t.output_name() != target.output_name()) =>
+                {
+                    batch
+                }
+                _ => {
+                    batches.push(vec![]);
+                    batches.last_mut().unwrap()
+                }
+            };
+            batch.push(target);
+        }
+        batches
+    }
+
+    /// Renders a batch in the worker's directory, returning the diagrams that failed
+    fn render_batch<'a>(
+        &self,
+        batch: &[&'a Target],
+        dir: &Path,
+    ) -> Vec<(&'a Target, anyhow::Error)> {
+        for target in batch {
+            self.observers
+                .iter()
+                .for_each(|o| o.on_render_start(target));
+        }
+        if batch.len() > 1 {
+            let start = Instant::now();
+            match self.compile_batch(batch, dir) {
+                Ok(()) => {
+                    self.finished(batch, start.elapsed());
+                    return vec![];
+                }
+                Err(err) => debug!("batch failed, rendering individually: {:#}", err),
+            }
+        }
+
+        let mut errors = vec![];
+        for target in batch {
+            if self.is_rendered(target) {
+                continue;
+            }
+            let start = Instant::now();
+            match self.compile(target, dir) {
+                Ok(()) => self.finished(&[target], start.elapsed()),
+                Err(error) => errors.push((*target, error)),
+            }
+        }
+        errors
+    }
+
+    /// Records the render time of the diagrams, shared evenly between them
+    fn finished(&self, targets: &[&Target], elapsed: Duration) {
+        let each = elapsed / targets.len() as u32;
+        for target in targets {
+            for observer in &self.observers {
+                observer.on_render_finish(target, each);
+            }
+        }
+        if !self.timings {
+            return;
+        }
+        let mut times = self.render_times.lock().unwrap();
+        for target in targets {
+            times.insert(target.output, each.as_millis() as u64);
+        }
+    }
+
+    /// Orders the batches by how long their diagrams took to render last time, slowest first,
+    /// so the slow ones aren't started last. Longest-processing-time-first keeps the
+    /// workers busy until close to the end of the build.
+    ///
+    /// A changed diagram is expected to take as long as its previous version, and
+    /// new ones the average.
+    fn slowest_first(&self, batches: &mut [Vec<&Target>]) {
+        let previous = match Manifest::read(&self.outdir.join(MANIFEST)) {
+            Ok(previous) => previous,
+            Err(_) => return,
+        };
+        let known: Vec<u64> = previous
+            .figures
+            .iter()
+            .filter_map(|f| f.render_ms)
+            .collect();
+        if known.is_empty() {
+            return;
+        }
+        let average = known.iter().sum::<u64>() / known.len() as u64;
+
+        let targets: Vec<Target> = batches.iter().flatten().map(|t| (*t).clone()).collect();
+        let current = Manifest::new(&targets);
+        let expected: HashMap<Uuid, u64> = current
+            .figures
+            .iter()
+            .map(|figure| {
+                let time = current
+                    .previous_version(figure, &previous)
+                    .and_then(|f| f.render_ms)
+                    .unwrap_or(average);
+                (figure.hash, time)
+            })
+            .collect();
+        batches.sort_by_cached_key(|batch| {
+            Reverse(batch.iter().map(|t| expected[&t.output]).sum::<u64>())
+        });
+    }
+
+    /// The directory a render worker runs plantuml in. Named diagrams are written
+    /// to `<name>.svg` next to their input, so each worker needs its own.
+    ///
+    /// They are all directly in the src dir like the tmpdir, as plantuml resolves
+    /// relative includes from the directory of its input.
+    fn work_dir(&self, worker: usize) -> Result<PathBuf> {
+        if worker == 0 {
+            return Ok(self.tmpdir()?.to_owned());
+        }
+        let dir = self.create_tmpdir(&format!("{}-{}", PURE_TMPDIR, worker))?;
+        let path = dir.path().to_owned();
+        self.worker_dirs.lock().unwrap().push(dir);
+        Ok(path)
+    }
+
+    fn compile(&self, target: &Target, dir: &Path) -> Result<()> {
+        let span = info_span!(
+            "diagram",
+            chapter = %target.chapter.display(),
+            line = target.line,
+            output = %target.output,
+            format = target.output_type,
+            backend = "plantuml",
+            otel.status_code = tracing::field::Empty,
+        )
+        .entered();
+        let result = self.compile_diagram(target, dir);
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        result
+    }
+
+    fn compile_diagram(&self, target: &Target, dir: &Path) -> Result<()> {
+        let input = self.write_input(target, dir)?;
+
+        let inputs = [input];
+        let output = self.invoke(&inputs, target.output_type, &["-stdrpt:2"])?;
+        let stderr = stderr(&output);
+        self.report_warnings(&[target], &inputs, &stderr);
+        if !output.status.success() {
+            // the shell couldn't find or run plantuml, or java couldn't find its jar
+            if matches!(output.status.code(), Some(126 | 127)) || stderr.contains("jarfile") {
+                let hint = resolve::not_found(&self.commands);
+                return Err(BackendMissing(format!("{}; {}", stderr.trim(), hint)).into());
+            }
+            let laid_out_by_graphviz = !target.pragmas.iter().any(|p| p.starts_with("layout "));
+            if laid_out_by_graphviz && graphviz_failed(&stderr) {
+                // graphviz crashes on some graphs, which smetana can often lay out
+                warn!(
+                    "{}:{}: graphviz failed to lay out the diagram, retrying with smetana",
+                    target.chapter.display(),
+                    target.line
+                );
+                let mut retry = target.clone();
+                retry
+                    .pragmas
+                    .insert(0, LayoutEngine::Smetana.pragma().unwrap().to_owned());
+                return self.compile_diagram(&retry, dir);
+            }
+            return Err(anyhow!("{}", target.input)
+                .context(stderr)
+                .context("could not compile plantuml"));
+        }
+
+        self.collect(target, dir)
+    }
+
+    /// Renders all the targets, which share an output type, with one plantuml invocation.
+    ///
+    /// If it fails, the diagrams that plantuml's report does not blame are still
+    /// collected, so only the failed ones need to be retried.
+    fn compile_batch(&self, targets: &[&Target], dir: &Path) -> Result<()> {
+        let _span = info_span!(
+            "batch",
+            chapter = %targets[0].chapter.display(),
+            diagrams = targets.len(),
+            backend = "plantuml",
+        )
+        .entered();
+        let inputs = targets
+            .iter()
+            .map(|target| self.write_input(target, dir))
+            .collect::<Result<Vec<_>>>()?;
+
+        let output = self.invoke(&inputs, targets[0].output_type, &["-stdrpt:2"])?;
+        let stderr = stderr(&output);
+        if output.status.success() {
+            self.report_warnings(targets, &inputs, &stderr);
+            for target in targets {
+                self.collect(target, dir)?;
+            }
+            return Ok(());
+        }
+
+        let failed = report::parse(&stderr, &inputs)
+            .into_iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .map(|diagnostic| diagnostic.input)
+            .collect::<HashSet<_>>();
+        // with nothing to blame, every diagram is suspect
+        if !failed.is_empty() {
+            for (i, target) in targets.iter().enumerate() {
+                if !failed.contains(&i) {
+                    // anything that can't be collected will be retried too
+                    let _ = self.collect(target, dir);
+                }
+            }
+        }
+
+        Err(anyhow!("{}", stderr).context("could not compile plantuml"))
+    }
+
+    /// Writes the puml contents to a file in the directory
+    fn write_input(&self, target: &Target, dir: &Path) -> Result<PathBuf> {
+        if let Some(includes) = &self.pure {
+            pure::check(&target.input, includes)?;
+        }
+
+        let source = target.source();
+        let source = self.aliases.expand(&source)?;
+        let source = match &self.stdlib {
+            Some(stdlib) => Cow::Owned(stdlib.stage(&source, dir)?),
+            None => {
+                if self.pinned {
+                    pure::check_pinned(&source)?;
+                }
+                source
+            }
+        };
+        let filename = target.output.to_string();
+        let input = dir.join(Path::new(&filename).with_extension(PUML));
+        std::fs::write(&input, source.as_bytes())
+            .with_context(|| "could not create tmp puml file")?;
+        Ok(input)
+    }
+
+    /// Logs the warnings plantuml reported, against the chapter lines of the diagrams
+    fn report_warnings(&self, targets: &[&Target], inputs: &[PathBuf], stderr: &str) {
+        for diagnostic in report::parse(stderr, inputs) {
+            if diagnostic.severity != Severity::Warning {
+                continue;
+            }
+            let target = targets[diagnostic.input];
+            // the diagram source starts on the line after the fence, and
+            // lines plantuml was given that aren't in it are blamed on the fence
+            let line = diagnostic.line.and_then(|line| target.input_line(line));
+            let line = target.line + line.unwrap_or(0);
+            warn!(
+                "{}:{}: plantuml: {}",
+                target.chapter.display(),
+                line,
+                diagnostic.message
+            );
+        }
+    }
+
+    /// Executes the plantuml cli on the inputs
+    fn invoke(&self, inputs: &[PathBuf], output_type: &str, flags: &[&str]) -> Result<Output> {
+        let mut script = format!("{} -t{} -nometadata", self.command, output_type);
+        let extra = self.extra_flags.iter().map(String::as_str);
+        for flag in flags.iter().copied().chain(extra) {
+            script.push(' ');
+            script.push_str(flag);
+        }
+        for input in inputs {
+            script.push(' ');
+            script.push_str(&input.display().to_string());
+        }
+
+        Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .output()
+            .with_context(|| "could not invoke plantuml")
+    }
+
+    /// Removes the inputs and leftover outputs of the last batch from the worker's directory,
+    /// and keeps what is staged in the tmpdir within the budget
+    fn clean_tmpdir(&self, dir: &Path) -> Result<()> {
+        let tmpdir = match self.tmpdir.get() {
+            Some(tmpdir) => tmpdir.path(),
+            None => return Ok(()),
+        };
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        let budget = match self.tmp_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        // only the staged images count, not the other workers' inputs or a staged stdlib
+        let staged = tmpdir.join(STAGED);
+        let used = if staged.is_dir() {
+            dir_size(&staged)?
+        } else {
+            0
+        };
+        if used <= budget {
+            return Ok(());
+        }
+        if self.transactional {
+            bail!(
+                "the staged images take {}MB, more than tmp-budget-mb allows in transactional mode",
+                used / 1024 / 1024
+            );
+        }
+        debug!("tmpdir is over budget, moving the staged images to the outdir");
+        // once no other worker is part way through staging an image
+        let _staging = self.staging.write().unwrap();
+        self.sync(None)
+    }
+
+    /// The directory plantuml is run in, created on first use
+    fn tmpdir(&self) -> Result<&Path> {
+        if let Some(tmpdir) = self.tmpdir.get() {
+            return Ok(tmpdir.path());
+        }
+
+        let tmpdir = self.create_tmpdir(PURE_TMPDIR)?;
+        Ok(self.tmpdir.get_or_init(|| tmpdir).path())
+    }
+
+    /// A directory in the src dir to run plantuml in, at `name` in pure mode
+    fn create_tmpdir(&self, name: &str) -> Result<TempDir> {
+        let tmpdir = if self.pure.is_some() {
+            // a fixed path, so nothing random can leak into the outputs
+            let path = self.src_dir.join(name);
+            if path.exists() {
+                std::fs::remove_dir_all(&path)
+                    .with_context(|| format!("could not remove stale {}", path.display()))?;
+            }
+            tempfile::Builder::new()
+                .prefix(name)
+                .rand_bytes(0)
+                .tempdir_in(&self.src_dir)?
+        } else if self.src_dir.is_dir() {
+            TempDir::new_in(&self.src_dir)?
+        } else {
+            TempDir::new()?
+        };
+        Ok(tmpdir)
+    }
+
+    fn create_outdir(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.outdir)
+            .with_context(|| format!("could not create {}", self.outdir.display()))
+    }
+
+    /// The per-run directory outputs are staged in, created on first use
+    fn staging_dir(&self) -> Result<PathBuf> {
+        let dir = match self.staged() {
+            Some(dir) => dir,
+            None => self.tmpdir()?.join(STAGED),
+        };
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        Ok(dir)
+    }
+
+    /// Where the target's image is staged, creating its shard directories
+    fn staged_file(&self, target: &Target) -> Result<PathBuf> {
+        let file = self.staging_dir()?.join(target.filename());
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        Ok(file)
+    }
+
+    /// Whether the target's image is in the outdir, or staged for it
+    fn is_rendered(&self, target: &Target) -> bool {
+        self.rendered_image(target).is_some()
+    }
+
+    /// The target's image, staged or in the outdir
+    fn rendered_image(&self, target: &Target) -> Option<PathBuf> {
+        let filename = target.filename();
+        let staged = self.staged().map(|dir| dir.join(&filename));
+        staged
+            .into_iter()
+            .chain([self.outdir.join(&filename)])
+            .find(|image| image.exists())
+    }
+
+    /// The directory outputs are staged in, if it may exist yet
+    fn staged(&self) -> Option<PathBuf> {
+        if self.resumable {
+            return Some(self.src_dir.join(RESUME_DIR).join(STAGED));
+        }
+        self.tmpdir.get().map(|t| t.path().join(STAGED))
+    }
+
+    /// Moves the staged outputs into the outdir, one rename per file.
+    ///
+    /// When given the diagrams the book uses, the outputs of any others
+    /// are removed from the outdir.
+    fn sync(&self, keep: Option<&HashSet<Uuid>>) -> Result<()> {
+        let staged = self.staged().filter(|dir| dir.is_dir());
+        if staged.is_none() && !self.outdir.exists() {
+            return Ok(());
+        }
+        self.create_outdir()?;
+        layout::migrate(&self.outdir, self.shard_depth)?;
+
+        if let Some(staged) = staged {
+            let (files, _) = layout::sharded_files(&staged)?;
+            for file in files {
+                let outfile = self.outdir.join(&file);
+                if let Some(dir) = outfile.parent() {
+                    std::fs::create_dir_all(dir)
+                        .with_context(|| format!("could not create {}", dir.display()))?;
+                }
+                std::fs::rename(staged.join(&file), &outfile).with_context(|| {
+                    format!("could not move staged output to {}", outfile.display())
+                })?;
+            }
+        }
+        if self.resumable {
+            // everything staged is in the outdir, so there's nothing to resume
+            let _ = std::fs::remove_dir_all(self.src_dir.join(RESUME_DIR));
+        }
+
+        let keep = match keep {
+            Some(keep) => keep,
+            None => return Ok(()),
+        };
+        let (files, shards) = layout::sharded_files(&self.outdir)?;
+        for file in files {
+            // the metadata files aren't named by uuid, and are left alone
+            if matches!(layout::output_of(&file), Some(output) if !keep.contains(&output)) {
+                let path = self.outdir.join(file);
+                debug!("removing unused {}", path.display());
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("could not remove {}", path.display()))?;
+            }
+        }
+        // deepest first, so their parents can be emptied too
+        for shard in shards.iter().rev() {
+            let _ = std::fs::remove_dir(self.outdir.join(shard));
+        }
+        Ok(())
+    }
+
+    /// Moves the compiled file to staging
+    fn collect(&self, target: &Target, dir: &Path) -> Result<()> {
+        let _staging = self.staging.read().unwrap();
+        let outfile = self.staged_file(target)?;
+        let output = dir.join(format!(
+            "{}.{}",
+            target.output_name(),
+            produced_extension(target.output_type)
+        ));
+        let image = std::fs::read(&output)
+            .with_context(|| format!("could not read {}", output.display()))?;
+        if let Some(problem) = invalid_output(target.output_type, &image) {
+            let _ = std::fs::remove_file(&output);
+            let message = format!(
+                "plantuml wrote an invalid {}: {}",
+                target.output_type, problem
+            );
+            return Err(InvalidOutput(message).into());
+        }
+        if target.output_type == SVG {
+            let svg = String::from_utf8(image).expect("checked it is utf-8");
+            if report::is_error_image(&svg) {
+                let _ = std::fs::remove_file(&output);
+                bail!("plantuml rendered an error image");
+            }
+            if let Some(svg) = self
+                .svg_metadata
+                .as_ref()
+                .and_then(|element| metadata::embed(&svg, element))
+            {
+                std::fs::write(&output, svg)
+                    .with_context(|| format!("could not write {}", output.display()))?;
+            }
+        }
+        std::fs::rename(&output, &outfile).with_context(|| {
+            format!(
+                "could not move compiled file ({}) to outdir ({})",
+                output.display(),
+                outfile.display()
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// Link to the line of the chapter source file that a diagram starts on
+    fn source_link(&self, source: &Path, line: usize) -> Option<String> {
+        let base = self.source_link_base.as_ref()?;
+        Some(format!("{}{}#L{}", base, url_path(source), line))
+    }
+
+    fn splice_chapter(
+        &self,
+        s: &str,
+        depth: usize,
+        source: &Path,
+        targets: &HashMap<usize, &Target>,
+    ) -> String {
+        replace_pumls(s, |link| {
+            if link.ignore {
+                return self.shown_source(s, link);
+            }
+            match targets.get(&link.start) {
+                Some(target) if target.input == link.contents => {
+                    let text = (target.output_type == TXT)
+                        .then(|| std::fs::read_to_string(self.outdir.join(target.filename())));
+                    let mut extras = vec![];
+                    if let Some(text) = self.text_fallback(target).filter(|_| text.is_none()) {
+                        extras.push(text);
+                    }
+                    if let Some(link) = self.source_link(source, target.line) {
+                        extras.push(format!("[edit this diagram]({})", link));
+                    }
+                    if self.online_editor {
+                        let url = editor_url(PLANTUML_SERVER, &target.source());
+                        extras.push(format!("[open in the PlantUML editor]({})", url));
+                    }
+                    match text {
+                        Some(Ok(text)) => target.text_markdown(&text, &extras),
+                        Some(Err(err)) => {
+                            warn!("could not read the text rendering: {}", err);
+                            self.shown_source(s, link)
+                        }
+                        None if self.paged(target) => {
+                            target.figure(pages::thumbnail(target, depth), &extras)
+                        }
+                        None => target.markdown(depth, &extras),
+                    }
+                }
+                // not rendered, leave the block as it is
+                _ => self.shown_source(s, link),
+            }
+        })
+    }
+
+    /// Whether the target's image is shown on a page of its own
+    fn paged(&self, target: &Target) -> bool {
+        if target.output_type != SVG {
+            return false;
+        }
+        target.page
+            || self.page_threshold.is_some_and(|max| {
+                std::fs::metadata(self.outdir.join(target.filename()))
+                    .is_ok_and(|image| image.len() > max)
+            })
+    }
+
+    /// A block left in the book to show its source, kept byte for byte, info string
+    /// and all, for whatever reads the block next. Only its language can be changed.
+    fn shown_source(&self, s: &str, link: &Puml) -> String {
+        let block = &s[link.start..link.end];
+        match &self.source_language {
+            Some(language) => format!("```{}{}", language, &block[PLANTUML_FENCE.len()..]),
+            None => block.to_owned(),
+        }
+    }
+}
+
+/// A comment listing the diagrams of a chapter, and where their images came from
+fn summary_comment(targets: &HashMap<usize, &Target>) -> String {
+    let mut targets: Vec<_> = targets.values().collect();
+    targets.sort_by_key(|t| t.start);
+    let mut comment = format!("<!-- mdbook-puml: diagrams replaced: {}\n", targets.len());
+    for target in targets {
+        comment.push_str(&format!(
+            "line {}: {} ({})\n",
+            target.line,
+            target.filename().display(),
+            target.cache.as_str()
+        ));
+    }
+    comment.push_str("-->");
+    comment
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr)
+        .trim_end()
+        .to_owned()
+}
+
+/// Whether plantuml failed because graphviz did, rather than the diagram being invalid
+fn graphviz_failed(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    GRAPHVIZ_FAILURES
+        .iter()
+        .any(|failure| stderr.contains(failure))
+}
+
+/// What is wrong with an image plantuml wrote, if it is empty, or too broken to be
+/// an SVG or PNG. Truncated images are the usual cause, eg when it runs out of memory.
+fn invalid_output(output_type: &str, image: &[u8]) -> Option<&'static str> {
+    if image.is_empty() {
+        return Some("it is empty");
+    }
+    match output_type {
+        SVG => {
+            let svg = match std::str::from_utf8(image) {
+                Ok(svg) => svg.trim_end(),
+                Err(_) => return Some("it is not utf-8"),
+            };
+            if !svg.contains("<svg") {
+                Some("it has no <svg> element")
+            } else if !svg.ends_with("</svg>") && !svg.ends_with("/>") {
+                Some("it is cut short")
+            } else {
+                None
+            }
+        }
+        PNG if !image.starts_with(b"\x89PNG\r\n\x1a\n") => Some("it has no PNG signature"),
+        PNG if image.get(12..16) != Some(b"IHDR") => Some("it is cut short"),
+        _ => None,
+    }
+}
+
+/// The extension of the file plantuml writes for an output format
+fn produced_extension(output_type: &str) -> &str {
+    match output_type {
+        TXT => "atxt",
+        other => other,
+    }
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The alt text of a diagram in the chapter from the template,
+/// unless the template uses something the diagram doesn't have
+fn alt_from_template(template: &str, target: &Target, chapter: &str) -> Option<String> {
+    let tags = target.tags.join(", ");
+    let values = [
+        ("kind", Some(target.kind.as_str())),
+        ("name", target.name.as_deref()),
+        ("chapter", Some(chapter)),
+        ("tags", Some(tags.as_str()).filter(|tags| !tags.is_empty())),
+    ];
+    let mut alt = template.to_owned();
+    for (key, value) in values {
+        let placeholder = format!("{{{}}}", key);
+        if alt.contains(&placeholder) {
+            alt = alt.replace(&placeholder, value?);
+        }
+    }
+    Some(alt)
+}
+
+/// What the config changes about the diagrams found in a chapter
+struct ScanOptions<'a> {
+    /// flags the images are rendered with, which are part of their hash
+    flags: &'a [String],
+    /// width to scale sequence diagrams down to
+    max_sequence_width: Option<u32>,
+    /// whether `width` and `height` are scaled into the image, rather than set in HTML
+    scale_sizes: bool,
+    pragmas: &'a HashMap<String, Vec<String>>,
+    layout_engine: LayoutEngine,
+    messages: &'a Messages,
+    /// where the lines were before embeds were expanded
+    lines: Option<&'a LineMap>,
+}
+
+fn scan_chapter(s: &str, chapter: &Path, options: &ScanOptions) -> Vec<Target> {
+    let messages = options.messages;
+    // the ids given so far, as a name may end in the suffix given to a repeated one
+    let mut ids = HashSet::<String>::new();
+
+    find_pumls(s)
+        .filter(|link| !link.ignore)
+        .map(|link| {
+            let name = find_name(link.contents);
+            let attributes = link.attributes();
+            let tags = attributes
+                .get("tags")
+                .map(|tags| tags.split_whitespace().map(str::to_owned).collect())
+                .unwrap_or_default();
+            let id = name
+                .map(slugify)
+                .filter(|slug| !slug.is_empty())
+                .map(|slug| {
+                    let mut id = format!("fig-{}", slug);
+                    let mut n = 1;
+                    while ids.contains(&id) {
+                        n += 1;
+                        id = format!("fig-{}-{}", slug, n);
+                    }
+                    ids.insert(id.clone());
+                    id
+                });
+            let line = s[..link.start].matches('\n').count() + 1;
+            let line = options.lines.map_or(line, |lines| lines.original(line));
+            let kind = find_kind(link.contents);
+            let default_width = options.max_sequence_width.filter(|_| kind == "sequence");
+            let mut width = max_px(&attributes, "width", chapter, line, messages);
+            let mut height = max_px(&attributes, "height", chapter, line, messages);
+            let mut scale = scale_directive(
+                max_px(&attributes, "max-width-px", chapter, line, messages),
+                max_px(&attributes, "max-height-px", chapter, line, messages),
+            )
+            .or_else(|| scale_directive(default_width, None));
+            if options.scale_sizes {
+                // the exact size replaces any maximum
+                if let Some(size) = size_directive(width.take(), height.take()) {
+                    scale = Some(size);
+                }
+            }
+            let layout_engine = match attributes.get("layout-engine") {
+                Some(name) => LayoutEngine::parse(name).unwrap_or_else(|| {
+                    warn!(
+                        "{}:{}: {}",
+                        chapter.display(),
+                        line,
+                        messages.get("unknown-layout-engine", &[("name", &name)])
+                    );
+                    options.layout_engine
+                }),
+                None => options.layout_engine,
+            };
+            let pragmas = layout_engine
+                .pragma()
+                .map(str::to_owned)
+                .into_iter()
+                .chain(
+                    [ALL_KINDS, kind.as_str()]
+                        .iter()
+                        .flat_map(|kind| options.pragmas.get(*kind).into_iter().flatten())
+                        .cloned(),
+                )
+                .collect();
+
+            let mut target = Target {
+                index: 0,
+                chapter: chapter.to_owned(),
+                start: link.start,
+                end: link.end,
+                line,
+                input: link.contents.to_owned(),
+                name: name.map(str::to_owned),
+                id,
+                kind,
+                tags,
+                alt: attributes.get("alt").map(str::to_owned),
+                decorative: attributes.flag("decorative"),
+                page: attributes.flag("page"),
+                width,
+                height,
+                scale,
+                pragmas,
+                output: Uuid::nil(),
+                output_type: SVG,
+                extension: None,
+                shard_depth: 0,
+                cache: CacheStatus::Rendered,
+            };
+            // the added directives change the image, so are part of its hash
+            target.output = match target.directives() {
+                Some(directives) => {
+                    let contents = add_directive(link.contents, &directives);
+                    Puml {
+                        contents: &contents,
+                        ..link.clone()
+                    }
+                    .uuid(options.flags)
+                }
+                None => link.uuid(options.flags),
+            };
+            target
+        })
+        .collect()
+}
+
+/// Total size of the files in a directory and its subdirectories
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// The width of an svg, from the `width` of its root element
+fn svg_width(svg: &str) -> Option<u32> {
+    let start = svg.find("<svg")?;
+    let tag = &svg[start..start + svg[start..].find('>')?];
+    let width = tag.split(" width=\"").nth(1)?;
+    let digits = width
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(width.len());
+    width[..digits].parse().ok()
+}
+
+/// A size limit in pixels from the attributes, warning about invalid values
+fn max_px(
+    attributes: &Attributes,
+    key: &str,
+    chapter: &Path,
+    line: usize,
+    messages: &Messages,
+) -> Option<u32> {
+    let value = attributes.get(key)?;
+    match value.parse() {
+        Ok(px) if px > 0 => Some(px),
+        _ => {
+            warn!(
+                "{}:{}: {}",
+                chapter.display(),
+                line,
+                messages.get("invalid-size", &[("key", &key), ("value", &value)])
+            );
+            None
+        }
+    }
+}
+
+/// The src directory of the book at `root`, which may be absolute or climb out of the
+/// root with `..`. This is where the images and files relative to the src are placed.
+///
+/// Symlinks are followed when it exists, but it need not exist yet,
+/// such as for books that only live in memory. If it does it must be a directory.
+pub fn resolve_src_dir(root: &Path, src: &Path) -> Result<PathBuf> {
+    let src_dir = resolve_path(&std::env::current_dir()?.join(root).join(src));
+    match std::fs::metadata(&src_dir) {
+        Ok(metadata) if !metadata.is_dir() => bail!(
+            "the book src ({}) is not a directory, check `book.src` in book.toml",
+            src_dir.display()
+        ),
+        Ok(_) => Ok(src_dir),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(src_dir),
+        Err(err) => {
+            Err(err).with_context(|| format!("could not read the book src ({})", src_dir.display()))
+        }
+    }
+}
+
+/// Checks a configured image extension can't be mistaken for the other files in the outdir
+fn check_extension(extension: &str) -> Result<()> {
+    let valid = extension
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if extension.is_empty() || !valid {
+        bail!(
+            "invalid image-extension {:?}, it can only have letters, digits, `-` and `_`",
+            extension
+        );
+    }
+    if [PUML, TXT, "json", "js"].contains(&extension) {
+        bail!(
+            "invalid image-extension {:?}, the outdir already has .{} files",
+            extension,
+            extension
+        );
+    }
+    Ok(())
+}
+
+/// The canonical form of an absolute path, or its normalized form if it doesn't exist
+fn resolve_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| normalize(path))
+}
+
+/// Resolves the `.` and `..` components of an absolute path
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                // `..` at the root is the root
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Joins the configured `source-link-base` with the book's src directory,
+/// relative to the book root. A src outside the root can't be placed in the
+/// repository, so its links are relative to the base alone.
+fn source_link_base(base: &str, src: Option<&Path>) -> String {
+    let mut base = base.to_owned();
+    if !base.ends_with('/') {
+        base.push('/');
+    }
+    let src = match src {
+        Some(src) => url_path(src),
+        None => {
+            warn!("the book src is outside the book root, so source links can't include it");
+            String::new()
+        }
+    };
+    if !src.is_empty() {
+        base.push_str(&src);
+        base.push('/');
+    }
+    base
+}
+
+/// Formats a relative path with forward slashes, as used in urls
+pub(crate) fn url_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => c.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Change;
+    use crate::ThemeFigure;
+    use proptest::prelude::*;
+
+    /// A stand-in for the plantuml cli, which writes an empty image
+    /// using the same output naming rules
+    const STUB_PLANTUML: &str = r#"
+[ "$1" = -version ] && { echo "PlantUML version stub"; exit 0; }
+for arg; do [ "$arg" = -pipe ] && { src=$(cat); case "$src" in *FAIL*) exit 1;; esac; case "$src $*" in *SVG_ONLY*-tpng*) exit 1;; esac; echo '<svg/>'; exit 0; }; done
+for arg; do case "$arg" in -ttxt) ext=atxt;; -t*) ext="${arg#-t}";; esac; done
+for input; do
+    case "$input" in -*) continue;; esac
+    name=$(sed -n '1s/^@start[a-z]* //p' "$input")
+    [ -n "$name" ] || name=$(basename "$input" .puml)
+    case "$ext" in
+        png) printf '\211PNG\r\n\032\n\0\0\0\rIHDR' > "$(dirname "$input")/$name.$ext";;
+        *) echo '<svg/>' > "$(dirname "$input")/$name.$ext";;
+    esac
+    grep -q ERROR_IMAGE "$input" && echo '<svg><text>Syntax Error?</text></svg>' > "$(dirname "$input")/$name.$ext"
+    [ "$ext" = svg ] && grep -q BROKEN_SVG "$input" && echo '<svg><g></svg>' > "$(dirname "$input")/$name.$ext"
+    grep -q EMPTY_OUTPUT "$input" && : > "$(dirname "$input")/$name.$ext"
+    grep -q FAIL "$input" && { echo "$input:2:error:Syntax Error?" >&2; failed=1; }
+    grep -q DOT_CRASH "$input" && ! grep -q 'layout smetana' "$input" && { echo "Dot executable crashed: Segmentation fault (core dumped)" >&2; failed=1; }
+done
+echo "$@" >> "$(dirname "$0")/invocations"
+exit ${failed:-0}
+"#;
+
+    fn stub_compiler(outdir: &Path) -> (TempDir, Compiler) {
+        let bin = TempDir::new().unwrap();
+        let script = bin.path().join("plantuml.sh");
+        std::fs::write(&script, STUB_PLANTUML).unwrap();
+
+        let compiler = Compiler {
+            src_dir: outdir.to_owned(),
+            tmpdir: OnceLock::from(TempDir::new().unwrap()),
+            worker_dirs: Mutex::default(),
+            staging: RwLock::default(),
+            embedded: Mutex::default(),
+            outdir: outdir.to_owned(),
+            command: format!("sh {}", script.display()),
+            commands: vec![],
+            source_link_base: None,
+            pure: None,
+            aliases: Aliases::default(),
+            stdlib: None,
+            pinned: false,
+            base: None,
+            compare: None,
+            gallery: false,
+            index: vec![],
+            diagrams_json: false,
+            diagrams_js: false,
+            text_fallback: None,
+            mode: Mode::Images,
+            image_extension: None,
+            alt_template: None,
+            shard_depth: 0,
+            svg_metadata: None,
+            png: false,
+            png_dpi: 96,
+            lenient: false,
+            policy: Policies::default(),
+            stale_fallback: false,
+            transactional: false,
+            resumable: false,
+            tmp_budget: None,
+            probe_ttl: Duration::ZERO,
+            probe_cache: bin.path().join(DEFAULT_CACHE_DIR),
+            batch: Batch::Diagram,
+            jobs: 1,
+            timings: false,
+            render_times: Mutex::default(),
+            extra_flags: vec![],
+            lint: None,
+            export_sources: false,
+            online_editor: false,
+            summary_comments: false,
+            source_language: None,
+            highlight_js: false,
+            page_threshold: None,
+            readable_width: None,
+            scale_to_readable_width: false,
+            scale_sizes: false,
+            pragmas: HashMap::new(),
+            layout_engine: LayoutEngine::Graphviz,
+            messages: Messages::default(),
+            observers: vec![],
+        };
+        (bin, compiler)
+    }
+
+    /// Runs every phase over a book containing the single chapter
+    fn replace_all(compiler: &Compiler, s: &str, path: &str) -> String {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), path, vec![]));
+
+        let targets = compiler.scan(&book);
+        let plan = compiler.plan(targets);
+        let results = compiler.render(plan).unwrap();
+        compiler.splice(&mut book, &results).unwrap();
+
+        match book.sections.pop() {
+            Some(BookItem::Chapter(ch)) => ch.content,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn replace() {
+        env_logger::init();
+
+        let s = r#"Some random text with
+```plantuml
+@startuml Document Name
+
+UML <-> Document
+
+@enduml
+```
+
+and
+
+```rust
+let foo = "bar";
+```
+
+```plantuml
+@startuml
+Foo <-> Bar
+@enduml
+```
+
+```plantuml,ignore
+@startuml
+Foo <-> Bar
+@enduml
+```
+"#;
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+
+        let res = replace_all(&compiler, s, "a/b/chapter.md");
+
+        assert_eq!(
+            res,
+            r#"Some random text with
+<figure id="fig-document-name">
+
+![Document Name](../../plantuml_images/bd15ddc5-f769-719d-dbb5-be1228872d69.svg)
+
+</figure>
+
+and
+
+```rust
+let foo = "bar";
+```
+
+![](../../plantuml_images/3a1375f3-0f44-4b13-f722-de95a4661ce7.svg)
+
+```plantuml,ignore
+@startuml
+Foo <-> Bar
+@enduml
+```
+"#
+        );
+    }
+
+    proptest! {
+        // each case runs the stub plantuml
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn round_trips(chapter in crate::markdown::tests::chapter()) {
+            let tmp = TempDir::new().unwrap();
+            let (_bin, compiler) = stub_compiler(tmp.path());
+            let markdown = chapter.markdown(|i| chapter.block(i));
+            let res = replace_all(&compiler, &markdown, "chapter.md");
+
+            // the text is kept byte for byte, in order, and so are ignored blocks
+            let last = chapter.text.len() - 1;
+            if last == 0 {
+                prop_assert_eq!(&res, &markdown);
+                return Ok(());
+            }
+            prop_assert!(res.starts_with(&chapter.text[0]));
+            prop_assert!(res.ends_with(&chapter.text[last]));
+            let mut rest = &res[chapter.text[0].len()..res.len() - chapter.text[last].len()];
+            for (i, text) in chapter.text[1..].iter().enumerate() {
+                let mut expected = vec![];
+                if chapter.ignored(i) {
+                    expected.push(chapter.block(i));
+                }
+                if i + 1 < last {
+                    expected.push(text.clone());
+                }
+                for expected in expected {
+                    let at = rest.find(&expected);
+                    prop_assert!(at.is_some(), "{:?} is not in {:?}", expected, rest);
+                    rest = &rest[at.unwrap() + expected.len()..];
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn phases() {
+        let s = r#"```plantuml
+@startuml
+Foo <-> Bar
+@enduml
+```
+
+```plantuml
+@startuml
+Foo <-> Bar
+@enduml
+```
+
+```plantuml
+@startuml
+Bar <-> Baz
+@enduml
+```
+"#;
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+
+        let targets = compiler.scan(&book);
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[1].line, 7);
+
+        // duplicate diagrams are only rendered once
+        let plan = compiler.plan(targets.clone());
+        assert_eq!(plan.to_render.len(), 2);
+        assert_eq!(plan.cached.len(), 1);
+        compiler.render(plan).unwrap();
+
+        // everything is now cached
+        let plan = compiler.plan(targets);
+        assert_eq!(plan.to_render.len(), 0);
+        assert_eq!(plan.cached.len(), 3);
+        for target in &plan.cached {
+            assert!(tmp.path().join(target.filename()).exists());
+        }
+
+        // splicing with no results leaves the diagrams alone
+        compiler.splice(&mut book, &[]).unwrap();
+        match &book.sections[0] {
+            BookItem::Chapter(ch) => assert_eq!(ch.content, s),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn render_chapter() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.gallery = true;
+        let other = "```plantuml\n@startuml\nFoo <-> Bar\n@enduml\n```\n";
+        replace_all(&compiler, other, "other.md");
+
+        let s = "# Open\n\n```plantuml\n@startuml\nBar <-> Baz\n@enduml\n```\n\n```plantuml\n@startuml\nBar <-> Baz\n@enduml\n```\n";
+        let rendered = compiler
+            .render_chapter(Path::new("guide/open.md"), s)
+            .unwrap();
+        assert_eq!(rendered.images.len(), 1);
+        assert!(rendered.images[0].exists());
+        assert!(rendered.content.starts_with("# Open\n\n"));
+        assert_eq!(rendered.content.matches("../plantuml_images/").count(), 2);
+
+        // the other chapter's image is kept
+        let images = std::fs::read_dir(tmp.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("svg".as_ref()))
+            .count();
+        assert_eq!(images, 2);
+    }
+
+    #[test]
+    fn source_links() {
+        let s = r#"# Chapter
+
+```plantuml
+@startuml Linked
+Foo <-> Bar
+@enduml
+```
+"#;
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.source_link_base = Some(source_link_base(
+            "https://github.com/org/repo/blob/main",
+            Some(Path::new("src")),
+        ));
+
+        let res = replace_all(&compiler, s, "nested/chapter.md");
+
+        assert_eq!(
+            res,
+            r#"# Chapter
+
+<figure id="fig-linked">
+
+![Linked](../plantuml_images/25f2a141-9a8e-2981-d1db-da3338a81f67.svg)
+
+[edit this diagram](https://github.com/org/repo/blob/main/src/nested/chapter.md#L3)
+
+</figure>
+"#
+        );
+    }
+
+    #[test]
+    fn online_editor() {
+        let s = "```plantuml\n@startuml\nFoo <-> Bar\n@enduml\n```\n";
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.online_editor = true;
+
+        let res = replace_all(&compiler, s, "chapter.md");
+        let url = editor_url(PLANTUML_SERVER, "@startuml\nFoo <-> Bar\n@enduml\n");
+        assert!(url.starts_with("https://www.plantuml.com/plantuml/uml/"));
+        assert!(res.ends_with(&format!("\n\n[open in the PlantUML editor]({})\n", url)));
+    }
+
+    #[test]
+    fn source_language() {
+        let s = "```plantuml,ignore\nFoo <-> Bar\n```\n\n```plantuml\nFAIL\n```\n";
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.lenient = true;
+        compiler.source_language = Some("puml".to_owned());
+        compiler.highlight_js = true;
+
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert_eq!(
+            res,
+            "```puml,ignore\nFoo <-> Bar\n```\n\n```puml\nFAIL\n```\n"
+        );
+        compiler.write_manifest(&[]).unwrap();
+        let grammar = std::fs::read_to_string(tmp.path().join(HIGHLIGHT_JS)).unwrap();
+        assert!(grammar.contains("hljs.registerLanguage(\"plantuml\""));
+    }
+
+    #[test]
+    fn passthrough() {
+        // crlf line endings, trailing whitespace and blank lines all survive
+        let s = "```plantuml ,ignore \r\n\r\nFoo <-> Bar  \r\n\r\n```  \r\n\n```plantuml\t\nFAIL\n\n\n```";
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.lenient = true;
+        assert_eq!(replace_all(&compiler, s, "chapter.md"), s);
+
+        compiler.source_language = Some("puml".to_owned());
+        assert_eq!(
+            replace_all(&compiler, s, "chapter.md"),
+            s.replace("```plantuml", "```puml")
+        );
+    }
+
+    #[test]
+    fn diff_base() {
+        let s = "```plantuml\n@startuml\nFoo <-> Bar\n@enduml\n```\n";
+        let changed = "```plantuml\n@startuml\nFoo <-> Baz\n@enduml\n```\n";
+
+        let base = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(base.path());
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.write_manifest(&results).unwrap();
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        let manifest = Manifest::read(&base.path().join(MANIFEST)).unwrap();
+        compiler.base = Some((base.path().to_owned(), manifest));
+
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+        book.push_item(Chapter::new(
+            "Other",
+            changed.to_owned(),
+            "other.md",
+            vec![],
+        ));
+
+        // only the new diagram gets rendered, the other is copied from the base
+        let plan = compiler.plan(compiler.scan(&book));
+        assert_eq!(plan.to_render.len(), 1);
+        let results = compiler.render(plan).unwrap();
+        for target in &results {
+            assert!(tmp.path().join(target.filename()).exists());
+        }
+
+        let manifest = compiler.write_manifest(&results).unwrap();
+        assert_eq!(manifest.figures.len(), 2);
+        assert_eq!(
+            manifest.plantuml_version.as_deref(),
+            Some("PlantUML version stub")
+        );
+        let changes: Vec<Change> =
+            serde_json::from_slice(&std::fs::read(tmp.path().join(CHANGES)).unwrap()).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].chapter, Path::new("other.md"));
+        assert_eq!(changes[0].before, None);
+    }
+
+    #[test]
+    fn version_probed_on_render() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "A",
+            "```plantuml\nA -> B\n```\n".to_owned(),
+            "a.md",
+            vec![],
+        ));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        let manifest = compiler.write_manifest(&results).unwrap();
+        assert_eq!(
+            manifest.plantuml_version.as_deref(),
+            Some("PlantUML version stub")
+        );
+
+        // with everything cached, the version is the one the images were rendered with
+        let manifest = Manifest {
+            plantuml_version: Some("PlantUML version 1.2020.0".to_owned()),
+            ..manifest
+        };
+        write_json(&tmp.path().join(MANIFEST), &manifest).unwrap();
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        assert_eq!(results[0].cache, CacheStatus::Cached);
+        let manifest = compiler.write_manifest(&results).unwrap();
+        assert_eq!(
+            manifest.plantuml_version.as_deref(),
+            Some("PlantUML version 1.2020.0")
+        );
+
+        book.push_item(Chapter::new(
+            "B",
+            "```plantuml\nA -> C\n```\n".to_owned(),
+            "b.md",
+            vec![],
+        ));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        let manifest = compiler.write_manifest(&results).unwrap();
+        assert_eq!(
+            manifest.plantuml_version.as_deref(),
+            Some("PlantUML version stub")
+        );
+    }
+
+    #[test]
+    fn embedded_lines() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+        std::fs::write(
+            tmp.path().join("flow.puml"),
+            "@startuml\nA -> B\nB -> C\n@enduml\n",
+        )
+        .unwrap();
+
+        let s = "# Flows\n\n![[flow.puml]]\n\nThen\n\n```plantuml\nC -> D\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        compiler.expand_embeds(&mut book).unwrap();
+        let targets = compiler.scan(&book);
+        // the lines of the chapter as written, not as expanded
+        let lines: Vec<_> = targets.iter().map(|t| t.line).collect();
+        assert_eq!(lines, [3, 7]);
+    }
+
+    #[test]
+    fn pure_mode() {
+        let root = TempDir::new().unwrap();
+        let pure = || Config {
+            pure: true,
+            probe_ttl: Some(60),
+            ..Config::default()
+        };
+        let err = Compiler::new(
+            root.path(),
+            Path::new("src"),
+            Config {
+                timings: true,
+                ..pure()
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("timings can't be used in pure mode"));
+
+        // nothing is written to the probe cache
+        let compiler = Compiler::new(root.path(), Path::new("src"), pure()).unwrap();
+        assert_eq!(compiler.probe_ttl, Duration::ZERO);
+    }
+
+    #[test]
+    fn figure_ids() {
+        let s = r#"```plantuml
+@startuml Login Sequence
+A -> B
+@enduml
+```
+
+```plantuml
+@startuml Login Sequence
+B -> A
+@enduml
+```
+
+```plantuml
+@startuml
+A -> A
+@enduml
+```
+
+```plantuml
+@startuml Login
+A -> B
+@enduml
+```
+
+```plantuml
+@startuml Login 2
+A -> C
+@enduml
+```
+
+```plantuml
+@startuml Login
+A -> D
+@enduml
+```
+"#;
+
+        let targets = scan_chapter(
+            s,
+            Path::new("chapter.md"),
+            &ScanOptions {
+                flags: &[],
+                max_sequence_width: None,
+                scale_sizes: false,
+                pragmas: &HashMap::new(),
+                layout_engine: LayoutEngine::Graphviz,
+                messages: &Messages::default(),
+                lines: None,
+            },
+        );
+        let ids = targets.iter().map(|t| t.id.as_deref()).collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                Some("fig-login-sequence"),
+                Some("fig-login-sequence-2"),
+                None,
+                Some("fig-login"),
+                Some("fig-login-2"),
+                Some("fig-login-3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn gallery_chapter() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.gallery = true;
+
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Auth",
+            "```plantuml\n@startuml Login\nA -> B\n@enduml\n```\n".to_owned(),
+            "auth/login.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Misc",
+            "```plantuml\n@startuml\nA -> C\n@enduml\n```\n".to_owned(),
+            "misc.md",
+            vec![],
+        ));
+
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.splice(&mut book, &results).unwrap();
+
+        let gallery = match book.sections.last() {
+            Some(BookItem::Chapter(ch)) => ch,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            gallery.path.as_deref(),
+            Some(Path::new("plantuml_gallery.md"))
+        );
+        assert!(gallery.content.contains(&format!(
+            r#"<a href="auth/login.html#fig-login"><img src="plantuml_images/{}.svg" alt="Login"></a>"#,
+            results[0].output
+        )));
+        assert!(gallery
+            .content
+            .contains(r#"<figcaption><a href="misc.html">Untitled</a> (Misc)</figcaption>"#));
+    }
+
+    #[test]
+    fn index_chapter() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.index = vec![IndexFormat::Chapter, IndexFormat::Json];
+
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Auth",
+            "```plantuml tags=\"auth security\"\n@startuml Login\nA -> B\n@enduml\n```\n"
+                .to_owned(),
+            "auth.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Model",
+            "```plantuml,tags=auth\n@startuml\nclass User\n@enduml\n```\n".to_owned(),
+            "model.md",
+            vec![],
+        ));
+
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.write_manifest(&results).unwrap();
+        compiler.splice(&mut book, &results).unwrap();
+
+        let index = match book.sections.last() {
+            Some(BookItem::Chapter(ch)) => ch,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            index.content,
+            r#"# Diagram Index
+
+## By kind
+
+### class
+
+- [Untitled](model.md) (Model)
+
+### sequence
+
+- [Login](auth.md#fig-login) (Auth)
+
+## By tag
+
+### auth
+
+- [Login](auth.md#fig-login) (Auth)
+- [Untitled](model.md) (Model)
+
+### security
+
+- [Login](auth.md#fig-login) (Auth)
+"#
+        );
+
+        let json: DiagramIndex =
+            serde_json::from_slice(&std::fs::read(tmp.path().join(INDEX_JSON)).unwrap()).unwrap();
+        assert_eq!(json.tags["auth"].len(), 2);
+    }
+
+    #[test]
+    fn theme_metadata() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.diagrams_json = true;
+        compiler.diagrams_js = true;
+
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Auth",
+            "```plantuml\n@startuml Login\nA -> B\n@enduml\n```\n".to_owned(),
+            "auth/login.md",
+            vec![],
+        ));
+
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.write_manifest(&results).unwrap();
+
+        let json = std::fs::read_to_string(tmp.path().join(DIAGRAMS_JSON)).unwrap();
+        let figures: Vec<ThemeFigure> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            figures,
+            vec![ThemeFigure {
+                id: Some("fig-login".to_owned()),
+                chapter: "auth/login.html".to_owned(),
+                caption: Some("Login".to_owned()),
+                image: format!("plantuml_images/{}.svg", results[0].output),
+                kind: "sequence".to_owned(),
+                tags: vec![],
+            }]
+        );
+
+        let js = std::fs::read_to_string(tmp.path().join(DIAGRAMS_JS)).unwrap();
+        assert!(js.contains("window.plantumlDiagrams = [{"));
+    }
+
+    #[test]
+    fn text_fallback() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.text_fallback = Some(TextFallback::Noscript);
+
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n\n```plantuml\n@startuml\nclass A\n@enduml\n```\n";
+        let res = replace_all(&compiler, s, "chapter.md");
+
+        // the stub writes `<svg/>` for every format
+        assert_eq!(
+            res,
+            r#"![](plantuml_images/a32ce1e3-2934-6b86-43ee-00f2f5d80951.svg)
+
+<noscript><pre class="plantuml-text-fallback">&lt;svg/&gt;</pre></noscript>
+
+![](plantuml_images/c43f52cd-433d-2627-6783-4de1d2c15add.svg)
+"#
+        );
+    }
+
+    #[test]
+    fn png() {
+        let tmp = TempDir::new().unwrap();
+        let (bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.png = true;
+
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n\n```plantuml\n@startuml\nBROKEN_SVG\n@enduml\n```\n";
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert_eq!(
+            res,
+            "![](plantuml_images/a32ce1e3-2934-6b86-43ee-00f2f5d80951.svg)\n\n![](plantuml_images/e151e58f-2c85-6cac-768c-8bbfc576fe82.svg)\n"
+        );
+        let png =
+            std::fs::read(tmp.path().join("a32ce1e3-2934-6b86-43ee-00f2f5d80951.png")).unwrap();
+        assert!(tmp
+            .path()
+            .join("e151e58f-2c85-6cac-768c-8bbfc576fe82.png")
+            .exists());
+
+        // the stub's svg is rasterised, the broken one needs plantuml
+        let invocations = std::fs::read_to_string(bin.path().join("invocations")).unwrap();
+        let pngs = invocations.lines().filter(|l| l.contains("-tpng")).count();
+        if cfg!(feature = "rasterize") {
+            assert!(png.starts_with(b"\x89PNG"));
+            assert_eq!(pngs, 1);
+        } else {
+            assert_eq!(pngs, 2);
+        }
+    }
+
+    #[test]
+    fn decorative() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+
+        let s = "```plantuml,decorative=true\n@startuml Divider\nA -> B\n@enduml\n```\n";
+        let res = replace_all(&compiler, s, "guide/chapter.md");
+        assert_eq!(
+            res,
+            r#"<figure id="fig-divider">
+
+<img src="../plantuml_images/1577f928-0e65-d261-e36f-10f2bcc2270c.svg" alt="" role="presentation">
+
+</figure>
+"#
+        );
+    }
+
+    #[test]
+    fn pages() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.page_threshold = Some(1 << 20);
+
+        let s = "```plantuml,page\n@startuml Overview\nA -> B\n@enduml\n```\n\n```plantuml\n@startuml\nB -> C\n@enduml\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Guide",
+            s.to_owned(),
+            "guide/index.md",
+            vec![],
+        ));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.splice(&mut book, &results).unwrap();
+
+        let ch = match &book.sections[0] {
+            BookItem::Chapter(ch) => ch,
+            _ => unreachable!(),
+        };
+        let page = format!("plantuml_pages/{}.html", results[0].output);
+        assert!(ch.content.starts_with(&format!(
+            r#"<figure id="fig-overview">
+
+<a href="../{}"><img src="../plantuml_images/{}.svg" alt="Overview" loading="lazy""#,
+            page, results[0].output
+        )));
+        assert!(ch.content.contains("![](../plantuml_images/"));
+        assert_eq!(ch.sub_items.len(), 1);
+        let page = match &ch.sub_items[0] {
+            BookItem::Chapter(page) => page,
+            _ => unreachable!(),
+        };
+        assert_eq!(page.name, "Overview");
+        assert_eq!(page.parent_names, ["Guide"]);
+        assert!(page.content.contains(&format!(
+            r#"<object data="../plantuml_images/{}.svg""#,
+            results[0].output
+        )));
+        assert!(page
+            .content
+            .contains("[Back to Guide](../guide/index.html#fig-overview)"));
+
+        // every image is larger than the threshold
+        compiler.page_threshold = Some(0);
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert_eq!(res.matches("<a href=\"plantuml_pages/").count(), 2);
+    }
+
+    #[test]
+    fn alt_template() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.alt_template = Some("{kind} diagram: {name}".to_owned());
+
+        let s = "```plantuml\n@startuml Login\nA -> B\n@enduml\n```\n\n```plantuml,alt=Given\n@startmindmap Plans\n* a\n@endmindmap\n```\n\n```plantuml\n@startuml\nA -> B\n@enduml\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Auth", s.to_owned(), "auth.md", vec![]));
+        let targets = compiler.scan(&book);
+        let alts: Vec<_> = targets.iter().map(|t| t.alt_text()).collect();
+        assert_eq!(alts, [Some("sequence diagram: Login"), Some("Given"), None]);
+
+        compiler.alt_template = Some("Diagram from {chapter} ({tags})".to_owned());
+        let s = "```plantuml,tags=\"auth login\"\nA -> B\n```\n\n```plantuml\nA -> B\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Auth", s.to_owned(), "auth.md", vec![]));
+        let targets = compiler.scan(&book);
+        assert_eq!(
+            targets[0].alt_text(),
+            Some("Diagram from Auth (auth, login)")
+        );
+        assert_eq!(targets[1].alt_text(), None);
+    }
+
+    #[test]
+    fn sizes() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+
+        let s = "```plantuml,width=300\n@startuml Login\nA -> B\n@enduml\n```\n";
+        let html = replace_all(&compiler, s, "chapter.md");
+        assert!(html.starts_with("<figure id=\"fig-login\">\n\n<img src=\"plantuml_images/"));
+        assert!(html.contains(r#".svg" alt="Login" width="300">"#));
+
+        // print renderers get the size in the image instead
+        compiler.scale_sizes = true;
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+        let targets = compiler.scan(&book);
+        assert_eq!(targets[0].scale.as_deref(), Some("scale 300 width"));
+        assert_eq!(targets[0].width, None);
+        let print = replace_all(&compiler, s, "chapter.md");
+        assert!(print.contains("![Login](plantuml_images/"));
+        assert_ne!(print, html);
+    }
+
+    #[test]
+    fn svg_metadata() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        let mut book = Book::new();
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n";
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+        let plain = compiler.scan(&book)[0].output;
+
+        let terms =
+            std::collections::BTreeMap::from([("license".to_owned(), "CC-BY-4.0".to_owned())]);
+        compiler.svg_metadata = Some(metadata::element(&terms).unwrap());
+        let targets = compiler.scan(&book);
+        assert_ne!(targets[0].output, plain);
+
+        let results = compiler.render(compiler.plan(targets)).unwrap();
+        let svg = std::fs::read_to_string(tmp.path().join(results[0].filename())).unwrap();
+        assert!(svg.starts_with("<svg><metadata>"));
+        assert!(svg.contains("<dcterms:license>CC-BY-4.0</dcterms:license>"));
+    }
+
+    #[test]
+    fn observer() {
+        #[derive(Clone, Default)]
+        struct Events(std::sync::Arc<Mutex<Vec<String>>>);
+        impl Observer for Events {
+            fn on_scan(&self, targets: &[Target]) {
+                let event = format!("scan {}", targets.len());
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_cache_hit(&self, target: &Target) {
+                let event = format!("cached {}", target.line);
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_render_start(&self, target: &Target) {
+                let event = format!("start {}", target.line);
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_render_finish(&self, target: &Target, _elapsed: Duration) {
+                let event = format!("finish {}", target.line);
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_error(&self, error: &RenderError) {
+                let event = format!("error {}", error.line);
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.lenient = true;
+        let events = Events::default();
+        compiler.add_observer(events.clone());
+
+        let s = "```plantuml\nA -> B\n```\n\n```plantuml\nFAIL\n```\n";
+        replace_all(&compiler, s, "chapter.md");
+        replace_all(&compiler, s, "chapter.md");
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            [
+                "scan 2", "start 1", "finish 1", "start 5", "error 5", // first build
+                "scan 2", "cached 1", "start 5", "error 5", // second build
+            ]
+        );
+    }
+
+    #[test]
+    fn policies() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        let s = "```plantuml\nA -> B\n```\n\n```plantuml\nFAIL\n```\n";
+        let render = |compiler: &Compiler| {
+            let mut book = Book::new();
+            book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+            compiler.render(compiler.plan(compiler.scan(&book)))
+        };
+
+        let errors = render(&compiler).unwrap_err();
+        let errors = errors.downcast_ref::<RenderErrors>().unwrap();
+        assert_eq!(ErrorClass::of(&errors.0[0].error), ErrorClass::SyntaxError);
+
+        // syntax errors only warn, and the diagram is left as a code block
+        compiler.policy.syntax_error = Some(Policy::Warn);
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert!(res.ends_with("```plantuml\nFAIL\n```\n"));
+
+        // a missing plantuml still fails the build, unless it's ignored
+        compiler.command = "mdbook-puml-missing-plantuml".to_owned();
+        let errors = render(&compiler).unwrap_err();
+        let errors = errors.downcast_ref::<RenderErrors>().unwrap();
+        assert_eq!(
+            ErrorClass::of(&errors.0[0].error),
+            ErrorClass::BackendMissing
+        );
+        compiler.lenient = true;
+        compiler.policy.backend_missing = Some(Policy::Error);
+        assert!(render(&compiler).is_err());
+        compiler.policy.backend_missing = Some(Policy::Ignore);
+        assert!(render(&compiler).is_ok());
+    }
+
+    #[test]
+    fn invalid_outputs() {
+        assert_eq!(invalid_output(SVG, b"<svg/>\n"), None);
+        assert_eq!(invalid_output(SVG, b""), Some("it is empty"));
+        assert_eq!(invalid_output(SVG, b"<svg><g>"), Some("it is cut short"));
+        assert_eq!(
+            invalid_output(SVG, b"<html/>"),
+            Some("it has no <svg> element")
+        );
+        assert_eq!(invalid_output(PNG, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), None);
+        assert_eq!(
+            invalid_output(PNG, b"\x89PNG\r\n\x1a\n"),
+            Some("it is cut short")
+        );
+        assert_eq!(
+            invalid_output(PNG, b"<svg/>"),
+            Some("it has no PNG signature")
+        );
+        assert_eq!(invalid_output(TXT, b"A -> B"), None);
+
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        let s = "```plantuml\nEMPTY_OUTPUT\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+        let errors = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap_err();
+        let errors = errors.downcast_ref::<RenderErrors>().unwrap();
+        assert_eq!(
+            ErrorClass::of(&errors.0[0].error),
+            ErrorClass::InvalidOutput
+        );
+
+        // the broken image is never published, even when it only warns
+        compiler.policy.invalid_output = Some(Policy::Warn);
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert_eq!(res, s);
+        let published = std::fs::read_dir(tmp.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension().is_some_and(|ext| ext == SVG));
+        assert!(!published);
+    }
+
+    #[test]
+    fn newer_kinds() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+
+        // named diagrams are rendered to `<name>.svg`, whatever their kind
+        let s = "```plantuml\n@startebnf Grammar\nrule = \"a\";\n@endebnf\n```\n\n```plantuml\n@startregex\n[a-z]+\n@endregex\n```\n\n```plantuml\n@startchen Movies\nentity Movie {\n}\n@endchen\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+        let targets = compiler.scan(&book);
+        let kinds = targets
+            .iter()
+            .map(|t| (t.kind.as_str(), t.id.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ("ebnf", Some("fig-grammar")),
+                ("regex", None),
+                ("chen", Some("fig-movies"))
+            ]
+        );
+
+        let results = compiler.render(compiler.plan(targets)).unwrap();
+        assert_eq!(results.len(), 3);
+        for target in &results {
+            assert!(tmp.path().join(target.filename()).exists());
+        }
+    }
+
+    #[test]
+    fn text_only() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.mode = Mode::TextOnly;
+
+        let s = "```plantuml\n@startuml Login\nA -> B\n@enduml\n```\n\n```plantuml\n@startuml\nclass A\n@enduml\n```\n";
+        let res = replace_all(&compiler, s, "chapter.md");
+
+        // the stub writes `<svg/>` for every format, and class diagrams have no text rendering
+        assert_eq!(
+            res,
+            "<figure id=\"fig-login\">\n\n```text\n<svg/>\n```\n\n</figure>\n\n```plantuml\n@startuml\nclass A\n@enduml\n```\n"
+        );
+        let images = std::fs::read_dir(tmp.path())
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().path().extension().map(|e| e.to_owned()))
+            .collect::<Vec<_>>();
+        assert_eq!(images, ["txt"]);
+    }
+
+    #[test]
+    fn image_extension() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.image_extension = Some("svgz".to_owned());
+
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n";
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert_eq!(
+            res,
+            "![](plantuml_images/a32ce1e3-2934-6b86-43ee-00f2f5d80951.svgz)\n"
+        );
+        assert!(tmp
+            .path()
+            .join("a32ce1e3-2934-6b86-43ee-00f2f5d80951.svgz")
+            .exists());
+
+        assert!(check_extension("svg_min").is_ok());
+        assert!(check_extension("").is_err());
+        assert!(check_extension("svg.gz").is_err());
+        assert!(check_extension("../svg").is_err());
+        assert!(check_extension("puml").is_err());
+    }
+
+    #[test]
+    fn summary_comments() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.summary_comments = true;
+
+        let s = "# A\n\n```plantuml\n@startuml\nA -> B\n@enduml\n```\n";
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert_eq!(
+            res,
+            "<!-- mdbook-puml: diagrams replaced: 1
+line 3: a32ce1e3-2934-6b86-43ee-00f2f5d80951.svg (rendered)
+-->
+
+# A
+
+![](plantuml_images/a32ce1e3-2934-6b86-43ee-00f2f5d80951.svg)
+"
+        );
+
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert!(res.starts_with("<!-- mdbook-puml: diagrams replaced: 1\nline 3: a32ce1e3-2934-6b86-43ee-00f2f5d80951.svg (cached)\n-->"));
+
+        // chapters without diagrams are left alone
+        assert_eq!(replace_all(&compiler, "# B\n", "chapter.md"), "# B\n");
+    }
+
+    #[test]
+    fn aggregate_errors() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.command = "false".to_owned();
+
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n\n```plantuml\n@startuml\nA -> C\n@enduml\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        book.push_item(Chapter::new("B", s.to_owned(), "b.md", vec![]));
+
+        // every failure is reported, not just the first
+        let err = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap_err();
+        let errors = err.downcast_ref::<RenderErrors>().unwrap();
+        assert_eq!(errors.0.len(), 2);
+        assert_eq!(errors.0[0].chapter, Path::new("a.md"));
+        assert_eq!(errors.0[1].line, 7);
+
+        // lenient mode leaves the diagrams as they are
+        compiler.lenient = true;
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        assert!(results.is_empty());
+        compiler.splice(&mut book, &results).unwrap();
+        match &book.sections[1] {
+            BookItem::Chapter(ch) => assert_eq!(ch.content, s),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn jobs() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.jobs = 3;
+        compiler.timings = true;
+
+        // the same name in each chapter, which workers sharing a directory would clash on
+        let s = "```plantuml\n@startuml Flow\nX -> B\n@enduml\n```\n\n```plantuml\nFAIL X\n```\n";
+        let mut book = Book::new();
+        for chapter in ["a.md", "b.md", "c.md", "d.md"] {
+            let s = s.replace('X', chapter);
+            book.push_item(Chapter::new(chapter, s, chapter, vec![]));
+        }
+
+        let err = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap_err();
+        let errors = err.downcast_ref::<RenderErrors>().unwrap();
+        let chapters: Vec<_> = errors.0.iter().map(|e| e.chapter.clone()).collect();
+        assert_eq!(
+            chapters,
+            ["a.md", "b.md", "c.md", "d.md"].map(PathBuf::from)
+        );
+
+        compiler.lenient = true;
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        for target in &results {
+            assert!(tmp.path().join(target.filename()).exists());
+        }
+        let manifest = compiler.write_manifest(&results).unwrap();
+        assert!(manifest.figures.iter().all(|f| f.render_ms.is_some()));
+
+        // every worker's directory is in the src dir, so relative includes resolve alike
+        compiler.tmpdir = OnceLock::new();
+        let dirs = [0, 2].map(|worker| compiler.work_dir(worker).unwrap());
+        assert_eq!(dirs[0].parent(), Some(tmp.path()));
+        assert_eq!(dirs[1].parent(), Some(tmp.path()));
+        assert_ne!(dirs[0], dirs[1]);
+    }
+
+    #[test]
+    fn slowest_first() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+        let diagram = |body: &str| format!("```plantuml\n@startuml\n{}\n@enduml\n```\n", body);
+
+        let mut book = Book::new();
+        let s = [diagram("A -> B"), diagram("A -> C"), diagram("A -> D")].join("\n");
+        book.push_item(Chapter::new("A", s, "a.md", vec![]));
+        let mut previous = Manifest::new(&compiler.scan(&book));
+        for (figure, ms) in previous.figures.iter_mut().zip([100, 3000, 1000]) {
+            figure.render_ms = Some(ms);
+        }
+        write_json(&tmp.path().join(MANIFEST), &previous).unwrap();
+
+        // the second diagram has changed, and a new one takes the average
+        let s = [
+            diagram("A -> B"),
+            diagram("A -> C2"),
+            diagram("A -> D"),
+            diagram("A -> E"),
+        ]
+        .join("\n");
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s, "a.md", vec![]));
+        let targets = compiler.scan(&book);
+        let mut batches = compiler.batches(&targets);
+        compiler.slowest_first(&mut batches);
+        let order: Vec<_> = batches.iter().map(|batch| batch[0].index).collect();
+        assert_eq!(order, [1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn error_images() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+
+        let s = "```plantuml\n@startuml\nA -> ERROR_IMAGE\n@enduml\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+
+        // plantuml succeeded, but the image is only an error message
+        let err = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap_err();
+        let errors = err.downcast_ref::<RenderErrors>().unwrap();
+        assert_eq!(errors.0.len(), 1);
+        let files: Vec<_> = std::fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec![layout::LAYOUT]);
+    }
+
+    #[test]
+    fn batch_by_chapter() {
+        let tmp = TempDir::new().unwrap();
+        let (bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.batch = Batch::Chapter;
+        compiler.lenient = true;
+
+        let diagram = |body: &str| format!("```plantuml\n@startuml\n{}\n@enduml\n```\n", body);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "A",
+            [diagram("A -> B"), diagram("A -> C"), diagram("A -> D")].join("\n"),
+            "a.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "B",
+            [diagram("B -> C"), diagram("FAIL"), diagram("B -> D")].join("\n"),
+            "b.md",
+            vec![],
+        ));
+
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        assert_eq!(results.len(), 5);
+        assert!(!results.iter().any(|t| t.input.contains("FAIL")));
+
+        // one invocation per chapter, with only the failed diagram retried
+        let invocations = std::fs::read_to_string(bin.path().join("invocations")).unwrap();
+        let counts = invocations
+            .lines()
+            .map(|l| l.matches(".puml").count())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![3, 3, 1]);
+    }
+
+    #[test]
+    fn extra_flags() {
+        let tmp = TempDir::new().unwrap();
+        let (bin, mut compiler) = stub_compiler(tmp.path());
+
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+
+        let plain = compiler.scan(&book);
+        compiler.extra_flags = vec!["-Sshadowing=false".to_owned()];
+        let flagged = compiler.scan(&book);
+        // the flags can change the image, so it isn't the same cache entry
+        assert_ne!(plain[0].output, flagged[0].output);
+
+        compiler.render(compiler.plan(flagged)).unwrap();
+        let invocations = std::fs::read_to_string(bin.path().join("invocations")).unwrap();
+        assert!(invocations.contains("-Sshadowing=false"));
+    }
+
+    #[test]
+    fn lazy_filesystem() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("missing");
+        let mut compiler = Compiler::new(&root, Path::new("src"), Config::default()).unwrap();
+        let (_bin, stub) = stub_compiler(tmp.path());
+        compiler.command = stub.command;
+
+        // nothing to render, so nothing is created
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", "no diagrams".to_owned(), "a.md", vec![]));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.write_manifest(&results).unwrap();
+        assert!(!root.exists());
+        assert!(compiler.tmpdir.get().is_none());
+
+        // without a src dir, plantuml runs in the system temp dir
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n";
+        book.push_item(Chapter::new("B", s.to_owned(), "b.md", vec![]));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.write_manifest(&results).unwrap();
+        assert!(!compiler.tmpdir().unwrap().starts_with(&root));
+        assert!(root.join("src").join(results[0].image()).exists());
+        assert!(root.join("src").join(REL_OUTDIR).join(MANIFEST).exists());
+    }
+
+    #[test]
+    fn render_to_vec() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.png_dpi = 192;
+
+        let svg = compiler
+            .render_to_vec("@startuml\nA -> B\n@enduml\n", Format::Svg)
+            .unwrap();
+        assert_eq!(svg, b"<svg/>\n");
+        assert!(compiler
+            .render_to_vec("@startuml\nFAIL\n@enduml\n", Format::Svg)
+            .is_err());
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+
+        // pngs plantuml can't render are rasterised from the svg, which is 100px wide
+        let png = compiler.render_to_vec("@startuml\nSVG_ONLY\n@enduml\n", Format::Png);
+        #[cfg(feature = "rasterize")]
+        assert_eq!(crate::rasterize::tests::width(&png.unwrap()), 200);
+        #[cfg(not(feature = "rasterize"))]
+        assert!(png.is_err());
+    }
+
+    #[test]
+    fn export_sources() {
+        let tmp = TempDir::new().unwrap();
+        let outdir = tmp.path().join(REL_OUTDIR);
+        let (_bin, mut compiler) = stub_compiler(&outdir);
+        compiler.export_sources = true;
+
+        let s = "```plantuml\nA -> B\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+
+        let source = std::fs::read_to_string(tmp.path().join(results[0].source_file())).unwrap();
+        assert_eq!(source, "@startuml\nA -> B\n@enduml\n");
+    }
+
+    #[test]
+    fn stale_fallback() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.lenient = true;
+        compiler.stale_fallback = true;
+
+        let render = |compiler: &Compiler, body: &str| {
+            let s = format!("```plantuml\n@startuml Flow\n{}\n@enduml\n```\n", body);
+            let mut book = Book::new();
+            book.push_item(Chapter::new("A", s, "a.md", vec![]));
+            let results = compiler
+                .render(compiler.plan(compiler.scan(&book)))
+                .unwrap();
+            compiler.write_manifest(&results).unwrap();
+            results
+        };
+
+        let good = render(&compiler, "A -> B");
+        let broken = render(&compiler, "A -> FAIL");
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].output, good[0].output);
+        assert!(broken[0].input.contains("FAIL"));
+
+        // still stale on the next build, which tries to render it again
+        let broken = render(&compiler, "A -> FAIL");
+        assert_eq!(broken[0].output, good[0].output);
+    }
+
+    #[test]
+    fn transactional() {
+        let tmp = TempDir::new().unwrap();
+        let outdir = tmp.path().join("out");
+        let (_bin, mut compiler) = stub_compiler(&outdir);
+        compiler.transactional = true;
+
+        let book = |body: &str| {
+            let s = format!("```plantuml\nA -> B\n```\n\n```plantuml\n{}\n```\n", body);
+            let mut book = Book::new();
+            book.push_item(Chapter::new("A", s, "a.md", vec![]));
+            book
+        };
+
+        // one failure leaves the outdir untouched
+        let plan = compiler.plan(compiler.scan(&book("A -> FAIL")));
+        assert!(compiler.render(plan).is_err());
+        assert!(!outdir.exists());
+
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book("C -> D"))))
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        for target in &results {
+            assert!(outdir.join(target.filename()).exists());
+        }
+    }
+
+    #[test]
+    fn sync() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, compiler) = stub_compiler(tmp.path());
+        std::fs::write(tmp.path().join(MANIFEST), "{}").unwrap();
+
+        let render = |body: &str| {
+            let s = format!("```plantuml\n{}\n```\n", body);
+            let mut book = Book::new();
+            book.push_item(Chapter::new("A", s, "a.md", vec![]));
+            compiler.render(compiler.plan(compiler.scan(&book)))
+        };
+
+        let files = || {
+            let mut files: Vec<_> = std::fs::read_dir(tmp.path())
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+            files
+        };
+
+        render("A -> B").unwrap();
+        // a failed build keeps the images that did render
+        assert!(render("C -> D\n```\n\n```plantuml\nFAIL").is_err());
+        assert_eq!(files().len(), 4);
+
+        // and a successful one removes those the book no longer uses
+        let new = render("A -> C").unwrap();
+        assert_eq!(
+            files(),
+            vec![
+                layout::LAYOUT.to_owned(),
+                new[0].filename().display().to_string(),
+                MANIFEST.to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn sharded() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.shard_depth = 2;
+        compiler.export_sources = true;
+
+        let s = "```plantuml\nA -> B\n```\n";
+        let res = replace_all(&compiler, s, "guide/chapter.md");
+        let image = res
+            .strip_prefix("![](../plantuml_images/")
+            .and_then(|rest| rest.strip_suffix(")\n"))
+            .unwrap();
+        let hash = &image[6..];
+        assert_eq!(image[..6], format!("{}/{}/", &hash[..2], &hash[2..4]));
+        assert!(tmp.path().join(image).exists());
+        assert!(tmp.path().join(image).with_extension(PUML).exists());
+
+        // unsharding moves the images out of their shards, without rendering them again
+        compiler.shard_depth = 0;
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Chapter",
+            s.to_owned(),
+            "guide/chapter.md",
+            vec![],
+        ));
+        let plan = compiler.plan(compiler.scan(&book));
+        assert_eq!((plan.cached.len(), plan.to_render.len()), (1, 0));
+        replace_all(&compiler, s, "guide/chapter.md");
+        assert!(!tmp.path().join(&image[..2]).exists());
+        assert!(tmp.path().join(hash).exists());
+        assert!(tmp.path().join(hash).with_extension(PUML).exists());
+    }
+
+    #[test]
+    fn src_dirs() {
+        let tmp = TempDir::new().unwrap();
+        let tmp_dir = tmp.path().canonicalize().unwrap();
+        let root = tmp_dir.join("book");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("book.toml"), "").unwrap();
+
+        let resolve = |src: &str| resolve_src_dir(&root, Path::new(src));
+        assert_eq!(resolve("src").unwrap(), root.join("src"));
+        assert_eq!(resolve("./src/../src").unwrap(), root.join("src"));
+        assert_eq!(resolve("../docs").unwrap(), tmp_dir.join("docs"));
+        let absolute = tmp_dir.join("elsewhere");
+        assert_eq!(resolve(absolute.to_str().unwrap()).unwrap(), absolute);
+        assert!(resolve("book.toml").is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("src"), tmp_dir.join("linked")).unwrap();
+            assert_eq!(resolve("../linked").unwrap(), root.join("src"));
+            assert_eq!(resolve("../linked/../book/src").unwrap(), root.join("src"));
+        }
+
+        let compiler = Compiler::new(&root, Path::new("../docs"), Config::default()).unwrap();
+        assert_eq!(compiler.outdir, tmp_dir.join("docs").join(REL_OUTDIR));
+    }
+
+    #[test]
+    fn max_size() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(&tmp.path().join(REL_OUTDIR));
+        compiler.export_sources = true;
+
+        let s = "```plantuml max-width-px=900\nA -> B\n```\n\n```plantuml\nA -> B\n```\n\n```plantuml max-height-px=big\nA -> B\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+
+        assert_ne!(results[0].output, results[1].output);
+        // invalid sizes are ignored
+        assert_eq!(results[1].output, results[2].output);
+        let source = std::fs::read_to_string(tmp.path().join(results[0].source_file())).unwrap();
+        assert_eq!(source, "@startuml\nscale max 900 width\nA -> B\n@enduml\n");
+    }
+
+    #[test]
+    fn readable_width() {
+        let svg = r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" style="width:1203px;" width="1203px" height="80px"><g/></svg>"#;
+        assert_eq!(svg_width(svg), Some(1203));
+        assert_eq!(svg_width("<svg/>"), None);
+
+        let s = "```plantuml\nA -> B\n```\n\n```plantuml\nclass A\n```\n\n```plantuml max-width-px=500\nA -> B\n```\n";
+        let targets = scan_chapter(
+            s,
+            Path::new("a.md"),
+            &ScanOptions {
+                flags: &[],
+                max_sequence_width: Some(900),
+                scale_sizes: false,
+                pragmas: &HashMap::new(),
+                layout_engine: LayoutEngine::Graphviz,
+                messages: &Messages::default(),
+                lines: None,
+            },
+        );
+        let scales: Vec<_> = targets.iter().map(|t| t.scale.as_deref()).collect();
+        assert_eq!(
+            scales,
+            vec![
+                Some("scale max 900 width"),
+                None,
+                Some("scale max 500 width")
+            ]
+        );
+    }
+
+    #[test]
+    fn warning_lines() {
+        let pragmas = HashMap::from([("all".to_owned(), vec!["layout smetana".to_owned()])]);
+        let s = "```plantuml max-width-px=500\nA -> B\nB -> C\n```\n\n```plantuml\n' title\n@startuml\nA -> B\n@enduml\n```\n";
+        let targets = scan_chapter(
+            s,
+            Path::new("a.md"),
+            &ScanOptions {
+                flags: &[],
+                max_sequence_width: None,
+                scale_sizes: false,
+                pragmas: &pragmas,
+                layout_engine: LayoutEngine::Graphviz,
+                messages: &Messages::default(),
+                lines: None,
+            },
+        );
+        // @startuml, the pragma and the scale are added before the first line
+        let lines: Vec<_> = (1..=6).map(|line| targets[0].input_line(line)).collect();
+        assert_eq!(lines, [None, None, None, Some(1), Some(2), None]);
+        // only the pragma is added, after the diagram's own @startuml
+        let lines: Vec<_> = (1..=5).map(|line| targets[1].input_line(line)).collect();
+        assert_eq!(lines, [Some(1), Some(2), None, Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn pragmas() {
+        let pragmas = HashMap::from([
+            ("all".to_owned(), vec!["layout smetana".to_owned()]),
+            ("sequence".to_owned(), vec!["teoz true".to_owned()]),
+        ]);
+        let s = "```plantuml\n@startuml\nA -> B\n@enduml\n```\n\n```plantuml max-width-px=500\nclass A\n```\n";
+        let targets = scan_chapter(
+            s,
+            Path::new("a.md"),
+            &ScanOptions {
+                flags: &[],
+                max_sequence_width: None,
+                scale_sizes: false,
+                pragmas: &pragmas,
+                layout_engine: LayoutEngine::Graphviz,
+                messages: &Messages::default(),
+                lines: None,
+            },
+        );
+        assert_eq!(
+            targets[0].source(),
+            "@startuml\n!pragma layout smetana\n!pragma teoz true\nA -> B\n@enduml\n"
+        );
+        assert_eq!(
+            targets[1].source(),
+            "@startuml\n!pragma layout smetana\nscale max 500 width\nclass A\n@enduml\n"
+        );
+
+        let plain = scan_chapter(
+            s,
+            Path::new("a.md"),
+            &ScanOptions {
+                flags: &[],
+                max_sequence_width: None,
+                scale_sizes: false,
+                pragmas: &HashMap::new(),
+                layout_engine: LayoutEngine::Graphviz,
+                messages: &Messages::default(),
+                lines: None,
+            },
+        );
+        assert_ne!(plain[0].output, targets[0].output);
+    }
+
+    #[test]
+    fn graphviz_crash() {
+        let tmp = TempDir::new().unwrap();
+        let (bin, compiler) = stub_compiler(tmp.path());
+
+        let s = "```plantuml\n@startuml\nclass DOT_CRASH\n@enduml\n```\n";
+        let res = replace_all(&compiler, s, "chapter.md");
+        assert!(res.starts_with("![](plantuml_images/"));
+        let invocations = std::fs::read_to_string(bin.path().join("invocations")).unwrap();
+        assert_eq!(invocations.lines().count(), 2);
+
+        // diagrams already laid out by another engine aren't retried
+        let s = "```plantuml,layout-engine=elk\n@startuml\nclass DOT_CRASH\n@enduml\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", s.to_owned(), "chapter.md", vec![]));
+        let plan = compiler.plan(compiler.scan(&book));
+        assert!(compiler.render(plan).is_err());
+    }
+
+    #[test]
+    fn layout_engines() {
+        let s = "```plantuml\nclass A\n```\n\n```plantuml layout-engine=graphviz\nclass A\n```\n\n```plantuml layout-engine=elk\nclass A\n```\n";
+        let targets = scan_chapter(
+            s,
+            Path::new("a.md"),
+            &ScanOptions {
+                flags: &[],
+                max_sequence_width: None,
+                scale_sizes: false,
+                pragmas: &HashMap::new(),
+                layout_engine: LayoutEngine::Smetana,
+                messages: &Messages::default(),
+                lines: None,
+            },
+        );
+        let pragmas: Vec<_> = targets.iter().map(|t| t.pragmas.clone()).collect();
+        assert_eq!(
+            pragmas,
+            vec![
+                vec!["layout smetana".to_owned()],
+                vec![],
+                vec!["layout elk".to_owned()]
+            ]
+        );
+    }
+
+    #[test]
+    fn resumable() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(&tmp.path().join(REL_OUTDIR));
+        compiler.src_dir = tmp.path().to_owned();
+        compiler.transactional = true;
+        compiler.resumable = true;
+
+        let book = |body: &str| {
+            let s = format!("```plantuml\nA -> B\n```\n\n```plantuml\n{}\n```\n", body);
+            let mut book = Book::new();
+            book.push_item(Chapter::new("A", s, "a.md", vec![]));
+            book
+        };
+
+        let resume_dir = tmp.path().join(RESUME_DIR);
+        let plan = compiler.plan(compiler.scan(&book("A -> FAIL")));
+        assert!(compiler.render(plan).is_err());
+        let progress = Progress::read(&resume_dir).unwrap();
+        assert_eq!(progress.rendered.len(), 1);
+        assert_eq!(progress.failed.len(), 1);
+        let rendered = *progress.rendered.iter().next().unwrap();
+        assert!(resume_dir
+            .join(STAGED)
+            .join(format!("{}.svg", rendered))
+            .exists());
+
+        // the next render picks up the staged image, and finishes the render
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book("C -> D"))))
+            .unwrap();
+        assert_eq!(results[0].output, rendered);
+        assert!(compiler.outdir.join(results[0].filename()).exists());
+        assert!(!resume_dir.exists());
+    }
+
+    #[test]
+    fn tmp_budget() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.tmp_budget = Some(0);
+
+        let s = "```plantuml\nA -> B\n```\n\n```plantuml\nA -> C\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        let plan = compiler.plan(compiler.scan(&book));
+
+        // each image goes straight to the outdir, and inputs don't pile up
+        let results = compiler.render(plan.clone()).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(dir_size(compiler.tmpdir().unwrap()).unwrap(), 0);
+
+        compiler.transactional = true;
+        let _ = std::fs::remove_dir_all(tmp.path());
+        assert!(compiler.render(plan).is_err());
+    }
+
+    #[test]
+    fn pinned() {
+        let tmp = TempDir::new().unwrap();
+        let (_bin, mut compiler) = stub_compiler(tmp.path());
+        compiler.pinned = true;
+
+        let s = "```plantuml\n!include <C4/C4_Container>\nA -> B\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        let err = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("must be vendored with `stdlib`"));
+
+        let s = "```plantuml\nA -> B\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        let manifest = Manifest {
+            plantuml_version: Some("PlantUML version 1.2020.0".to_owned()),
+            figures: vec![],
+        };
+        std::fs::write(
+            tmp.path().join(MANIFEST),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        let err = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("pinned to \"PlantUML version 1.2020.0\""));
+
+        std::fs::remove_file(tmp.path().join(MANIFEST)).unwrap();
+        let results = compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+        compiler.write_manifest(&results).unwrap();
+        compiler
+            .render(compiler.plan(compiler.scan(&book)))
+            .unwrap();
+    }
+}
@@ -0,0 +1,724 @@
+use crate::compiler::PLANTUML;
+use crate::{ErrorClass, IndexFormat, Lints, RenderLogFormat};
+use anyhow::{bail, Context, Result};
+use mdbook::preprocess::PreprocessorContext;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// The name of the `[preprocessor.<name>]` table in `book.toml`
+pub const CONFIG_KEY: &str = "puml";
+
+/// The table used by mdbook-plantuml, read when there is no `[preprocessor.puml]`
+/// so books can switch by changing its `command`
+pub(crate) const LEGACY_CONFIG_KEY: &str = "plantuml";
+
+/// Config shared by every book in a repository, found by searching up from the book root.
+/// It has the same keys as `[preprocessor.puml]`, which override it key by key.
+pub const WORKSPACE_CONFIG: &str = "mdbook-puml.toml";
+
+/// mdbook-plantuml's options, and what they are now
+const LEGACY_KEYS: &[(&str, Option<&str>)] = &[
+    ("plantuml-cmd", Some("plantuml-command")),
+    ("use-data-uris", None),
+    ("clickable-img", None),
+    ("piped", None),
+    ("verbose", None),
+];
+
+/// The options of each `profile`, which the book's own options override
+const PROFILES: &[(&str, &str)] = &[
+    (
+        "fast",
+        r#"
+lenient = true
+stale-fallback = true
+batch = "chapter"
+jobs = "auto"
+"#,
+    ),
+    (
+        "strict",
+        r#"
+lenient = false
+transactional = true
+
+[policy]
+syntax-error = "error"
+unsupported = "error"
+backend-missing = "error"
+invalid-output = "error"
+io-error = "error"
+
+[lint]
+forbid-includeurl = true
+deny = true
+"#,
+    ),
+    (
+        "reproducible",
+        r#"
+pure = true
+pinned = true
+lenient = false
+"#,
+    ),
+];
+
+/// Keys of the preprocessor table that mdbook reads itself
+const MDBOOK_KEYS: &[&str] = &["command", "renderers", "before", "after", "optional"];
+
+/// User configuration, read from the `[preprocessor.puml]` table in `book.toml`
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// A preset of options for a kind of build, which the other options override:
+    /// `"fast"` renders leniently, a chapter per invocation with a job per CPU.
+    /// `"strict"` fails on any problem, including lints and images that plantuml
+    /// wrote broken, and leaves the images untouched when it does.
+    /// `"reproducible"` renders in pure mode and `pinned`.
+    /// Diagrams are always rendered by the local plantuml, there's no server backend.
+    pub profile: Option<String>,
+    /// Base url of the repository the book lives in,
+    /// eg `https://github.com/org/repo/blob/main/`.
+    /// When set, every figure links back to the source lines of its diagram.
+    pub source_link_base: Option<String>,
+    /// Makes renders reproducible under hermetic build systems.
+    /// Network access, clock functions and undeclared includes are rejected,
+    /// the temporary directory has a fixed path, and the plantuml version isn't cached.
+    pub pure: bool,
+    /// Pins what renders depend on outside of the book: `<...>` stdlib includes must
+    /// come from the vendored `stdlib`, and plantuml must be the version recorded in
+    /// `manifest.json` by the last build, which has to be removed to upgrade it.
+    pub pinned: bool,
+    /// Files that diagrams may include in pure mode, relative to the book src
+    pub includes: Vec<PathBuf>,
+    /// Short names for include files (relative to the book src), so that eg
+    /// `c4 = "vendor/C4_Container.puml"` lets diagrams `!include <alias:c4>`
+    pub aliases: BTreeMap<String, PathBuf>,
+    /// A vendored copy of the plantuml stdlib (relative to the book root), eg a checkout
+    /// of plantuml-stdlib at a tag. `!include <aws/...>` reads from it rather than the
+    /// stdlib bundled into plantuml, so icons don't change between plantuml releases.
+    pub stdlib: Option<PathBuf>,
+    /// Image directory of a previous build (relative to the book root), eg from a CI artifact.
+    /// Diagrams found in its manifest are copied over instead of rendered,
+    /// and the figures that changed are listed in `changes.json`.
+    pub diff_base: Option<PathBuf>,
+    /// Directory of baseline images (relative to the book root) to compare renders against.
+    /// Differences are reported in `regressions.json`.
+    pub compare_baseline: Option<PathBuf>,
+    /// Proportion of differing SVG elements, from 0 to 1, above which a comparison is reported
+    pub compare_threshold: f64,
+    /// Adds a chapter to the end of the book showing every diagram,
+    /// each linking back to the chapter it is from
+    pub gallery: bool,
+    /// Where to emit an index of the diagrams grouped by kind and tag:
+    /// `"chapter"` adds a chapter to the end of the book, `"json"` writes `index.json`
+    pub index: Vec<IndexFormat>,
+    /// Writes `plantuml_images/diagrams.json` into the book,
+    /// describing every figure for custom themes
+    pub diagrams_json: bool,
+    /// Writes `plantuml_images/diagrams.js`, which defines the same data as
+    /// `window.plantumlDiagrams` for themes to add to `additional-js`
+    pub diagrams_js: bool,
+    /// Also renders sequence diagrams as ascii art (`plantuml -ttxt`), included in
+    /// the page inside a `<noscript>` (`"noscript"`) or print-only (`"print"`) region
+    pub text_fallback: Option<TextFallback>,
+    /// `"text-only"` replaces every diagram with its ascii art rendering in a code block,
+    /// for an entirely text version of the book. Diagrams that have none are left as
+    /// their source. Defaults to `"images"`.
+    pub mode: Mode,
+    /// Extension of the image files, when it should differ from the format, eg `svgz`
+    /// for a step that compresses the images after the build
+    pub image_extension: Option<String>,
+    /// Renders the diagrams for `mdbook test` too, which otherwise leaves them as they
+    /// are, as doctests don't need the images
+    pub render_in_tests: bool,
+    /// Renderers, eg `["pandoc"]`, that the `width` and `height` attributes of blocks
+    /// are rendered into the images for, as a `scale` directive. Other renderers, such
+    /// as the HTML one, set the size on the `<img>` tag.
+    pub scale_sizes_for: Vec<String>,
+    /// Alt text for images whose blocks don't give their own, eg
+    /// `"{kind} diagram: {name}"`. `{kind}`, `{name}`, `{chapter}` and `{tags}` are
+    /// replaced by the diagram's, and diagrams missing one that is used get no alt text.
+    pub alt_template: Option<String>,
+    /// Stores images under this many directories named by pairs of their hash,
+    /// eg `plantuml_images/ab/cd/abcd….svg` for 2, to keep directories small in books
+    /// with thousands of diagrams. At most 4. Changing it moves the existing images to their new directories.
+    pub shard_depth: usize,
+    /// Also writes a PNG of each SVG image next to it, for renderers and themes that
+    /// can't use SVG. With the default `rasterize` feature the SVG is rasterised,
+    /// rather than rendered by plantuml a second time.
+    pub png: bool,
+    /// Resolution of the PNGs rasterised from SVG images, which is also used for
+    /// PNGs plantuml fails to render. Defaults to 96, the SVG's own size.
+    pub png_dpi: Option<u32>,
+    /// Dublin Core terms embedded in every SVG image as RDF metadata, eg
+    /// `{ license = "CC-BY-4.0", creator = "Docs Team" }`. `author` is taken to mean `creator`.
+    pub svg_metadata: BTreeMap<String, String>,
+    /// Diagrams that fail to render are left as code blocks with a warning,
+    /// instead of failing the build
+    pub lenient: bool,
+    /// What each class of render failure does, overriding `lenient` for that class:
+    /// `"error"` fails the build, `"warn"` logs it, and `"ignore"` only logs it at
+    /// debug level. Failed diagrams are left as code blocks unless the build fails.
+    pub policy: Policies,
+    /// In lenient mode, diagrams that fail to render keep showing the image
+    /// from the last build they rendered in, found through its manifest
+    pub stale_fallback: bool,
+    /// A failed build leaves `plantuml_images` untouched, instead of keeping
+    /// the images that did render
+    pub transactional: bool,
+    /// Stages rendered images in `<src>/.plantuml-resume` until the build finishes,
+    /// so an interrupted or failed render picks up where it left off
+    pub resumable: bool,
+    /// Most megabytes of rendered images to hold in the temporary directory.
+    /// Beyond it they are moved into `plantuml_images` early, or fail the build
+    /// in transactional mode.
+    pub tmp_budget_mb: Option<u64>,
+    /// Seconds to reuse the cached `plantuml -version` probe for, defaults to an hour.
+    /// It's never cached in pure mode.
+    pub probe_ttl: Option<u64>,
+    /// Directory (relative to the book root) the `plantuml -version` probe is cached in,
+    /// defaults to `.mdbook-puml`
+    pub cache_dir: Option<PathBuf>,
+    /// How many plantuml invocations to run at once, or `"auto"` for one per CPU.
+    /// Defaults to 1.
+    pub jobs: Option<Jobs>,
+    /// Records how long each diagram took to render in `manifest.json`, and renders the
+    /// slowest first next time, so they don't hold up the end of a build with several `jobs`.
+    /// The times are only written to the book, never sent anywhere.
+    /// Not allowed in pure mode, as the times differ on every build.
+    pub timings: bool,
+    /// How many diagrams to render per plantuml invocation.
+    /// `"chapter"` renders each chapter's diagrams together, saving JVM startups.
+    pub batch: Batch,
+    /// Flags appended to every plantuml invocation, eg `["-Sshadowing=false"]`.
+    /// They are passed through the shell as written, and changing them re-renders every diagram.
+    pub extra_flags: Vec<String>,
+    /// Checks the diagrams against the rules in `[preprocessor.puml.lint]`
+    pub lint: Option<Lints>,
+    /// The plantuml executable, eg `java -jar plantuml.jar`. Defaults to `plantuml`.
+    /// A list of commands is tried in order, using the first that is installed, and a
+    /// lone `.jar` (relative to the book root) is run with java.
+    pub plantuml_command: Option<PlantumlCommand>,
+    /// Copies each diagram's source into the book next to its image, as `<hash>.puml`
+    pub export_sources: bool,
+    /// Links each figure to the diagram in the online editor on plantuml.com
+    pub online_editor: bool,
+    /// Appends a line for each diagram rendered, with the time, its hash, where it is,
+    /// the backend, how long it took and whether it failed, to `plantuml_images/render.log`
+    /// (`"text"`) or `render.jsonl` (`"jsonl"`), as an audit trail of the images
+    /// Not allowed in pure mode, as the times differ on every build.
+    pub render_log: Option<RenderLogFormat>,
+    /// Adds an HTML comment to the top of each chapter with diagrams, listing their
+    /// images and where each came from, for debugging pages that show stale diagrams
+    pub summary_comments: bool,
+    /// Language of the code blocks that show diagram sources, when they are ignored
+    /// or fail to render, eg `puml` to match a theme's highlighting.
+    /// By default the blocks are left exactly as written.
+    pub source_language: Option<String>,
+    /// Writes `plantuml_images/highlight-plantuml.js`, a highlight.js grammar for
+    /// `plantuml` and `puml` code blocks, for themes to add to `additional-js`
+    pub highlight_js: bool,
+    /// Shows SVG images larger than this many bytes as a thumbnail, linking to a page
+    /// of their own, so the chapter loads quickly. Blocks can ask for this with `page`.
+    pub page_threshold: Option<u64>,
+    /// Warns about sequence diagrams rendered wider than this many pixels,
+    /// suggesting they are split with `newpage`
+    pub readable_width: Option<u32>,
+    /// Scales sequence diagrams wider than `readable-width` down to it,
+    /// unless the block sets its own size
+    pub scale_to_readable_width: bool,
+    /// `!pragma` directives added to diagrams, by kind of diagram, eg
+    /// `sequence = ["teoz true"]`. Those under `all` are added to every diagram.
+    pub pragmas: HashMap<String, Vec<String>>,
+    /// Language of the warnings and errors, defaulting to `book.language`.
+    /// Can be set for a single build with `MDBOOK_PREPROCESSOR__PUML__LANGUAGE`.
+    pub language: Option<String>,
+    /// Catalogs of translated messages by language, each mapping message keys to templates
+    pub messages: HashMap<String, HashMap<String, String>>,
+    /// How diagrams are laid out, overridden by the `layout-engine` attribute of a block.
+    /// Defaults to graphviz.
+    pub layout_engine: LayoutEngine,
+}
+
+/// A number of `jobs`, or `"auto"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Jobs {
+    Count(usize),
+    Auto(AutoJobs),
+}
+
+/// `"auto"`, a job per CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoJobs {
+    Auto,
+}
+
+impl Jobs {
+    /// How many invocations to run at once, at least one
+    pub fn count(self) -> usize {
+        match self {
+            Jobs::Count(count) => count.max(1),
+            Jobs::Auto(AutoJobs::Auto) => {
+                std::thread::available_parallelism().map_or(1, |n| n.get())
+            }
+        }
+    }
+}
+
+/// The `plantuml-command`, or the commands to try in order
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum PlantumlCommand {
+    One(String),
+    Fallbacks(Vec<String>),
+}
+
+/// What a render failure does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Error,
+    Warn,
+    Ignore,
+}
+
+/// The policy for each [`ErrorClass`], if it isn't decided by `lenient`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Policies {
+    /// Plantuml rejected the diagram
+    pub syntax_error: Option<Policy>,
+    /// Plantuml can't render the kind of diagram to the format
+    pub unsupported: Option<Policy>,
+    /// Plantuml, or the java it runs on, couldn't be run
+    pub backend_missing: Option<Policy>,
+    /// Plantuml wrote an empty image, or one that isn't an SVG or PNG.
+    /// It is never published.
+    pub invalid_output: Option<Policy>,
+    /// Reading or writing a file failed
+    pub io_error: Option<Policy>,
+}
+
+impl Policies {
+    pub(crate) fn get(&self, class: ErrorClass) -> Option<Policy> {
+        match class {
+            ErrorClass::SyntaxError => self.syntax_error,
+            ErrorClass::Unsupported => self.unsupported,
+            ErrorClass::BackendMissing => self.backend_missing,
+            ErrorClass::InvalidOutput => self.invalid_output,
+            ErrorClass::IoError => self.io_error,
+        }
+    }
+}
+
+/// How diagrams are grouped into plantuml invocations
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Batch {
+    /// One invocation per diagram
+    #[default]
+    Diagram,
+    /// One invocation per chapter. If it fails, the diagrams are retried
+    /// individually to find which ones failed.
+    Chapter,
+}
+
+/// The engine plantuml lays out diagrams with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutEngine {
+    /// Graphviz's `dot`, which must be installed
+    #[default]
+    Graphviz,
+    /// Smetana, a port of graphviz built into plantuml, so nothing else needs installing
+    Smetana,
+    /// The Eclipse Layout Kernel, built into plantuml
+    Elk,
+}
+
+impl LayoutEngine {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "graphviz" => Some(Self::Graphviz),
+            "smetana" => Some(Self::Smetana),
+            "elk" => Some(Self::Elk),
+            _ => None,
+        }
+    }
+
+    /// The pragma selecting the engine, graphviz being plantuml's default
+    pub(crate) fn pragma(self) -> Option<&'static str> {
+        match self {
+            Self::Graphviz => None,
+            Self::Smetana => Some("layout smetana"),
+            Self::Elk => Some("layout elk"),
+        }
+    }
+}
+
+/// What diagrams are replaced with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Rendered images
+    #[default]
+    Images,
+    /// Their ascii art rendering (`plantuml -ttxt`), eg for screen reader testing
+    TextOnly,
+}
+
+/// Where the ascii art rendering of a diagram is placed in the page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TextFallback {
+    /// Shown when scripts are disabled, and picked up by most content extractors
+    Noscript,
+    /// Only shown when printing
+    Print,
+}
+
+impl Config {
+    pub fn from_context(ctx: &PreprocessorContext) -> Result<Self> {
+        Self::from_book_config(&ctx.config, &ctx.root)
+    }
+
+    /// Reads the config from the `book.toml` of the book at `root`, and the
+    /// workspace config above it, for when running outside of mdbook
+    pub fn from_book_config(config: &mdbook::Config, root: &Path) -> Result<Self> {
+        let mut merged = match find_workspace_config(root) {
+            Some(path) => {
+                let table = read_workspace_config(&path)?;
+                translate_legacy(&path.display().to_string(), table)
+            }
+            None => toml::value::Table::new(),
+        };
+        let key = match config.get_preprocessor(CONFIG_KEY) {
+            Some(table) => {
+                let section = format!("[preprocessor.{}]", CONFIG_KEY);
+                merge(&mut merged, translate_legacy(&section, table.clone()));
+                CONFIG_KEY
+            }
+            None => match config.get_preprocessor(LEGACY_CONFIG_KEY) {
+                Some(table) => {
+                    let section = format!("[preprocessor.{}]", LEGACY_CONFIG_KEY);
+                    merge(&mut merged, translate_legacy(&section, table.clone()));
+                    LEGACY_CONFIG_KEY
+                }
+                None => CONFIG_KEY,
+            },
+        };
+        if let Some(profile) = merged.get("profile") {
+            let profile = profile.as_str().context("profile must be a string")?;
+            let mut preset = self::profile(profile)?;
+            merge(&mut preset, merged);
+            merged = preset;
+        }
+        let mut parsed: Config = toml::Value::Table(merged)
+            .try_into()
+            .with_context(|| format!("invalid [preprocessor.{}] config", key))?;
+        if parsed.language.is_none() {
+            parsed.language = config.book.language.clone();
+        }
+        Ok(parsed)
+    }
+
+    /// The commands to run plantuml with, in the order to try them
+    pub fn plantuml_commands(&self) -> Vec<String> {
+        match &self.plantuml_command {
+            None => vec![PLANTUML.to_owned()],
+            Some(PlantumlCommand::One(command)) => vec![command.clone()],
+            Some(PlantumlCommand::Fallbacks(commands)) => commands.clone(),
+        }
+    }
+
+    /// JSON Schema of the `[preprocessor.puml]` table, for editors and CI to validate
+    /// `book.toml` against. mdbook's own keys, such as `command`, are left to mdbook.
+    pub fn schema() -> serde_json::Value {
+        let mut schema = schemars::schema_for!(Config);
+        schema.schema.metadata().title = Some(format!("[preprocessor.{}]", CONFIG_KEY));
+        serde_json::to_value(schema).expect("schemas serialize")
+    }
+}
+
+/// The keys of the book's preprocessor table and the workspace config that no option
+/// reads, which are usually misspelt. They are ignored by the build.
+pub(crate) fn unknown_keys(config: &mdbook::Config, root: &Path) -> Result<Vec<String>> {
+    let known = serde_json::to_value(Config::default())?;
+    let known = known.as_object().expect("config is a table");
+
+    let mut tables = vec![];
+    if let Some(path) = find_workspace_config(root) {
+        tables.push(read_workspace_config(&path)?);
+    }
+    for key in [CONFIG_KEY, LEGACY_CONFIG_KEY] {
+        if let Some(table) = config.get_preprocessor(key) {
+            tables.push(table.clone());
+            break;
+        }
+    }
+
+    let mut unknown = vec![];
+    for key in tables.iter().flat_map(|table| table.keys()) {
+        let is_known = known.contains_key(key)
+            || MDBOOK_KEYS.contains(&key.as_str())
+            || LEGACY_KEYS.iter().any(|(legacy, _)| legacy == key);
+        if !is_known && !unknown.contains(key) {
+            unknown.push(key.clone());
+        }
+    }
+    Ok(unknown)
+}
+
+/// The options of a profile
+fn profile(name: &str) -> Result<toml::value::Table> {
+    let preset = match PROFILES.iter().find(|(profile, _)| *profile == name) {
+        Some((_, preset)) => preset,
+        None => {
+            let names: Vec<_> = PROFILES.iter().map(|(profile, _)| *profile).collect();
+            bail!(
+                "unknown profile {:?}, expected one of {}",
+                name,
+                names.join(", ")
+            );
+        }
+    };
+    Ok(toml::from_str(preset).expect("profiles are valid"))
+}
+
+/// The closest workspace config in the book root or the directories above it
+fn find_workspace_config(root: &Path) -> Option<PathBuf> {
+    root.ancestors()
+        .map(|dir| dir.join(WORKSPACE_CONFIG))
+        .find(|path| path.is_file())
+}
+
+fn read_workspace_config(path: &Path) -> Result<toml::value::Table> {
+    let file = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let table = toml::from_str(&file).with_context(|| format!("invalid {}", path.display()))?;
+    debug!("using workspace config {}", path.display());
+    Ok(table)
+}
+
+/// Overrides the keys of `base`, merging nested tables such as `lint`
+fn merge(base: &mut toml::value::Table, overrides: toml::value::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Replaces mdbook-plantuml's options with their equivalents, warning that they are deprecated
+/// in `section`, the table or file they were read from
+fn translate_legacy(section: &str, mut table: toml::value::Table) -> toml::value::Table {
+    for (legacy, native) in LEGACY_KEYS {
+        let value = match table.remove(*legacy) {
+            Some(value) => value,
+            None => continue,
+        };
+        match native {
+            Some(native) => {
+                warn!(
+                    "{} {} is deprecated, use {} instead",
+                    section, legacy, native
+                );
+                table.entry(*native).or_insert(value);
+            }
+            None => warn!(
+                "{} {} is from mdbook-plantuml, and has no effect",
+                section, legacy
+            ),
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn legacy_keys() {
+        let root = tempfile::TempDir::new().unwrap();
+        let book = mdbook::Config::from_str(
+            r#"
+[preprocessor.plantuml]
+command = "mdbook-puml"
+plantuml-cmd = "java -jar plantuml.jar"
+use-data-uris = true
+"#,
+        )
+        .unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert_eq!(config.plantuml_commands(), ["java -jar plantuml.jar"]);
+
+        // the native table takes precedence
+        let book = mdbook::Config::from_str(
+            r#"
+[preprocessor.plantuml]
+plantuml-cmd = "old"
+
+[preprocessor.puml]
+plantuml-command = "new"
+plantuml-cmd = "older"
+"#,
+        )
+        .unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert_eq!(config.plantuml_commands(), ["new"]);
+
+        let book = mdbook::Config::from_str(
+            "[preprocessor.puml]\nplantuml-command = [\"plantuml\", \"plantuml.jar\"]\n",
+        )
+        .unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert_eq!(config.plantuml_commands(), ["plantuml", "plantuml.jar"]);
+
+        // and so does the workspace config
+        std::fs::write(
+            root.path().join(WORKSPACE_CONFIG),
+            "plantuml-cmd = \"java -jar workspace.jar\"\n",
+        )
+        .unwrap();
+        let book = mdbook::Config::from_str("[preprocessor.puml]\n").unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert_eq!(config.plantuml_commands(), ["java -jar workspace.jar"]);
+        let book =
+            mdbook::Config::from_str("[preprocessor.puml]\nplantuml-command = \"book\"\n").unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert_eq!(config.plantuml_commands(), ["book"]);
+    }
+
+    #[test]
+    fn profiles() {
+        let root = tempfile::TempDir::new().unwrap();
+        let book = mdbook::Config::from_str(
+            r#"
+[preprocessor.puml]
+profile = "strict"
+transactional = false
+
+[preprocessor.puml.lint]
+require-title = true
+"#,
+        )
+        .unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert!(!config.transactional);
+        let lint = config.lint.unwrap();
+        assert!(lint.require_title && lint.forbid_includeurl && lint.deny);
+
+        let book = mdbook::Config::from_str("[preprocessor.puml]\nprofile = \"fast\"\n").unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert!(config.lenient);
+        assert_eq!(config.jobs, Some(Jobs::Auto(AutoJobs::Auto)));
+        assert!(config.jobs.unwrap().count() >= 1);
+
+        let book =
+            mdbook::Config::from_str("[preprocessor.puml]\nprofile = \"reproducible\"\n").unwrap();
+        let config = Config::from_book_config(&book, root.path()).unwrap();
+        assert!(config.pure && config.pinned);
+
+        // every preset is made of options that exist
+        let known = serde_json::to_value(Config::default()).unwrap();
+        for (name, preset) in PROFILES {
+            let preset: toml::value::Table = toml::from_str(preset).unwrap();
+            for key in preset.keys() {
+                assert!(known.get(key).is_some(), "{} sets unknown {}", name, key);
+            }
+            toml::Value::Table(preset).try_into::<Config>().unwrap();
+        }
+
+        let book = mdbook::Config::from_str("[preprocessor.puml]\nprofile = \"quick\"\n").unwrap();
+        let err = Config::from_book_config(&book, root.path()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown profile \"quick\", expected one of fast, strict, reproducible"
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        let root = tempfile::TempDir::new().unwrap();
+        let book = mdbook::Config::from_str(
+            r#"
+[preprocessor.puml]
+command = "mdbook-puml"
+plantuml-cmd = "plantuml"
+lenient = true
+lenent = true
+"#,
+        )
+        .unwrap();
+        assert_eq!(unknown_keys(&book, root.path()).unwrap(), ["lenent"]);
+    }
+
+    #[test]
+    fn schema() {
+        let schema = Config::schema();
+        let properties = schema["properties"].as_object().unwrap();
+        let keys = serde_json::to_value(Config::default()).unwrap();
+        for key in keys.as_object().unwrap().keys() {
+            assert!(properties.contains_key(key), "{} is missing", key);
+        }
+        assert_eq!(
+            schema["definitions"]["Policy"]["enum"],
+            serde_json::json!(["error", "warn", "ignore"])
+        );
+        assert!(properties["plantuml-command"]["description"]
+            .as_str()
+            .unwrap()
+            .starts_with("The plantuml executable"));
+    }
+
+    #[test]
+    fn workspace() {
+        let workspace = tempfile::TempDir::new().unwrap();
+        let root = workspace.path().join("books/guide");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            workspace.path().join(WORKSPACE_CONFIG),
+            r#"
+plantuml-command = "java -jar plantuml.jar"
+lenient = true
+
+[lint]
+require-title = true
+deny = true
+"#,
+        )
+        .unwrap();
+
+        let book = mdbook::Config::from_str(
+            r#"
+[preprocessor.puml]
+lenient = false
+
+[preprocessor.puml.lint]
+deny = false
+"#,
+        )
+        .unwrap();
+        let config = Config::from_book_config(&book, &root).unwrap();
+        assert_eq!(config.plantuml_commands(), ["java -jar plantuml.jar"]);
+        assert!(!config.lenient);
+        let lint = config.lint.unwrap();
+        assert!(lint.require_title);
+        assert!(!lint.deny);
+
+        // books without a table of their own use the workspace's
+        let config = Config::from_book_config(&mdbook::Config::default(), &root).unwrap();
+        assert!(config.lenient);
+    }
+}
@@ -0,0 +1,213 @@
+//! `mdbook-puml daemon`, which answers render requests for a book over stdio,
+//! so editors and repeated rebuilds share one [`Compiler`], with the book's
+//! config, the probed plantuml version and the outdir's images, rather than
+//! loading them afresh for every change. Plantuml itself is still started for
+//! every render, so its startup time isn't avoided.
+//!
+//! The protocol is JSON-RPC 2.0, one message per line. The methods are:
+//!
+//! - `render_chapter`, with `path` and `content`, which renders a chapter as
+//!   [`Compiler::render_chapter`] does, returning its `content` and `images`
+//! - `render`, with `source` and an optional `format`, which renders a single
+//!   diagram, returning the `image`. PNG images are base64 encoded.
+//! - `shutdown`, which stops the daemon once it has replied
+
+use crate::preview::Format;
+use crate::Compiler;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A request that was understood, but failed
+const RENDER_FAILED: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct RenderChapter {
+    path: PathBuf,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct Render {
+    source: String,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// Answers the requests read from `input` until it closes or a `shutdown`
+pub fn serve(compiler: &Compiler, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    for line in input.lines() {
+        let line = line.context("could not read a request")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                reply(
+                    &mut output,
+                    Value::Null,
+                    Err((PARSE_ERROR, err.to_string())),
+                )?;
+                continue;
+            }
+        };
+        let _span = debug_span!("request", method = %request.method).entered();
+        let shutdown = request.method == "shutdown";
+        let result = handle(compiler, &request.method, request.params);
+        reply(&mut output, request.id, result)?;
+        if shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn handle(compiler: &Compiler, method: &str, params: Value) -> Result<Value, (i64, String)> {
+    match method {
+        "render_chapter" => {
+            let params: RenderChapter = parse(params)?;
+            let rendered = compiler
+                .render_chapter(&params.path, &params.content)
+                .map_err(failed)?;
+            Ok(json!({ "content": rendered.content, "images": rendered.images }))
+        }
+        "render" => {
+            let params: Render = parse(params)?;
+            let format = match params.format.as_deref() {
+                None | Some("svg") => Format::Svg,
+                Some("png") => Format::Png,
+                Some("txt") => Format::Txt,
+                Some(other) => {
+                    let message = format!("unknown format {:?}, expected svg, png or txt", other);
+                    return Err((INVALID_PARAMS, message));
+                }
+            };
+            let image = compiler
+                .render_to_vec(&params.source, format)
+                .map_err(failed)?;
+            let image = match format {
+                Format::Png => base64(&image),
+                _ => String::from_utf8(image)
+                    .map_err(|_| failed(anyhow!("plantuml output is not utf-8")))?,
+            };
+            Ok(json!({ "image": image }))
+        }
+        "shutdown" => Ok(Value::Null),
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {:?}", method))),
+    }
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))
+}
+
+fn failed(err: anyhow::Error) -> (i64, String) {
+    (RENDER_FAILED, format!("{:#}", err))
+}
+
+fn reply(output: &mut impl Write, id: Value, result: Result<Value, (i64, String)>) -> Result<()> {
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    };
+    serde_json::to_writer(&mut *output, &response)?;
+    writeln!(output)?;
+    output.flush().context("could not send a response")
+}
+
+/// Standard, padded base64
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, PlantumlCommand};
+    use std::path::Path;
+
+    #[test]
+    fn base64() {
+        assert_eq!(super::base64(b""), "");
+        assert_eq!(super::base64(b"f"), "Zg==");
+        assert_eq!(super::base64(b"fo"), "Zm8=");
+        assert_eq!(super::base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn requests() {
+        let root = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(root.path().join("src")).unwrap();
+        let stub = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/books/plantuml.sh");
+        let config = Config {
+            plantuml_command: Some(PlantumlCommand::One(format!("sh {}", stub.display()))),
+            ..Config::default()
+        };
+        let compiler = Compiler::new(root.path(), Path::new("src"), config).unwrap();
+
+        let requests = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"render_chapter","params":{"path":"intro.md","content":"```plantuml\n@startuml\nA -> B\n@enduml\n```\n"}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"render","params":{"source":"@startuml\nA -> B\n@enduml\n","format":"gif"}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"draw"}"#,
+            "not json",
+            r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","id":5,"method":"shutdown"}"#,
+        ];
+        let mut output = vec![];
+        serve(&compiler, requests.join("\n").as_bytes(), &mut output).unwrap();
+        let responses: Vec<Value> = output
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+
+        assert_eq!(responses.len(), 5, "stops at the first shutdown");
+        let images = responses[0]["result"]["images"].as_array().unwrap();
+        assert_eq!(images.len(), 1);
+        assert!(Path::new(images[0].as_str().unwrap()).exists());
+        assert!(responses[0]["result"]["content"]
+            .as_str()
+            .unwrap()
+            .contains("plantuml_images/"));
+        assert_eq!(responses[1]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(responses[2]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[3]["error"]["code"], PARSE_ERROR);
+        assert_eq!(responses[3]["id"], Value::Null);
+        assert_eq!(responses[4]["id"], 4);
+        assert_eq!(responses[4]["result"], Value::Null);
+    }
+}
@@ -0,0 +1,249 @@
+//! `mdbook-puml doctor`, which checks everything a build needs before one is
+//! attempted, with a hint for fixing each problem found
+
+use crate::config::unknown_keys;
+use crate::preview::Format;
+use crate::resolve;
+use crate::{probe, Compiler, Config, LayoutEngine};
+use anyhow::anyhow;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+/// The diagram rendered to check plantuml works
+const TEST_DIAGRAM: &str = "@startuml\nAlice -> Bob: hello\n@enduml\n";
+
+/// How a check went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Builds may work, but something looks wrong
+    Warn,
+    /// Builds will fail
+    Fail,
+}
+
+/// The result of one check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// How to fix it, if it didn't pass
+    pub hint: Option<&'static str>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(
+        name: &'static str,
+        status: Status,
+        detail: impl Into<String>,
+        hint: &'static str,
+    ) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "{:<4}  {}: {}", status, self.name, self.detail)?;
+        if let Some(hint) = self.hint {
+            write!(f, "\n      hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the config of the book at `root`, the tools plantuml needs, that a diagram
+/// renders, and that the images can be written
+pub fn run(book: &mdbook::Config, root: &Path) -> Vec<Check> {
+    let mut checks = vec![];
+    let config = match Config::from_book_config(book, root) {
+        Ok(config) => config,
+        Err(err) => {
+            checks.push(Check::problem(
+                "config",
+                Status::Fail,
+                format!("{:#}", err),
+                "fix the [preprocessor.puml] table in book.toml",
+            ));
+            return checks;
+        }
+    };
+    checks.push(match unknown_keys(book, root) {
+        Ok(unknown) if unknown.is_empty() => Check::ok("config", "valid"),
+        Ok(unknown) => Check::problem(
+            "config",
+            Status::Warn,
+            format!("unknown keys {}", unknown.join(", ")),
+            "they are ignored, so check them for typos",
+        ),
+        Err(err) => Check::problem(
+            "config",
+            Status::Fail,
+            format!("{:#}", err),
+            "fix mdbook-puml.toml",
+        ),
+    });
+
+    let commands = config.plantuml_commands();
+    let plantuml = match resolve::resolve(&commands, root) {
+        Some(command) => probe::probe_version(&command),
+        None => Err(anyhow!(resolve::not_found(&commands))),
+    };
+    let found = plantuml.is_ok();
+    checks.push(match plantuml {
+        Ok(version) => Check::ok("plantuml", version),
+        Err(err) => Check::problem(
+            "plantuml",
+            Status::Fail,
+            format!("{:#}", err),
+            "install plantuml, or set `plantuml-command`, eg to `java -jar plantuml.jar`",
+        ),
+    });
+
+    checks.push(match tool_version("java", "-version") {
+        Some(version) => Check::ok("java", version),
+        // plantuml may be a native build, which is fine if it ran
+        None => Check::problem(
+            "java",
+            if found { Status::Warn } else { Status::Fail },
+            "`java -version` failed",
+            "install a Java runtime, which plantuml needs",
+        ),
+    });
+
+    checks.push(match (config.layout_engine, tool_version("dot", "-V")) {
+        (_, Some(version)) => Check::ok("graphviz", version),
+        (LayoutEngine::Graphviz, None) => Check::problem(
+            "graphviz",
+            Status::Fail,
+            "`dot -V` failed",
+            "install graphviz, or set `layout-engine = \"smetana\"` to use the engine built into plantuml",
+        ),
+        (_, None) => Check::ok(
+            "graphviz",
+            "not installed, nor needed by the configured layout-engine",
+        ),
+    });
+
+    let compiler = match Compiler::new(root, &book.book.src, config) {
+        Ok(compiler) => compiler,
+        Err(err) => {
+            checks.push(Check::problem(
+                "config",
+                Status::Fail,
+                format!("{:#}", err),
+                "fix the [preprocessor.puml] table in book.toml",
+            ));
+            return checks;
+        }
+    };
+    checks.push(match compiler.render_to_vec(TEST_DIAGRAM, Format::Svg) {
+        Ok(_) => Check::ok("render", "rendered a test diagram"),
+        Err(err) => Check::problem(
+            "render",
+            Status::Fail,
+            format!("{:#}", err),
+            "run plantuml on a diagram by hand to see what it needs",
+        ),
+    });
+
+    checks.push(check_writable(compiler.outdir()));
+    checks
+}
+
+/// The first line a tool prints about its version, if it runs.
+/// Java and graphviz print theirs to stderr.
+fn tool_version(tool: &str, flag: &str) -> Option<String> {
+    let output = Command::new(tool).arg(flag).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let printed = [output.stdout, output.stderr].concat();
+    let printed = String::from_utf8_lossy(&printed);
+    Some(printed.lines().next().unwrap_or("").trim().to_owned())
+}
+
+/// Checks a file can be created in the outdir, or the directory it will be created in
+fn check_writable(outdir: &Path) -> Check {
+    let dir = match outdir.ancestors().find(|dir| dir.is_dir()) {
+        Some(dir) => dir,
+        None => {
+            return Check::problem(
+                "outdir",
+                Status::Fail,
+                format!("{} has no existing parent", outdir.display()),
+                "check the book's `src` directory exists",
+            )
+        }
+    };
+    match tempfile::NamedTempFile::new_in(dir) {
+        Ok(_) => Check::ok("outdir", format!("{} is writable", outdir.display())),
+        Err(err) => Check::problem(
+            "outdir",
+            Status::Fail,
+            format!("cannot write to {}: {}", dir.display(), err),
+            "make the book's src dir writable, as images are written into it",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn status(checks: &[Check], name: &str) -> Vec<Status> {
+        checks
+            .iter()
+            .filter(|check| check.name == name)
+            .map(|check| check.status)
+            .collect()
+    }
+
+    #[test]
+    fn checks() {
+        let root = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(root.path().join("src")).unwrap();
+        let stub = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/books/plantuml.sh");
+        let book = mdbook::Config::from_str(&format!(
+            "[preprocessor.puml]\nplantuml-command = \"sh {}\"\nlayout-engine = \"smetana\"\nlenent = true\n",
+            stub.display()
+        ))
+        .unwrap();
+
+        let checks = run(&book, root.path());
+        assert_eq!(status(&checks, "config"), [Status::Warn]);
+        assert_eq!(status(&checks, "plantuml"), [Status::Ok]);
+        assert_eq!(status(&checks, "graphviz"), [Status::Ok]);
+        assert_eq!(status(&checks, "render"), [Status::Ok]);
+        assert_eq!(status(&checks, "outdir"), [Status::Ok]);
+
+        let book = mdbook::Config::from_str("[preprocessor.puml]\nplantuml-command = \"false\"\n")
+            .unwrap();
+        let checks = run(&book, root.path());
+        assert_eq!(status(&checks, "config"), [Status::Ok]);
+        assert_eq!(status(&checks, "plantuml"), [Status::Fail]);
+        assert_eq!(status(&checks, "render"), [Status::Fail]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Wiki style `![[diagram.puml]]` embeds, as used by Obsidian, which are
+//! expanded into fenced blocks before the book is scanned
+
+use crate::compiler::normalize;
+use crate::errors::{RenderError, RenderErrors};
+use crate::markdown::find_code;
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+
+/// Extensions of the files that are embedded as diagrams
+const EXTENSIONS: &[&str] = &["puml", "plantuml", "pu", "iuml"];
+
+/// Maps the lines of expanded markdown back to the lines of the chapter source
+#[derive(Debug, Default, PartialEq, Clone)]
+pub(crate) struct LineMap {
+    /// the first line of each expanded block, how many lines it added,
+    /// and how many had been added before it
+    blocks: Vec<(usize, usize, usize)>,
+}
+
+impl LineMap {
+    /// The line of the chapter source that an expanded line came from.
+    /// The lines of an expanded block are all on the line of its embed.
+    pub fn original(&self, line: usize) -> usize {
+        match self.blocks.iter().rev().find(|(start, ..)| line >= *start) {
+            Some(&(start, added, before)) if line < start + added => start - before,
+            Some(&(_, added, before)) => line - before - added,
+            None => line,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// Replaces each embed of a diagram file that starts a line with a fenced block of its source,
+/// along with where that moved the lines of the markdown to.
+///
+/// Files are looked up relative to the chapter, then to the book src. In pure mode,
+/// they must be in the declared `includes`, and every embed that isn't fails as
+/// [`RenderErrors`]. Embeds within code, of other files, or of files that can't be read,
+/// are left as they are.
+pub(crate) fn expand(
+    markdown: &str,
+    chapter: &Path,
+    src_dir: &Path,
+    declared: Option<&[PathBuf]>,
+) -> Result<(String, LineMap)> {
+    let chapter_dir = src_dir.join(chapter);
+    let chapter_dir = chapter_dir.parent().unwrap_or(src_dir);
+    let code = find_code(markdown);
+    let mut errors = vec![];
+    let mut expanded = String::with_capacity(markdown.len());
+    let mut lines = LineMap::default();
+    let mut inserted = 0;
+    // the end of the markdown copied into `expanded`
+    let mut copied = 0;
+    let mut from = 0;
+
+    while let Some(start) = markdown[from..].find("![[").map(|start| from + start) {
+        let end = match markdown[start..].find("]]") {
+            Some(end) => start + end,
+            None => break,
+        };
+        from = end + 2;
+        // the fence has to start a line, and nothing in code is markdown
+        let line_start = markdown[..start].rfind('\n').map_or(0, |i| i + 1);
+        if line_start != start || code.iter().any(|code| code.contains(&start)) {
+            continue;
+        }
+        // `![[file.puml|alias]]` gives a display name, which isn't needed here
+        let target = markdown[start + 3..end]
+            .split('|')
+            .next()
+            .unwrap_or("")
+            .trim();
+        let mut source = match read_diagram(target, chapter_dir, src_dir, declared) {
+            Ok(Some(source)) => source,
+            Ok(None) => continue,
+            Err(error) => {
+                errors.push(RenderError {
+                    chapter: chapter.to_owned(),
+                    line: markdown[..start].matches('\n').count() + 1,
+                    error,
+                });
+                continue;
+            }
+        };
+
+        expanded.push_str(&markdown[copied..start]);
+        if !source.ends_with('\n') {
+            source.push('\n');
+        }
+        let mut block = format!("```plantuml\n{}```", source);
+        // the closing fence needs a line to itself
+        if !markdown[end + 2..].starts_with(['\n', '\r']) {
+            block.push('\n');
+        }
+        let line = expanded.matches('\n').count() + 1;
+        let added = block.matches('\n').count();
+        lines.blocks.push((line, added, inserted));
+        inserted += added;
+        expanded.push_str(&block);
+        copied = end + 2;
+    }
+
+    if !errors.is_empty() {
+        return Err(RenderErrors(errors).into());
+    }
+    expanded.push_str(&markdown[copied..]);
+    Ok((expanded, lines))
+}
+
+fn read_diagram(
+    target: &str,
+    chapter_dir: &Path,
+    src_dir: &Path,
+    declared: Option<&[PathBuf]>,
+) -> Result<Option<String>> {
+    let path = PathBuf::from(target);
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    if !extension.is_some_and(|ext| EXTENSIONS.contains(&ext)) {
+        return Ok(None);
+    }
+
+    for dir in [chapter_dir, src_dir] {
+        let file = normalize(&dir.join(&path));
+        if !file.is_file() {
+            continue;
+        }
+        if let Some(declared) = declared {
+            let relative = file.strip_prefix(src_dir).ok();
+            if !relative.is_some_and(|relative| declared.iter().any(|d| d == relative)) {
+                bail!(
+                    "embedded diagram {} is not declared in `includes`, which is required in pure mode",
+                    target
+                );
+            }
+        }
+        if let Ok(source) = std::fs::read_to_string(&file) {
+            return Ok(Some(source));
+        }
+    }
+    warn!("could not find embedded diagram {}", target);
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands() {
+        let src = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(src.path().join("guide")).unwrap();
+        std::fs::write(src.path().join("guide/local.puml"), "A -> B\n").unwrap();
+        std::fs::write(src.path().join("shared.puml"), "C -> D").unwrap();
+
+        let s = "![[local.puml]]\n\n![[shared.puml|Shared]] after\n\n![[photo.png]] ![[missing.puml]]\n";
+        let (expanded, lines) = expand(s, Path::new("guide/a.md"), src.path(), None).unwrap();
+        assert_eq!(
+            expanded,
+            "```plantuml\nA -> B\n```\n\n```plantuml\nC -> D\n```\n after\n\n![[photo.png]] ![[missing.puml]]\n"
+        );
+        let original: Vec<_> = (1..=10).map(|line| lines.original(line)).collect();
+        assert_eq!(original, [1, 1, 1, 2, 3, 3, 3, 3, 4, 5]);
+
+        // only embeds starting a line outside of code are expanded
+        let s = "See ![[local.puml]]\n\n```md\n![[local.puml]]\n```\n\nSo `a\n![[local.puml]]` b\n\n```\n![[local.puml]]\n";
+        let (expanded, lines) = expand(s, Path::new("guide/a.md"), src.path(), None).unwrap();
+        assert_eq!(expanded, s);
+        assert!(lines.is_empty());
+    }
+
+    #[test]
+    fn pure() {
+        let src = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(src.path().join("guide")).unwrap();
+        std::fs::write(src.path().join("guide/local.puml"), "A -> B\n").unwrap();
+        std::fs::write(src.path().join("shared.puml"), "C -> D\n").unwrap();
+
+        let declared = [PathBuf::from("guide/local.puml")];
+        let expanded = expand(
+            "![[local.puml]]\n",
+            Path::new("guide/a.md"),
+            src.path(),
+            Some(&declared),
+        );
+        assert_eq!(expanded.unwrap().0, "```plantuml\nA -> B\n```\n");
+        let err = expand(
+            "![[shared.puml]]\n\n![[../shared.puml]]\n",
+            Path::new("guide/a.md"),
+            src.path(),
+            Some(&declared),
+        );
+        let err = err.unwrap_err();
+        let errors = err.downcast_ref::<RenderErrors>().unwrap();
+        let lines: Vec<_> = errors.0.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [1, 3]);
+        assert!(errors.0[0]
+            .to_string()
+            .contains("not declared in `includes`"));
+    }
+}
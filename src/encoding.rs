@@ -0,0 +1,84 @@
+//! PlantUML's text encoding, used by its servers to take a diagram in the url.
+//!
+//! The source is deflated, then written with plantuml's own base64 alphabet.
+//! See <https://plantuml.com/text-encoding>.
+
+use anyhow::{anyhow, bail, Result};
+
+const ALPHABET: &[u8; 64] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz-_";
+
+/// Encodes a diagram the way plantuml servers expect it in their urls,
+/// eg `https://www.plantuml.com/plantuml/svg/<encoded>`
+pub fn encode_plantuml(source: &str) -> String {
+    let deflated = miniz_oxide::deflate::compress_to_vec(source.as_bytes(), 9);
+
+    let mut encoded = String::with_capacity(deflated.len().div_ceil(3) * 4);
+    for chunk in deflated.chunks(3) {
+        // a partial chunk at the end is padded with zeros, not `=`
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let sextets = [
+            b[0] >> 2,
+            (b[0] & 0x3) << 4 | b[1] >> 4,
+            (b[1] & 0xf) << 2 | b[2] >> 6,
+            b[2] & 0x3f,
+        ];
+        encoded.extend(sextets.iter().map(|&s| ALPHABET[s as usize] as char));
+    }
+    encoded
+}
+
+/// Decodes a diagram from the encoded part of a plantuml server url
+pub fn decode_plantuml(encoded: &str) -> Result<String> {
+    let sextets = encoded
+        .bytes()
+        .map(|b| match ALPHABET.iter().position(|&a| a == b) {
+            Some(i) => Ok(i as u8),
+            None => bail!("{:?} is not in the plantuml encoding alphabet", b as char),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut deflated = Vec::with_capacity(sextets.len() / 4 * 3);
+    for s in sextets.chunks(4) {
+        let s = [
+            s[0],
+            s.get(1).copied().unwrap_or(0),
+            s.get(2).copied().unwrap_or(0),
+            s.get(3).copied().unwrap_or(0),
+        ];
+        deflated.extend([
+            s[0] << 2 | s[1] >> 4,
+            s[1] << 4 | s[2] >> 2,
+            s[2] << 6 | s[3],
+        ]);
+    }
+
+    // the zero padding is ignored once the deflate stream has ended
+    let inflated = miniz_oxide::inflate::decompress_to_vec(&deflated)
+        .map_err(|err| anyhow!("invalid deflate data: {:?}", err.status))?;
+    Ok(String::from_utf8(inflated)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let source = "@startuml\nBob -> Alice : hello\n@enduml\n";
+        let encoded = encode_plantuml(source);
+        assert!(encoded.bytes().all(|b| ALPHABET.contains(&b)));
+        assert_eq!(encoded.len() % 4, 0);
+        assert_eq!(decode_plantuml(&encoded).unwrap(), source);
+
+        // the example from the plantuml docs, from a different deflate implementation
+        assert_eq!(
+            decode_plantuml("SyfFKj2rKt3CoKnELR1Io4ZDoSa70000").unwrap(),
+            "Bob -> Alice : hello"
+        );
+        assert!(decode_plantuml("not base64!").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::fmt::{self, Write};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A diagram that could not be rendered
+#[derive(Debug)]
+pub struct RenderError {
+    /// Path of the chapter containing the diagram, relative to the book src
+    pub chapter: PathBuf,
+    /// Line the diagram starts on
+    pub line: usize,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {:#}",
+            self.chapter.display(),
+            self.line,
+            self.error
+        )
+    }
+}
+
+/// The kinds of render failure, which can each be given a [`Policy`](crate::Policy)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    SyntaxError,
+    Unsupported,
+    BackendMissing,
+    InvalidOutput,
+    IoError,
+}
+
+impl ErrorClass {
+    /// The class of a render failure
+    pub fn of(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<BackendMissing>().is_some() {
+            ErrorClass::BackendMissing
+        } else if error.downcast_ref::<Unsupported>().is_some() {
+            ErrorClass::Unsupported
+        } else if error.downcast_ref::<InvalidOutput>().is_some() {
+            ErrorClass::InvalidOutput
+        } else if error.chain().any(|cause| cause.is::<std::io::Error>()) {
+            ErrorClass::IoError
+        } else {
+            ErrorClass::SyntaxError
+        }
+    }
+}
+
+/// Plantuml couldn't be run, with what the shell or java said about it and where
+/// plantuml might be found
+#[derive(Debug)]
+pub(crate) struct BackendMissing(pub String);
+
+impl fmt::Display for BackendMissing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not run plantuml: {}", self.0)
+    }
+}
+
+impl std::error::Error for BackendMissing {}
+
+/// Plantuml can't render the diagram to the format, with why
+#[derive(Debug)]
+pub(crate) struct Unsupported(pub String);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Plantuml succeeded, but wrote an image that is empty or not of its format
+#[derive(Debug)]
+pub(crate) struct InvalidOutput(pub String);
+
+impl fmt::Display for InvalidOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidOutput {}
+
+/// Every diagram in the book that failed to render, grouped by chapter
+#[derive(Debug)]
+pub struct RenderErrors(pub Vec<RenderError>);
+
+impl fmt::Display for RenderErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.len() {
+            1 => write!(f, "1 diagram failed to render")?,
+            n => write!(f, "{} diagrams failed to render", n)?,
+        }
+        write_grouped(f, &self.0)
+    }
+}
+
+impl std::error::Error for RenderErrors {}
+
+/// Every lint failure in the book, grouped by chapter
+#[derive(Debug)]
+pub struct LintErrors(pub Vec<RenderError>);
+
+impl fmt::Display for LintErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.len() {
+            1 => write!(f, "1 lint failure")?,
+            n => write!(f, "{} lint failures", n)?,
+        }
+        write_grouped(f, &self.0)
+    }
+}
+
+impl std::error::Error for LintErrors {}
+
+fn write_grouped(f: &mut fmt::Formatter<'_>, errors: &[RenderError]) -> fmt::Result {
+    let mut chapter = None;
+    for error in errors {
+        if chapter != Some(&error.chapter) {
+            chapter = Some(&error.chapter);
+            write!(f, "\n{}:", error.chapter.display())?;
+        }
+        write!(f, "\n  line {}: {:#}", error.line, error.error)?;
+    }
+    Ok(())
+}
+
+/// Formats a [`RenderErrors`] or [`LintErrors`] like compiler diagnostics,
+/// quoting the chapter line each error points at.
+///
+/// Returns `None` for any other error, which has nothing to point at.
+pub fn report(err: &anyhow::Error, src_dir: &Path) -> Option<String> {
+    let (summary, errors) = if let Some(errors) = err.downcast_ref::<RenderErrors>() {
+        (summary(errors), &errors.0)
+    } else if let Some(errors) = err.downcast_ref::<LintErrors>() {
+        (summary(errors), &errors.0)
+    } else {
+        return None;
+    };
+    Some(write_report(&summary, errors, src_dir, use_color()))
+}
+
+/// The first line of an error's message
+fn summary(err: &impl fmt::Display) -> String {
+    err.to_string()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Whether to color diagnostics, following <https://no-color.org>.
+/// CI logs usually render colors, even though they aren't terminals.
+fn use_color() -> bool {
+    if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        return false;
+    }
+    std::env::var_os("CI").is_some() || std::io::stderr().is_terminal()
+}
+
+fn write_report(summary: &str, errors: &[RenderError], src_dir: &Path, color: bool) -> String {
+    let paint = |style: &str, text: &str| match color {
+        true => format!("{}{}{}", style, text, RESET),
+        false => text.to_owned(),
+    };
+
+    let mut out = String::new();
+    for error in errors {
+        let chapter = std::fs::read_to_string(src_dir.join(&error.chapter)).unwrap_or_default();
+        let mut lines = chapter.lines().skip(error.line.saturating_sub(1));
+        let mut snippet = lines.next().into_iter().collect::<Vec<_>>();
+        // the fence alone doesn't say which diagram it is
+        if snippet.first().is_some_and(|l| l.starts_with("```")) {
+            snippet.extend(lines.next());
+        }
+
+        let number = (error.line + snippet.len().saturating_sub(1)).to_string();
+        let gutter = " ".repeat(number.len());
+        let message = format!("{:#}", error.error);
+        let _ = writeln!(
+            out,
+            "{}{}",
+            paint(RED, "error"),
+            paint(BOLD, &format!(": {}", message))
+        );
+        let _ = writeln!(
+            out,
+            "{}{} {}:{}",
+            gutter,
+            paint(BLUE, "-->"),
+            error.chapter.display(),
+            error.line
+        );
+        if !snippet.is_empty() {
+            let _ = writeln!(out, "{} {}", gutter, paint(BLUE, "|"));
+            for (i, line) in snippet.iter().enumerate() {
+                let number = format!("{:>w$}", error.line + i, w = gutter.len());
+                let _ = writeln!(
+                    out,
+                    "{} {} {}",
+                    paint(BLUE, &number),
+                    paint(BLUE, "|"),
+                    line
+                );
+            }
+            let _ = writeln!(out, "{} {}", gutter, paint(BLUE, "|"));
+        }
+        out.push('\n');
+    }
+    let _ = writeln!(
+        out,
+        "{}{}",
+        paint(RED, "error"),
+        paint(BOLD, &format!(": {}", summary))
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn reports() {
+        let src = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            src.path().join("a.md"),
+            "# A\n\n```plantuml\n@startuml Login\nA ->\n@enduml\n```\n",
+        )
+        .unwrap();
+
+        let errors = RenderErrors(vec![RenderError {
+            chapter: "a.md".into(),
+            line: 3,
+            error: anyhow!("Syntax Error?").context("could not compile plantuml"),
+        }]);
+        assert_eq!(
+            write_report(&summary(&errors), &errors.0, src.path(), false),
+            "error: could not compile plantuml: Syntax Error?
+ --> a.md:3
+  |
+3 | ```plantuml
+4 | @startuml Login
+  |
+
+error: 1 diagram failed to render
+"
+        );
+
+        let colored = write_report(&summary(&errors), &errors.0, src.path(), true);
+        assert!(colored.starts_with("\x1b[1;31merror\x1b[0m"));
+
+        assert!(report(&anyhow!("other"), src.path()).is_none());
+    }
+}
@@ -0,0 +1,45 @@
+//! A synthesized chapter showing every diagram of the book at once
+
+use crate::compiler::{escape_html, url_path};
+use crate::{chapter_names, Target};
+use mdbook::book::{Book, Chapter};
+use std::fmt::Write;
+
+pub(crate) const GALLERY_PATH: &str = "plantuml_gallery.md";
+const GALLERY_TITLE: &str = "Diagram Gallery";
+
+/// Builds the gallery chapter, with a figure linking back to its chapter for each diagram
+pub(crate) fn gallery(book: &Book, results: &[Target]) -> Chapter {
+    let chapters = chapter_names(book);
+
+    let mut content = format!("# {}\n\n", GALLERY_TITLE);
+    content.push_str(
+        r#"<div class="plantuml-gallery" style="display: grid; grid-template-columns: repeat(auto-fill, minmax(16em, 1fr)); gap: 1em;">"#,
+    );
+    content.push('\n');
+
+    for target in results {
+        let chapter = chapters.get(target.chapter.as_path()).unwrap_or(&"");
+        let mut href = url_path(&target.chapter.with_extension("html"));
+        if let Some(id) = &target.id {
+            href = format!("{}#{}", href, id);
+        }
+        let name = target.name.as_deref().unwrap_or("Untitled");
+
+        let _ = write!(
+            content,
+            r#"<figure>
+<a href="{href}"><img src="{src}" alt="{name}"></a>
+<figcaption><a href="{href}">{name}</a> ({chapter})</figcaption>
+</figure>
+"#,
+            href = escape_html(&href),
+            src = escape_html(&url_path(&target.image())),
+            name = escape_html(name),
+            chapter = escape_html(chapter),
+        );
+    }
+    content.push_str("</div>\n");
+
+    Chapter::new(GALLERY_TITLE, content, GALLERY_PATH, vec![])
+}
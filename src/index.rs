@@ -0,0 +1,89 @@
+//! An index of the book's diagrams, grouped by kind and by tag
+
+use crate::compiler::url_path;
+use crate::{chapter_names, Figure, Target};
+use mdbook::book::{Book, Chapter};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+pub(crate) const INDEX_JSON: &str = "index.json";
+const INDEX_PATH: &str = "plantuml_index.md";
+const INDEX_TITLE: &str = "Diagram Index";
+
+/// Where the diagram index is emitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    /// A chapter added to the end of the book
+    Chapter,
+    /// `index.json`, next to the images
+    Json,
+}
+
+/// The figures of the book grouped by kind and by tag
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DiagramIndex {
+    pub kinds: BTreeMap<String, Vec<Figure>>,
+    pub tags: BTreeMap<String, Vec<Figure>>,
+}
+
+impl DiagramIndex {
+    pub fn new(results: &[Target]) -> Self {
+        let mut index = DiagramIndex::default();
+        for target in results {
+            let figure = Figure::from(target);
+            for tag in &target.tags {
+                index
+                    .tags
+                    .entry(tag.clone())
+                    .or_default()
+                    .push(figure.clone());
+            }
+            index
+                .kinds
+                .entry(target.kind.clone())
+                .or_default()
+                .push(figure);
+        }
+        index
+    }
+
+    pub(crate) fn chapter(&self, book: &Book) -> Chapter {
+        let chapters = chapter_names(book);
+
+        let mut content = format!("# {}\n\n## By kind\n", INDEX_TITLE);
+        write_groups(&mut content, &self.kinds, &chapters);
+        if !self.tags.is_empty() {
+            content.push_str("\n## By tag\n");
+            write_groups(&mut content, &self.tags, &chapters);
+        }
+
+        Chapter::new(INDEX_TITLE, content, INDEX_PATH, vec![])
+    }
+}
+
+fn write_groups(
+    content: &mut String,
+    groups: &BTreeMap<String, Vec<Figure>>,
+    chapters: &HashMap<&Path, &str>,
+) {
+    for (group, figures) in groups {
+        let _ = write!(content, "\n### {}\n\n", group);
+        for figure in figures {
+            let mut href = url_path(&figure.chapter);
+            if let Some(id) = &figure.id {
+                href = format!("{}#{}", href, id);
+            }
+            let _ = writeln!(
+                content,
+                "- [{}]({}) ({})",
+                figure.name.as_deref().unwrap_or("Untitled"),
+                href,
+                chapters.get(figure.chapter.as_path()).unwrap_or(&""),
+            );
+        }
+    }
+}
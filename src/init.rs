@@ -0,0 +1,169 @@
+//! `mdbook-puml init`, which sets up an existing book to use the preprocessor,
+//! with a sample chapter of diagrams to start from
+
+use crate::compiler::{resolve_src_dir, REL_OUTDIR};
+use crate::config::{CONFIG_KEY, LEGACY_CONFIG_KEY};
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Path of the sample chapter, relative to the book src
+const SAMPLE_CHAPTER: &str = "diagrams.md";
+
+const SAMPLE: &str = r#"# Diagrams
+
+Diagrams are written in fenced `plantuml` blocks, and rendered into
+`plantuml_images` when the book is built.
+
+## Named diagrams
+
+A diagram named with `@startuml <name>` gets a title, and an anchor to link to,
+such as [the login flow](#fig-login-flow).
+
+```plantuml,alt="Sequence of a user logging in"
+@startuml Login Flow
+actor User
+User -> App: log in
+App -> Auth: check credentials
+Auth --> App: token
+App --> User: welcome
+@enduml
+```
+
+## Light and dark themes
+
+A transparent background with a theme's palette keeps a diagram readable
+on both the light and dark mdbook themes.
+
+```plantuml
+@startuml
+!theme plain
+skinparam backgroundColor transparent
+component Book
+component Preprocessor
+Book -> Preprocessor
+@enduml
+```
+
+```plantuml
+@startuml
+!theme cyborg
+skinparam backgroundColor transparent
+component Book
+component Preprocessor
+Book -> Preprocessor
+@enduml
+```
+"#;
+
+/// Adds the preprocessor to the book at `root`, creates its image directory and
+/// the sample chapter, returning the paths it changed. Whatever is already set up
+/// is left as it is, so it can be run again.
+pub fn init(root: &Path) -> Result<Vec<PathBuf>> {
+    let book_toml = root.join("book.toml");
+    let toml = match std::fs::read_to_string(&book_toml) {
+        Ok(toml) => toml,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => bail!(
+            "{} has no book.toml, create the book with `mdbook init` first",
+            root.display()
+        ),
+        Err(err) => {
+            return Err(err).with_context(|| format!("could not read {}", book_toml.display()))
+        }
+    };
+    let config = mdbook::Config::from_str(&toml)
+        .with_context(|| format!("invalid {}", book_toml.display()))?;
+    let mut changed = vec![];
+
+    let configured = config.get_preprocessor(CONFIG_KEY).is_some()
+        || config.get_preprocessor(LEGACY_CONFIG_KEY).is_some();
+    if !configured {
+        // appended as text, so the rest of the file keeps its formatting
+        let mut toml = toml;
+        if !toml.is_empty() && !toml.ends_with('\n') {
+            toml.push('\n');
+        }
+        toml.push_str(&format!(
+            "\n[preprocessor.{}]\ncommand = \"mdbook-puml\"\n",
+            CONFIG_KEY
+        ));
+        std::fs::write(&book_toml, toml)
+            .with_context(|| format!("could not write {}", book_toml.display()))?;
+        changed.push(book_toml);
+    }
+
+    let src_dir = resolve_src_dir(root, &config.book.src)?;
+    let outdir = src_dir.join(REL_OUTDIR);
+    if !outdir.is_dir() {
+        std::fs::create_dir_all(&outdir)
+            .with_context(|| format!("could not create {}", outdir.display()))?;
+        changed.push(outdir);
+    }
+
+    let sample = src_dir.join(SAMPLE_CHAPTER);
+    if !sample.exists() {
+        std::fs::write(&sample, SAMPLE)
+            .with_context(|| format!("could not write {}", sample.display()))?;
+        changed.push(sample);
+    }
+
+    let summary = src_dir.join("SUMMARY.md");
+    let mut contents = std::fs::read_to_string(&summary).unwrap_or_default();
+    if !contents.contains(&format!("]({})", SAMPLE_CHAPTER)) {
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&format!("- [Diagrams]({})\n", SAMPLE_CHAPTER));
+        std::fs::write(&summary, contents)
+            .with_context(|| format!("could not write {}", summary.display()))?;
+        changed.push(summary);
+    }
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Compiler, Config};
+    use mdbook::book::{Book, Chapter};
+
+    #[test]
+    fn initialised() {
+        let root = tempfile::TempDir::new().unwrap();
+        assert!(init(root.path()).is_err());
+
+        std::fs::write(root.path().join("book.toml"), "[book]\ntitle = \"Guide\"").unwrap();
+        std::fs::create_dir(root.path().join("src")).unwrap();
+        std::fs::write(
+            root.path().join("src/SUMMARY.md"),
+            "# Summary\n\n- [Intro](intro.md)",
+        )
+        .unwrap();
+
+        let changed = init(root.path()).unwrap();
+        assert_eq!(changed.len(), 4);
+        let config =
+            mdbook::Config::from_str(&std::fs::read_to_string(&changed[0]).unwrap()).unwrap();
+        assert_eq!(config.book.title.as_deref(), Some("Guide"));
+        assert!(config.get_preprocessor(CONFIG_KEY).is_some());
+        assert!(root.path().join("src").join(REL_OUTDIR).is_dir());
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("src/SUMMARY.md")).unwrap(),
+            "# Summary\n\n- [Intro](intro.md)\n- [Diagrams](diagrams.md)\n"
+        );
+        assert!(init(root.path()).unwrap().is_empty(), "already set up");
+
+        let compiler = Compiler::new(root.path(), Path::new("src"), Config::default()).unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Diagrams",
+            SAMPLE.to_owned(),
+            SAMPLE_CHAPTER,
+            vec![],
+        ));
+        let targets = compiler.scan(&book);
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[0].id.as_deref(), Some("fig-login-flow"));
+    }
+}
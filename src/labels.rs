@@ -0,0 +1,139 @@
+//! The human readable text of diagrams, so spelling and terminology checkers
+//! can cover diagrams as well as prose
+
+use crate::Target;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A piece of text shown in a diagram
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Label {
+    /// Path of the chapter containing the diagram, relative to the book src
+    pub chapter: PathBuf,
+    /// Line of the chapter the text is on
+    pub line: usize,
+    /// What the text is, eg `participant`, `message`, `note` or `title`
+    pub kind: String,
+    pub text: String,
+}
+
+/// Keywords declaring participants, whose names are shown
+const PARTICIPANTS: &[&str] = &[
+    "participant",
+    "actor",
+    "boundary",
+    "control",
+    "entity",
+    "database",
+    "collections",
+    "queue",
+];
+
+/// Keywords whose argument is shown as is
+const TEXTS: &[&str] = &["title", "caption", "header", "footer", "legend"];
+
+/// The labels of every diagram, in book order
+pub fn labels(targets: &[Target]) -> Vec<Label> {
+    let mut labels = vec![];
+    for target in targets {
+        for (line, kind, text) in diagram_labels(&target.input) {
+            labels.push(Label {
+                chapter: target.chapter.clone(),
+                // the source starts on the line after the fence
+                line: target.line + line,
+                kind: kind.to_owned(),
+                text,
+            });
+        }
+    }
+    labels
+}
+
+/// The labels of a diagram, with the line of its source they are on
+fn diagram_labels(source: &str) -> Vec<(usize, &str, String)> {
+    let mut labels = vec![];
+    // the note currently being read, which runs until `end note`
+    let mut note: Option<(usize, Vec<&str>)> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        let n = i + 1;
+
+        if let Some((start, text)) = &mut note {
+            if line == "end note" || line == "endnote" {
+                labels.push((*start, "note", text.join("\n")));
+                note = None;
+            } else {
+                text.push(line);
+            }
+            continue;
+        }
+
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        if keyword == "note" || keyword == "hnote" || keyword == "rnote" {
+            match rest.split_once(':') {
+                Some((_, text)) => labels.push((n, "note", text.trim().to_owned())),
+                None => note = Some((n + 1, vec![])),
+            }
+        } else if TEXTS.contains(&keyword) && !rest.is_empty() {
+            labels.push((n, keyword, unquote(rest).to_owned()));
+        } else if PARTICIPANTS.contains(&keyword) {
+            // `participant "Long Name" as L` shows the quoted name
+            let name = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.split('"').next().unwrap_or(quoted),
+                None => rest.split_whitespace().next().unwrap_or(rest),
+            };
+            if !name.is_empty() {
+                labels.push((n, "participant", name.to_owned()));
+            }
+        } else if line.contains("->") || line.contains("<-") {
+            if let Some((_, text)) = line.split_once(':') {
+                let text = text.trim();
+                if !text.is_empty() {
+                    labels.push((n, "message", text.to_owned()));
+                }
+            }
+        }
+    }
+    labels
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts() {
+        let source = "@startuml Login
+title Logging in
+participant \"Web Browser\" as B
+actor User
+User -> B : enters pasword
+note left: retries are limited
+note over B
+  stores the
+  session cookie
+end note
+@enduml
+";
+        assert_eq!(
+            diagram_labels(source),
+            vec![
+                (2, "title", "Logging in".to_owned()),
+                (3, "participant", "Web Browser".to_owned()),
+                (4, "participant", "User".to_owned()),
+                (5, "message", "enters pasword".to_owned()),
+                (6, "note", "retries are limited".to_owned()),
+                (8, "note", "stores the\nsession cookie".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Versioning of the layout of `plantuml_images`.
+//!
+//! The outdir records the version of the layout it was written with, and the
+//! shard depth of its images, in a `.layout` file. Outdirs with an older layout
+//! or another shard depth are migrated when the book is next built, so a change
+//! of naming scheme doesn't leave stale copies of every image behind, or render
+//! them all again. An outdir from a newer release is refused rather than mixed
+//! with a layout it doesn't understand.
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Version of the layout this release writes
+pub(crate) const LAYOUT_VERSION: u32 = 2;
+
+/// The file in the outdir recording its layout version
+pub(crate) const LAYOUT: &str = ".layout";
+
+/// Each migration moves an outdir from the version of its index to the next,
+/// given the shard depth its images should end up at.
+///
+/// Outdirs from before the marker was introduced are version 0, and have the
+/// same flat `<uuid>.<ext>` layout as version 1, so they only need marking.
+/// Version 1 didn't record the shard depth, so its images are resharded from
+/// wherever they are.
+const MIGRATIONS: &[fn(&Path, usize) -> Result<()>] = &[|_, _| Ok(()), reshard];
+
+/// The layout recorded by the marker, or version 0 when there is none
+struct Marker {
+    version: u32,
+    shard_depth: Option<usize>,
+}
+
+impl Marker {
+    fn read(path: &Path) -> Result<Self> {
+        let marker = match std::fs::read_to_string(path) {
+            Ok(marker) => marker,
+            Err(_) => {
+                return Ok(Marker {
+                    version: 0,
+                    shard_depth: None,
+                })
+            }
+        };
+        let invalid = || format!("invalid layout in {}", path.display());
+        let mut lines = marker.lines();
+        let version = lines
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .parse()
+            .with_context(invalid)?;
+        let mut shard_depth = None;
+        for line in lines {
+            if let Some(depth) = line.strip_prefix("shard-depth ") {
+                shard_depth = Some(depth.trim().parse().with_context(invalid)?);
+            }
+        }
+        Ok(Marker {
+            version,
+            shard_depth,
+        })
+    }
+}
+
+/// Brings the outdir up to the current layout version and shard depth,
+/// marking it with both
+pub(crate) fn migrate(outdir: &Path, shard_depth: usize) -> Result<()> {
+    let path = outdir.join(LAYOUT);
+    let marker = Marker::read(&path)?;
+    if marker.version > LAYOUT_VERSION {
+        bail!(
+            "{} has layout version {}, but this mdbook-puml only understands up to version {}. \
+            Upgrade mdbook-puml, or delete the directory to rebuild it",
+            outdir.display(),
+            marker.version,
+            LAYOUT_VERSION
+        );
+    }
+    if marker.version == LAYOUT_VERSION && marker.shard_depth == Some(shard_depth) {
+        return Ok(());
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(marker.version as usize) {
+        info!(
+            "migrating {} to layout version {}",
+            outdir.display(),
+            from + 1
+        );
+        migration(outdir, shard_depth)?;
+    }
+    if marker.version == LAYOUT_VERSION {
+        info!(
+            "moving the images in {} to shard depth {}",
+            outdir.display(),
+            shard_depth
+        );
+        reshard(outdir, shard_depth)?;
+    }
+    std::fs::write(
+        &path,
+        format!("{}\nshard-depth {}\n", LAYOUT_VERSION, shard_depth),
+    )
+    .with_context(|| format!("could not write {}", path.display()))
+}
+
+/// The directories the files of an output are stored under, eg `ab/cd`
+pub(crate) fn shard(output: Uuid, depth: usize) -> PathBuf {
+    let hash = output.to_simple().to_string();
+    (0..depth).map(|i| &hash[2 * i..2 * i + 2]).collect()
+}
+
+/// The output a file in the outdir belongs to. Only outputs are named by uuid,
+/// the metadata files aren't.
+pub(crate) fn output_of(file: &Path) -> Option<Uuid> {
+    file.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| Uuid::parse_str(stem).ok())
+}
+
+/// Moves every output into its shard at the given depth, removing the shards left empty
+fn reshard(outdir: &Path, depth: usize) -> Result<()> {
+    let (files, shards) = sharded_files(outdir)?;
+    for file in files {
+        let (output, name) = match (output_of(&file), file.file_name()) {
+            (Some(output), Some(name)) => (output, name),
+            _ => continue,
+        };
+        let to = shard(output, depth).join(name);
+        if to == file {
+            continue;
+        }
+        let to = outdir.join(to);
+        if let Some(dir) = to.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        std::fs::rename(outdir.join(&file), &to)
+            .with_context(|| format!("could not move {} to {}", file.display(), to.display()))?;
+    }
+    // deepest first, so their parents can be emptied too
+    for shard in shards.iter().rev() {
+        let _ = std::fs::remove_dir(outdir.join(shard));
+    }
+    Ok(())
+}
+
+/// The files in the directory and its shard directories, and the shard directories,
+/// relative to it. Shards are listed before the shards within them.
+pub(crate) fn sharded_files(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let (mut files, mut shards) = (vec![], vec![]);
+    let mut queue = vec![PathBuf::new()];
+    while let Some(relative) = queue.pop() {
+        let path = dir.join(&relative);
+        let entries = std::fs::read_dir(&path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let is_shard = name.len() == 2
+                && name
+                    .to_str()
+                    .is_some_and(|name| name.bytes().all(|b| b.is_ascii_hexdigit()));
+            if entry.file_type()?.is_dir() {
+                if is_shard {
+                    shards.push(relative.join(&name));
+                    queue.push(relative.join(&name));
+                }
+            } else {
+                files.push(relative.join(&name));
+            }
+        }
+    }
+    Ok((files, shards))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        let outdir = tempfile::TempDir::new().unwrap();
+        let marker = outdir.path().join(LAYOUT);
+        assert_eq!(MIGRATIONS.len(), LAYOUT_VERSION as usize);
+
+        migrate(outdir.path(), 0).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&marker).unwrap(),
+            "2\nshard-depth 0\n"
+        );
+        migrate(outdir.path(), 0).unwrap();
+
+        std::fs::write(&marker, "3\n").unwrap();
+        assert!(migrate(outdir.path(), 0).is_err());
+        std::fs::write(&marker, "one").unwrap();
+        assert!(migrate(outdir.path(), 0).is_err());
+    }
+
+    #[test]
+    fn reshards() {
+        let outdir = tempfile::TempDir::new().unwrap();
+        let output = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        let hash = output.to_simple().to_string();
+        let flat = outdir.path().join(output.to_string());
+        std::fs::write(flat.with_extension("svg"), "").unwrap();
+        std::fs::write(flat.with_extension("puml"), "").unwrap();
+        std::fs::write(outdir.path().join("manifest.json"), "").unwrap();
+
+        // version 1 didn't record a depth, so the images are moved from wherever they are
+        std::fs::write(outdir.path().join(LAYOUT), "1\n").unwrap();
+        migrate(outdir.path(), 2).unwrap();
+        let sharded = outdir
+            .path()
+            .join(&hash[..2])
+            .join(&hash[2..4])
+            .join(output.to_string());
+        assert!(sharded.with_extension("svg").exists());
+        assert!(sharded.with_extension("puml").exists());
+        assert!(!flat.with_extension("svg").exists());
+        assert!(outdir.path().join("manifest.json").exists());
+
+        // and back again when unsharded, removing the empty shards
+        migrate(outdir.path(), 0).unwrap();
+        assert!(flat.with_extension("svg").exists());
+        assert!(flat.with_extension("puml").exists());
+        assert!(!outdir.path().join(&hash[..2]).exists());
+    }
+}
@@ -1,55 +1,164 @@
-use aho_corasick::{AhoCorasick, AhoCorasickBuilder, FindIter, MatchKind};
-use anyhow::{anyhow, Context, Result};
-use lazy_static::lazy_static;
+//! Without the default `render` feature, only the [`preview`] module is built.
+
+#[cfg(feature = "render")]
+use anyhow::Result;
+#[cfg(feature = "render")]
 use mdbook::book::Book;
+#[cfg(feature = "render")]
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+#[cfg(feature = "render")]
 use mdbook::BookItem;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
-use std::path::{Path, PathBuf};
-use std::process::Command;
-use tempfile::TempDir;
-use uuid::Uuid;
+#[cfg(feature = "render")]
+use std::collections::HashMap;
+#[cfg(feature = "render")]
+use std::path::Path;
 
+#[cfg(feature = "render")]
 #[macro_use]
-extern crate log;
-
-const REL_OUTDIR: &str = "plantuml_images";
-const SVG: &str = "svg";
-const PUML: &str = "puml";
+extern crate tracing;
+
+mod encoding;
+mod markdown;
+pub mod preview;
+
+#[cfg(feature = "render")]
+mod aliases;
+#[cfg(feature = "render")]
+mod capabilities;
+#[cfg(feature = "render")]
+mod compare;
+#[cfg(feature = "render")]
+mod compiler;
+#[cfg(feature = "render")]
+mod config;
+#[cfg(feature = "render")]
+pub mod daemon;
+#[cfg(feature = "render")]
+pub mod doctor;
+#[cfg(feature = "render")]
+mod embeds;
+#[cfg(feature = "render")]
+mod errors;
+#[cfg(feature = "render")]
+mod gallery;
+#[cfg(feature = "render")]
+mod index;
+#[cfg(feature = "render")]
+pub mod init;
+#[cfg(feature = "render")]
+mod labels;
+#[cfg(feature = "render")]
+mod layout;
+#[cfg(feature = "render")]
+mod lint;
+#[cfg(feature = "render")]
+mod manifest;
+#[cfg(feature = "render")]
+mod messages;
+#[cfg(feature = "render")]
+mod metadata;
+#[cfg(feature = "render")]
+pub mod migrate;
+#[cfg(feature = "render")]
+mod observer;
+#[cfg(feature = "render")]
+mod pages;
+#[cfg(feature = "render")]
+mod probe;
+#[cfg(feature = "render")]
+mod pure;
+#[cfg(feature = "rasterize")]
+mod rasterize;
+#[cfg(feature = "render")]
+mod render_log;
+#[cfg(feature = "render")]
+mod report;
+#[cfg(feature = "render")]
+mod resolve;
+#[cfg(feature = "render")]
+mod resume;
+#[cfg(feature = "render")]
+mod stdlib;
+#[cfg(feature = "render")]
+pub mod style;
+#[cfg(feature = "render")]
+mod theme;
+#[cfg(feature = "render")]
+pub use compare::Regression;
+#[cfg(feature = "render")]
+pub use compiler::{resolve_src_dir, CacheStatus, Compiler, Plan, RenderedChapter, Target};
+#[cfg(feature = "render")]
+pub use config::{
+    AutoJobs, Batch, Config, Jobs, LayoutEngine, Mode, PlantumlCommand, Policies, Policy,
+    TextFallback,
+};
+pub use encoding::{decode_plantuml, encode_plantuml};
+#[cfg(feature = "render")]
+pub use errors::{report, ErrorClass, LintErrors, RenderError, RenderErrors};
+#[cfg(feature = "render")]
+pub use index::{DiagramIndex, IndexFormat};
+#[cfg(feature = "render")]
+pub use labels::{labels, Label};
+#[cfg(feature = "render")]
+pub use lint::Lints;
+#[cfg(feature = "render")]
+pub use manifest::{Change, Figure, Manifest};
+#[cfg(feature = "render")]
+pub use observer::Observer;
+pub use preview::Format;
+#[cfg(feature = "render")]
+pub use render_log::RenderLogFormat;
+#[cfg(feature = "render")]
+pub use theme::ThemeFigure;
+
+/// The renderer `mdbook test` runs preprocessors for
+#[cfg(feature = "render")]
+const TEST_RENDERER: &str = "test";
 
 /// A preprocessor for prerendering plantuml as images
+#[cfg(feature = "render")]
 pub struct PumlPreprocessor;
 
+#[cfg(feature = "render")]
 impl Preprocessor for PumlPreprocessor {
     fn name(&self) -> &str {
         "plantuml-preprocessor"
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let src_dir = ctx.root.join(&ctx.config.book.src);
-        let outdir = src_dir.join(REL_OUTDIR);
-        std::fs::create_dir_all(&outdir)
-            .with_context(|| format!("could not create {}", outdir.display()))?;
-
-        let compiler = Compiler {
-            tmpdir: TempDir::new_in(src_dir)?,
-            outdir,
-        };
+        let _span = info_span!("mdbook-puml", root = %ctx.root.display()).entered();
+        if ctx.renderer == TEST_RENDERER && !Config::from_context(ctx)?.render_in_tests {
+            // doctests only run rust code blocks, so the diagrams can stay as they are
+            info!("skipping rendering for `mdbook test`");
+            return Ok(book);
+        }
+        let compiler = Compiler::from_context(ctx)?;
 
-        try_for_each_mut(&mut book.sections, &mut |section: &mut BookItem| {
-            if let BookItem::Chapter(ref mut ch) = *section {
-                let depth = ch.path.as_ref().unwrap().components().count();
-                let content = compiler.replace_all(&ch.content, depth - 1)?;
-                ch.content = content;
-            }
-            Ok(())
-        })?;
+        compiler.expand_embeds(&mut book)?;
+        let targets = compiler.scan(&book);
+        compiler.lint(&targets)?;
+        let plan = compiler.plan(targets);
+        let results = compiler.render(plan)?;
+        compiler.write_manifest(&results)?;
+        compiler.compare(&results)?;
+        compiler.splice(&mut book, &results)?;
 
         Ok(book)
     }
 }
 
+/// The names of the book's chapters, by their path
+#[cfg(feature = "render")]
+pub(crate) fn chapter_names(book: &Book) -> HashMap<&Path, &str> {
+    book.iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some((ch.path.as_deref()?, ch.name.as_str())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(feature = "render")]
 pub fn try_for_each_mut<'a, F, I>(items: I, func: &mut F) -> Result<()>
 where
     F: FnMut(&mut BookItem) -> Result<()>,
@@ -65,309 +174,40 @@ where
     Ok(())
 }
 
-#[derive(Debug, PartialEq, Clone)]
-struct Target<'a> {
-    output: Uuid,
-    input: &'a str,
-    name: Option<&'a str>,
-    output_type: &'static str,
-}
-
-struct Compiler {
-    tmpdir: TempDir,
-    outdir: PathBuf,
-}
-
-impl Compiler {
-    fn compile(&self, target: Target) -> Result<()> {
-        let filename = target.output.to_string();
-        let filename = Path::new(&filename);
-        let outfile = self
-            .outdir
-            .join(filename.with_extension(target.output_type));
-
-        // check if we have it cached
-        if outfile.exists() {
-            info!("{} exists. returning early", target.output);
-            return Ok(());
-        }
-
-        // write the puml contents to a tmp file
-        let input = self.tmpdir.path().join(filename.with_extension(PUML));
-        std::fs::write(&input, &target.input).with_context(|| "could not create tmp puml file")?;
-
-        // execute plantuml cli
-        let script = format!(
-            "plantuml -t{} -nometadata {}",
-            target.output_type,
-            input.display(),
-        );
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(script)
-            .output()
-            .with_context(|| "could not invoke plantuml")?;
-
-        if !output.status.success() {
-            let mut err = anyhow!("{}", target.input);
-
-            if let Ok(stderr) = String::from_utf8(output.stderr) {
-                err = err.context(stderr)
-            }
-
-            return Err(err.context("could not compile plantuml"));
-        }
-
-        // move the compiled file to the outdir
-        let output = match &target.name {
-            Some(name) => Path::new(name),
-            None => filename,
-        };
-        let output = self
-            .tmpdir
-            .path()
-            .join(output.with_extension(target.output_type));
-        std::fs::rename(&output, &outfile).with_context(|| {
-            format!(
-                "could not move compiled file ({}) to outdir ({})",
-                output.display(),
-                outfile.display()
-            )
-        })?;
-
-        Ok(())
-    }
-
-    fn replace_all(&self, s: &str, depth: usize) -> Result<String> {
-        // When replacing one thing in a string by something with a different length,
-        // the indices after that will not correspond,
-        // we therefore have to store the difference to correct this
-        let mut previous_end_index = 0;
-        let mut replaced = String::new();
-
-        for link in find_pumls(s) {
-            replaced.push_str(&s[previous_end_index..link.start]);
-
-            let new_content = link.render(self, depth)?;
-            replaced.push_str(&new_content);
-            previous_end_index = link.end;
-        }
-
-        replaced.push_str(&s[previous_end_index..]);
-        Ok(replaced)
-    }
-}
-
-#[derive(PartialEq, Debug, Clone)]
-struct Puml<'a> {
-    start: usize,
-    end: usize,
-    contents: &'a str,
-    ignore: bool,
-}
-
-impl<'a> Puml<'a> {
-    fn uuid(&self) -> Uuid {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(self.contents.as_bytes());
-
-        let lhs = hasher.finish() as u128;
-        hasher.write_u8(0);
-        let rhs = hasher.finish() as u128;
-        Uuid::from_u128(lhs << 64 | rhs)
-    }
-
-    fn render(&self, compiler: &Compiler, depth: usize) -> Result<String> {
-        if self.ignore {
-            return Ok(format!(
-                r#"```plantuml
-{}```"#,
-                self.contents
-            ));
-        }
-
-        let uuid = self.uuid();
-        let name = find_name(self.contents);
-        compiler.compile(Target {
-            output: uuid,
-            input: self.contents,
-            name,
-            output_type: SVG,
-        })?;
-
-        Ok(format!(
-            r#"![{}]({}{}/{}.{})"#,
-            name.unwrap_or(""),
-            "../".repeat(depth), // traverse up `depth` folders
-            REL_OUTDIR,          // go into the relative image outdir
-            uuid,                // with the uuid as the filename
-            SVG                  // and svg file extension
-        ))
-    }
-}
-
-struct PumlIter<'a>(&'a str, FindIter<'a, 'a, usize>);
-
-impl<'a> Iterator for PumlIter<'a> {
-    type Item = Puml<'a>;
-    fn next(&mut self) -> Option<Puml<'a>> {
-        let start = loop {
-            let m = self.1.next()?;
-            if m.pattern() != 1 {
-                break m;
-            }
-        };
-
-        let end = self.1.next()?;
-        Some(Puml {
-            start: start.start(),
-            end: end.end(),
-            contents: &self.0[start.end()..end.start()],
-            ignore: start.pattern() == 2,
-        })
-    }
-}
-
-fn find_pumls(contents: &str) -> PumlIter<'_> {
-    // lazily compute following regex
-    // r"\\\{\{#plantuml\}\}|\{\{#plantuml\s*([^}]+)\}\}")?;
-    lazy_static! {
-        static ref AC: AhoCorasick = AhoCorasickBuilder::new()
-            .match_kind(MatchKind::LeftmostLongest)
-            .build(["```plantuml\n", "```", "```plantuml,ignore\n"]);
-    }
-    PumlIter(contents, AC.find_iter(contents))
-}
-
-fn find_name(contents: &str) -> Option<&str> {
-    contents
-        .strip_prefix("@startuml ")
-        .map(|m| match m.find('\n') {
-            Some(i) => &m[..i],
-            None => m,
-        })
-}
-
-#[cfg(test)]
+#[cfg(all(test, feature = "render"))]
 mod tests {
     use super::*;
+    use mdbook::book::Chapter;
 
     #[test]
-    fn test_find_plantuml() {
-        let s = r#"Some random text with
-```plantuml
-@startuml Document Name
-
-UML <-> Document
-
-@enduml
-```
-
-and
-
-```rust
-let foo = "bar";
-```
-
-```plantuml
-@startuml Another Doc
-Foo
-@enduml
-```
-
-```plantuml,ignore
-@startuml
-Foo <-> Bar
-@enduml
-```
-"#;
-
-        let res = find_pumls(s).collect::<Vec<_>>();
-
-        assert_eq!(
-            res,
-            vec![
-                Puml {
-                    start: 22,
-                    end: 88,
-                    contents: "@startuml Document Name\n\nUML <-> Document\n\n@enduml\n",
-                    ignore: false,
-                },
-                Puml {
-                    start: 125,
-                    end: 174,
-                    contents: "@startuml Another Doc\nFoo\n@enduml\n",
-                    ignore: false,
-                },
-                Puml {
-                    start: 176,
-                    end: 228,
-                    contents: "@startuml\nFoo <-> Bar\n@enduml\n",
-                    ignore: true,
-                },
-            ]
-        );
-    }
-
-    #[test]
-    fn replace() {
-        env_logger::init();
-
-        let s = r#"Some random text with
-```plantuml
-@startuml Document Name
-
-UML <-> Document
-
-@enduml
-```
-
-and
-
-```rust
-let foo = "bar";
-```
-
-```plantuml
-@startuml
-Foo <-> Bar
-@enduml
-```
-
-```plantuml,ignore
-@startuml
-Foo <-> Bar
-@enduml
-```
-"#;
-
-        let tmp = TempDir::new().unwrap();
-        let compiler = Compiler {
-            tmpdir: TempDir::new().unwrap(),
-            outdir: tmp.path().to_owned(),
+    fn skipped_by_mdbook_test() {
+        let root = tempfile::TempDir::new().unwrap();
+        let context = |renderer: &str, config: &str| -> PreprocessorContext {
+            let config: mdbook::Config = config.parse().unwrap();
+            serde_json::from_value(serde_json::json!({
+                "root": root.path(),
+                "config": config,
+                "renderer": renderer,
+                "mdbook_version": mdbook::MDBOOK_VERSION,
+            }))
+            .unwrap()
         };
-
-        let res = compiler.replace_all(s, 2).unwrap();
-
-        assert_eq!(
-            res,
-            r#"Some random text with
-![Document Name](../../plantuml_images/bd15ddc5-f769-719d-dbb5-be1228872d69.svg)
-
-and
-
-```rust
-let foo = "bar";
-```
-
-![](../../plantuml_images/3a1375f3-0f44-4b13-f722-de95a4661ce7.svg)
-
-```plantuml
-@startuml
-Foo <-> Bar
-@enduml
-```
-"#
-        );
+        let mut book = Book::new();
+        let content = "```plantuml\nA -> B\n```\n";
+        book.push_item(Chapter::new("A", content.to_owned(), "a.md", vec![]));
+
+        // plantuml would fail, if it were run
+        let config = "[preprocessor.puml]\nplantuml-command = \"false\"\n";
+        let tested = PumlPreprocessor
+            .run(&context(TEST_RENDERER, config), book.clone())
+            .unwrap();
+        assert_eq!(tested, book);
+
+        let config = format!("{}render-in-tests = true\n", config);
+        let ctx = context(TEST_RENDERER, &config);
+        assert!(PumlPreprocessor.run(&ctx, book.clone()).is_err());
+        assert!(PumlPreprocessor
+            .run(&context("html", &config), book)
+            .is_err());
     }
 }
@@ -0,0 +1,202 @@
+//! Opt-in checks that diagrams follow a book's documentation standards
+
+use crate::{RenderError, Target};
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The lint rules to check, from the `[preprocessor.puml.lint]` table
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Lints {
+    /// Diagrams must be named with `@startuml <name>`
+    pub require_name: bool,
+    /// Diagrams must have a `title`
+    pub require_title: bool,
+    /// Forbids the deprecated `!includeurl`, and `!include` of urls
+    pub forbid_includeurl: bool,
+    /// Maximum depth of nested blocks, eg packages or `alt`/`loop` groups
+    pub max_nesting: Option<usize>,
+    /// Maximum number of lines in a diagram
+    pub max_lines: Option<usize>,
+    /// Images must have alt text that reads like prose, not an identifier such as `login_flow`
+    pub alt_text: bool,
+    /// Maximum length of alt text, in characters
+    pub max_alt_length: Option<usize>,
+    /// Lint failures fail the build, instead of only warning
+    pub deny: bool,
+}
+
+/// Sequence diagram groups, closed by `end`
+const GROUPS: &[&str] = &[
+    "alt", "opt", "loop", "par", "par2", "break", "critical", "group",
+];
+
+impl Lints {
+    /// Every problem found with the diagrams
+    pub(crate) fn check(&self, targets: &[Target]) -> Vec<RenderError> {
+        let mut problems = vec![];
+        for target in targets {
+            // decorative images rightly have no alt text
+            let alt = (!target.decorative)
+                .then(|| self.check_alt(target.alt_text()))
+                .flatten()
+                .map(|message| (None, message));
+            for (line, message) in self.check_diagram(&target.input).into_iter().chain(alt) {
+                problems.push(RenderError {
+                    chapter: target.chapter.clone(),
+                    // the source starts on the line after the fence
+                    line: target.line + line.unwrap_or(0),
+                    error: anyhow!(message),
+                });
+            }
+        }
+        problems
+    }
+
+    /// Problems with the alt text of an image, suggesting the `alt` attribute
+    fn check_alt(&self, alt: Option<&str>) -> Option<String> {
+        let problem = match alt {
+            None if self.alt_text => "has no alt text".to_owned(),
+            Some(alt) if self.alt_text && looks_like_identifier(alt) => {
+                format!("alt text {:?} reads like an identifier", alt)
+            }
+            Some(alt) => {
+                let max = self.max_alt_length?;
+                let len = alt.chars().count();
+                if len <= max {
+                    return None;
+                }
+                format!("alt text is {} characters long, more than {}", len, max)
+            }
+            None => return None,
+        };
+        Some(format!(
+            "image {}, describe the diagram with `alt=\"...\"`",
+            problem
+        ))
+    }
+
+    /// Problems with a diagram, with the line of its source they are on, if any
+    fn check_diagram(&self, source: &str) -> Vec<(Option<usize>, String)> {
+        let mut problems = vec![];
+        let mut title = false;
+        let mut depth = 0usize;
+        let mut max_depth = 0;
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            let keyword = line.split_whitespace().next().unwrap_or("");
+
+            title |= keyword == "title";
+
+            if self.forbid_includeurl {
+                let url_include = keyword.starts_with("!include") && line.contains("://");
+                if keyword == "!includeurl" || url_include {
+                    problems.push((Some(i + 1), "diagram includes a url".to_owned()));
+                }
+            }
+
+            if line.ends_with('{') || GROUPS.contains(&keyword) {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            if line.starts_with('}') || keyword == "end" {
+                depth = depth.saturating_sub(1);
+            }
+        }
+
+        if self.require_name && crate::markdown::find_name(source).is_none() {
+            problems.push((None, "diagram has no name (`@startuml <name>`)".to_owned()));
+        }
+        if self.require_title && !title {
+            problems.push((None, "diagram has no title".to_owned()));
+        }
+        if let Some(max) = self.max_nesting.filter(|max| max_depth > *max) {
+            problems.push((
+                None,
+                format!("diagram nests {} deep, more than {}", max_depth, max),
+            ));
+        }
+        let lines = source.lines().count();
+        if let Some(max) = self.max_lines.filter(|max| lines > *max) {
+            problems.push((
+                None,
+                format!("diagram is {} lines long, more than {}", lines, max),
+            ));
+        }
+        problems
+    }
+}
+
+/// Whether text is a single word like `login_flow`, `LoginFlow` or `fig2`
+fn looks_like_identifier(text: &str) -> bool {
+    if text.contains(char::is_whitespace) {
+        return false;
+    }
+    let camel_case = text
+        .chars()
+        .zip(text.chars().skip(1))
+        .any(|(a, b)| a.is_lowercase() && b.is_uppercase());
+    text.contains(['_', '-', '.']) || camel_case || text.contains(|c: char| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let lints = Lints {
+            require_name: true,
+            require_title: true,
+            forbid_includeurl: true,
+            max_nesting: Some(1),
+            max_lines: Some(8),
+            alt_text: false,
+            max_alt_length: None,
+            deny: false,
+        };
+
+        let good = "@startuml Login\ntitle Login\nalt ok\nA -> B\nend\n@enduml\n";
+        assert_eq!(lints.check_diagram(good), vec![]);
+
+        let bad = "@startuml\n!includeurl https://example.com/style.puml\npackage a {\nalt ok\nA -> B\nend\n}\n@enduml\n";
+        let messages = lints.check_diagram(bad);
+        assert_eq!(
+            messages,
+            vec![
+                (Some(2), "diagram includes a url".to_owned()),
+                (None, "diagram has no name (`@startuml <name>`)".to_owned()),
+                (None, "diagram has no title".to_owned()),
+                (None, "diagram nests 2 deep, more than 1".to_owned()),
+            ]
+        );
+
+        // nothing is checked by default
+        assert_eq!(Lints::default().check_diagram(bad), vec![]);
+    }
+
+    #[test]
+    fn alt_text() {
+        let lints = Lints {
+            alt_text: true,
+            max_alt_length: Some(20),
+            ..Lints::default()
+        };
+        assert_eq!(lints.check_alt(Some("Logging in")), None);
+        assert_eq!(
+            lints.check_alt(Some("login_flow")),
+            Some(
+                "image alt text \"login_flow\" reads like an identifier, describe the diagram with `alt=\"...\"`"
+                    .to_owned()
+            )
+        );
+        assert!(lints.check_alt(Some("LoginFlow")).is_some());
+        assert!(lints.check_alt(None).is_some());
+        assert!(lints
+            .check_alt(Some("The whole login flow, from start to end"))
+            .is_some());
+        assert_eq!(Lints::default().check_alt(None), None);
+    }
+}
@@ -1,8 +1,16 @@
-use clap::{App, Arg, ArgMatches, SubCommand};
+use anyhow::Context;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
+use mdbook::BookItem;
+use mdbook::MDBook;
+use mdbook_puml::daemon;
+use mdbook_puml::doctor::{self, Status};
+use mdbook_puml::init;
+use mdbook_puml::{migrate, style, Compiler, Config, Figure, Target};
 use semver::{Version, VersionReq};
-use std::io;
+use std::io::{self, Write};
+use std::path::Path;
 use std::process;
 
 pub fn make_app() -> App<'static, 'static> {
@@ -13,9 +21,101 @@ pub fn make_app() -> App<'static, 'static> {
                 .arg(Arg::with_name("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            SubCommand::with_name("list")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .possible_values(&["table", "json"])
+                        .default_value("table"),
+                )
+                .about("List every diagram in the book with its location"),
+        )
+        .subcommand(
+            SubCommand::with_name("labels")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about(
+                    "Print the text shown in every diagram as JSON, for spelling and terminology checks",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Only list the chapters that need formatting, failing if there are any"),
+                )
+                .about("Format the diagrams in the book's chapters"),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Only list the chapters that need migrating, failing if there are any"),
+                )
+                .about("Replace `{{#plantuml file.puml}}` directives with fenced blocks"),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Check that plantuml and the book's config are ready to build the book"),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .subcommand(SubCommand::with_name("schema").about(
+                    "Print the JSON Schema of the [preprocessor.puml] table in book.toml",
+                ))
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .about("Describe the preprocessor's config"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Set up the book to use mdbook-puml, with a sample chapter, and check it builds"),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Answer JSON-RPC render requests for the book over stdin and stdout"),
+        )
 }
 
 fn main() -> anyhow::Result<()> {
+    #[cfg(feature = "otlp")]
+    otlp::init()?;
+    #[cfg(not(feature = "otlp"))]
     env_logger::init();
 
     let matches = make_app().get_matches();
@@ -24,8 +124,29 @@ fn main() -> anyhow::Result<()> {
 
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("list") {
+        handle_list(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("labels") {
+        handle_labels(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("doctor") {
+        handle_doctor(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("init") {
+        handle_init(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("config") {
+        handle_config(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("daemon") {
+        handle_daemon(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("fmt") {
+        rewrite_chapters(sub_args, |_, content| Ok(style::format_markdown(content)))
+    } else if let Some(sub_args) = matches.subcommand_matches("migrate") {
+        rewrite_chapters(sub_args, |path, content| {
+            migrate::migrate_markdown(content, path.parent().expect("Is a file"))
+        })
     } else {
-        handle_preprocessing(&preprocessor)
+        let result = handle_preprocessing(&preprocessor);
+        #[cfg(feature = "otlp")]
+        otlp::shutdown();
+        result
     }
 }
 
@@ -45,7 +166,20 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
         );
     }
 
-    let processed_book = pre.run(&ctx, book)?;
+    let processed_book = match pre.run(&ctx, book) {
+        Ok(book) => book,
+        Err(err) => {
+            // an invalid src dir is the error itself, which has no report
+            let src_dir = mdbook_puml::resolve_src_dir(&ctx.root, &ctx.config.book.src).ok();
+            if let Some(report) = src_dir.and_then(|src_dir| mdbook_puml::report(&err, &src_dir)) {
+                eprint!("{}", report);
+                #[cfg(feature = "otlp")]
+                otlp::shutdown();
+                process::exit(1);
+            }
+            return Err(err);
+        }
+    };
     serde_json::to_writer(io::stdout(), &processed_book)?;
 
     Ok(())
@@ -62,3 +196,207 @@ fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
         process::exit(1);
     }
 }
+
+/// Finds the diagrams of the book in the directory, without rendering anything
+fn scan_book(sub_args: &ArgMatches) -> anyhow::Result<Vec<Target>> {
+    let mut book = MDBook::load(sub_args.value_of("dir").expect("Has default"))?;
+    let config = Config::from_book_config(&book.config, &book.root)?;
+    let compiler = Compiler::new(&book.root, &book.config.book.src, config)?;
+    compiler.expand_embeds(&mut book.book)?;
+    Ok(compiler.scan(&book.book))
+}
+
+/// Rewrites the source file of every chapter in the book.
+/// With `--check`, the files that would change are listed instead.
+fn rewrite_chapters(
+    sub_args: &ArgMatches,
+    rewrite: impl Fn(&Path, &str) -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    let book = MDBook::load(sub_args.value_of("dir").expect("Has default"))?;
+    let src_dir = book.source_dir();
+    let check = sub_args.is_present("check");
+
+    let mut changed = 0;
+    for item in book.iter() {
+        let path = match item {
+            BookItem::Chapter(ch) => match &ch.source_path {
+                Some(path) => src_dir.join(path),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        let rewritten =
+            rewrite(&path, &content).with_context(|| format!("in {}", path.display()))?;
+        if rewritten == content {
+            continue;
+        }
+
+        changed += 1;
+        if check {
+            println!("{}", path.display());
+        } else {
+            std::fs::write(&path, rewritten)
+                .with_context(|| format!("could not write {}", path.display()))?;
+        }
+    }
+
+    if check && changed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn handle_labels(sub_args: &ArgMatches) -> anyhow::Result<()> {
+    let labels = mdbook_puml::labels(&scan_book(sub_args)?);
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &labels)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+fn handle_init(sub_args: &ArgMatches) -> anyhow::Result<()> {
+    let root = Path::new(sub_args.value_of("dir").expect("Has default"));
+    for path in init::init(root)? {
+        println!("wrote {}", path.display());
+    }
+    doctor(root)
+}
+
+fn handle_doctor(sub_args: &ArgMatches) -> anyhow::Result<()> {
+    doctor(Path::new(sub_args.value_of("dir").expect("Has default")))
+}
+
+fn doctor(root: &Path) -> anyhow::Result<()> {
+    let book = MDBook::load(root)?;
+    let checks = doctor::run(&book.config, &book.root);
+    for check in &checks {
+        println!("{}", check);
+    }
+    if checks.iter().any(|check| check.status == Status::Fail) {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn handle_config(sub_args: &ArgMatches) -> anyhow::Result<()> {
+    if sub_args.subcommand_matches("schema").is_some() {
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &Config::schema())?;
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+fn handle_daemon(sub_args: &ArgMatches) -> anyhow::Result<()> {
+    let book = MDBook::load(sub_args.value_of("dir").expect("Has default"))?;
+    let config = Config::from_book_config(&book.config, &book.root)?;
+    let compiler = Compiler::new(&book.root, &book.config.book.src, config)?;
+    daemon::serve(&compiler, io::stdin().lock(), io::stdout().lock())
+}
+
+fn handle_list(sub_args: &ArgMatches) -> anyhow::Result<()> {
+    let figures = scan_book(sub_args)?
+        .iter()
+        .map(Figure::from)
+        .collect::<Vec<_>>();
+
+    let mut stdout = io::stdout().lock();
+    if sub_args.value_of("format") == Some("json") {
+        serde_json::to_writer_pretty(&mut stdout, &figures)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
+    let rows = figures
+        .iter()
+        .map(|figure| {
+            [
+                format!("{}:{}", figure.chapter.display(), figure.line),
+                figure.kind.clone(),
+                figure.hash.to_string(),
+                figure.name.clone().unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["LOCATION", "KIND", "HASH", "NAME"].map(str::to_owned);
+    let mut widths = [0; 3];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = format!(
+            "{:w0$}  {:w1$}  {:w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+        writeln!(stdout, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Exporting the tracing spans over OTLP, so the rendering of many books can be monitored
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use std::sync::OnceLock;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::{EnvFilter, Layer};
+
+    const ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    /// Exports spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set,
+    /// logging to stderr as `RUST_LOG` says. Otherwise only logs, with env_logger.
+    pub fn init() -> anyhow::Result<()> {
+        if std::env::var_os(ENDPOINT).is_none() {
+            env_logger::init();
+            return Ok(());
+        }
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()?;
+        let mut resource = Resource::builder();
+        if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+            resource = resource.with_service_name("mdbook-puml");
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build();
+        let tracer = provider.tracer("mdbook-puml");
+
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .with_filter(EnvFilter::from_default_env()),
+            )
+            .try_init()?;
+        let _ = PROVIDER.set(provider);
+        Ok(())
+    }
+
+    /// Exports the spans that haven't been exported yet, before exiting
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("could not export spans: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! A record of every figure in the book, written next to the images after each build.
+//!
+//! Manifests from previous builds can be used as a base to work out which figures changed.
+
+use crate::Target;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub(crate) const MANIFEST: &str = "manifest.json";
+pub(crate) const CHANGES: &str = "changes.json";
+
+/// A rendered diagram in the book
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Figure {
+    /// Path of the chapter containing the diagram, relative to the book src
+    pub chapter: PathBuf,
+    /// Line the diagram starts on
+    pub line: usize,
+    /// The name given by `@start<kind> <name>`
+    pub name: Option<String>,
+    /// Anchor of the figure within its chapter
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The kind of diagram, eg `sequence` or `mindmap`
+    #[serde(default)]
+    pub kind: String,
+    /// Tags given with the `tags` attribute
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Hash of the diagram source
+    pub hash: Uuid,
+    /// Path of the image, relative to the book src
+    pub image: PathBuf,
+    /// Milliseconds the diagram took to render, when timings are recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub render_ms: Option<u64>,
+}
+
+impl From<&Target> for Figure {
+    fn from(target: &Target) -> Self {
+        Figure {
+            chapter: target.chapter.clone(),
+            line: target.line,
+            name: target.name.clone(),
+            id: target.id.clone(),
+            kind: target.kind.clone(),
+            tags: target.tags.clone(),
+            hash: target.output,
+            image: target.image(),
+            render_ms: None,
+        }
+    }
+}
+
+/// All the figures of a book, in book order
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The version reported by `plantuml -version` when the manifest was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plantuml_version: Option<String>,
+    pub figures: Vec<Figure>,
+}
+
+/// A figure whose source differs from the base build
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub chapter: PathBuf,
+    pub line: usize,
+    pub name: Option<String>,
+    /// The image of the figure in the base build, if it existed
+    pub before: Option<PathBuf>,
+    /// The newly rendered image
+    pub after: PathBuf,
+}
+
+impl Manifest {
+    pub fn new(results: &[Target]) -> Self {
+        Manifest {
+            plantuml_version: None,
+            figures: results.iter().map(Figure::from).collect(),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = std::fs::read(path)
+            .with_context(|| format!("could not read manifest {}", path.display()))?;
+        serde_json::from_slice(&file)
+            .with_context(|| format!("could not parse manifest {}", path.display()))
+    }
+
+    pub fn contains(&self, hash: Uuid) -> bool {
+        self.figures.iter().any(|f| f.hash == hash)
+    }
+
+    /// Lists the figures which are new compared to `base`.
+    ///
+    /// A changed figure is paired with its previous version by name within the
+    /// same chapter, or by position among the unnamed figures of the chapter.
+    pub fn changes(&self, base: &Manifest) -> Vec<Change> {
+        self.figures
+            .iter()
+            .filter(|figure| !base.contains(figure.hash))
+            .map(|figure| {
+                let before = self.previous_version(figure, base);
+                Change {
+                    chapter: figure.chapter.clone(),
+                    line: figure.line,
+                    name: figure.name.clone(),
+                    before: before.map(|f| f.image.clone()),
+                    after: figure.image.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// The figure in `base` that `figure` is a new version of,
+    /// by name within the same chapter, or by position among the unnamed figures of the chapter
+    pub(crate) fn previous_version<'a>(
+        &self,
+        figure: &Figure,
+        base: &'a Manifest,
+    ) -> Option<&'a Figure> {
+        match &figure.name {
+            Some(name) => base
+                .figures
+                .iter()
+                .find(|f| f.chapter == figure.chapter && f.name.as_ref() == Some(name)),
+            None => {
+                let index = self.position(figure);
+                base.unnamed_in_chapter(&figure.chapter).nth(index)
+            }
+        }
+    }
+
+    fn unnamed_in_chapter<'a>(&'a self, chapter: &Path) -> impl Iterator<Item = &'a Figure> {
+        let chapter = chapter.to_owned();
+        self.figures
+            .iter()
+            .filter(move |f| f.chapter == chapter && f.name.is_none())
+    }
+
+    /// The position of an unnamed figure among the others in its chapter
+    fn position(&self, figure: &Figure) -> usize {
+        self.unnamed_in_chapter(&figure.chapter)
+            .position(|f| f == figure)
+            .unwrap_or_default()
+    }
+}
+
+/// Writes `value` as json, leaving the file untouched if it would not change
+pub(crate) fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    write_if_changed(path, &serde_json::to_vec_pretty(value)?)
+}
+
+/// Writes the file, leaving it untouched if it would not change.
+/// Touching files in the src dir would cause `mdbook serve` to rebuild forever.
+pub(crate) fn write_if_changed(path: &Path, contents: &[u8]) -> Result<()> {
+    if std::fs::read(path).is_ok_and(|old| old == contents) {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+    }
+    std::fs::write(path, contents).with_context(|| format!("could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn figure(chapter: &str, line: usize, name: Option<&str>, hash: u128) -> Figure {
+        Figure {
+            chapter: chapter.into(),
+            line,
+            name: name.map(str::to_owned),
+            id: None,
+            kind: "sequence".to_owned(),
+            tags: vec![],
+            hash: Uuid::from_u128(hash),
+            image: format!("plantuml_images/{}.svg", Uuid::from_u128(hash)).into(),
+            render_ms: None,
+        }
+    }
+
+    #[test]
+    fn changes() {
+        let base = Manifest {
+            plantuml_version: None,
+            figures: vec![
+                figure("a.md", 1, Some("Login"), 1),
+                figure("a.md", 10, None, 2),
+                figure("b.md", 1, None, 3),
+            ],
+        };
+        let new = Manifest {
+            plantuml_version: None,
+            figures: vec![
+                figure("a.md", 1, None, 4),
+                figure("a.md", 10, Some("Login"), 5),
+                figure("b.md", 1, None, 3),
+                figure("b.md", 8, None, 6),
+            ],
+        };
+
+        let changes = new.changes(&base);
+        let before = changes.iter().map(|c| c.before.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            before,
+            vec![
+                // unnamed figures pair up with unnamed ones, never the named
+                Some(base.figures[1].image.clone()),
+                Some(base.figures[0].image.clone()),
+                None,
+            ]
+        );
+    }
+}
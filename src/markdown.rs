@@ -0,0 +1,695 @@
+// the rendering parts are unused by the preview-only build
+#![cfg_attr(not(feature = "render"), allow(dead_code))]
+
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, FindIter, MatchKind};
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::iter::Peekable;
+use std::ops::Range;
+use uuid::Uuid;
+
+/// The opening of a plantuml block's fence
+pub(crate) const PLANTUML_FENCE: &str = "```plantuml";
+
+#[derive(PartialEq, Debug, Clone)]
+pub(crate) struct Puml<'a> {
+    pub start: usize,
+    pub end: usize,
+    /// the rest of the opening fence line after `plantuml`, eg `,ignore`
+    pub info: &'a str,
+    pub contents: &'a str,
+    pub ignore: bool,
+}
+
+impl<'a> Puml<'a> {
+    /// Identifies the rendered image, by the diagram and the flags it's rendered with
+    pub fn uuid(&self, flags: &[String]) -> Uuid {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(self.contents.as_bytes());
+        for flag in flags {
+            hasher.write_u8(0xff);
+            hasher.write(flag.as_bytes());
+        }
+
+        let lhs = hasher.finish() as u128;
+        hasher.write_u8(0);
+        let rhs = hasher.finish() as u128;
+        Uuid::from_u128(lhs << 64 | rhs)
+    }
+
+    pub fn attributes(&self) -> Attributes<'a> {
+        Attributes::parse(self.info)
+    }
+}
+
+pub(crate) struct PumlIter<'a> {
+    s: &'a str,
+    fences: Peekable<FindIter<'a, 'a, usize>>,
+    /// offsets of the `plantuml` fences that were never closed
+    unterminated: Vec<usize>,
+}
+
+impl<'a> Iterator for PumlIter<'a> {
+    type Item = Puml<'a>;
+    fn next(&mut self) -> Option<Puml<'a>> {
+        'open: loop {
+            let start = self.fences.next()?;
+            if start.pattern() != 0 {
+                continue;
+            }
+
+            let rest = &self.s[start.end()..];
+            let info = match rest.find('\n') {
+                Some(end) => &rest[..end],
+                None => {
+                    self.unterminated.push(start.start());
+                    continue;
+                }
+            };
+            // only `plantuml` on its own or followed by attributes, not eg `plantumlfoo`.
+            // Info strings can't contain backticks, so "```plantuml ```" is inline code.
+            let attributes = info.is_empty() || info.starts_with([',', ' ', '\t', '\r']);
+            if !attributes || info.contains('`') {
+                continue;
+            }
+
+            let contents_start = start.end() + info.len() + 1;
+            let end = loop {
+                let m = match self.fences.peek() {
+                    Some(m) => m.clone(),
+                    None => {
+                        self.unterminated.push(start.start());
+                        return None;
+                    }
+                };
+                if m.start() < contents_start {
+                    self.fences.next();
+                    continue;
+                }
+                if m.pattern() != 0 && closes_fence(&self.s[m.end()..]) {
+                    self.fences.next();
+                    break m;
+                }
+                // another block opens first, so this one was never closed.
+                // It's left as it is, and the other block is found as usual.
+                self.unterminated.push(start.start());
+                continue 'open;
+            };
+
+            return Some(Puml {
+                start: start.start(),
+                end: end.end(),
+                info: info.trim_end_matches('\r'),
+                contents: &self.s[contents_start..end.start()],
+                ignore: Attributes::parse(info).flag("ignore"),
+            });
+        }
+    }
+}
+
+/// Whether a "```" followed by `rest` closes a block, with nothing else on its line
+fn closes_fence(rest: &str) -> bool {
+    let line = rest.split('\n').next().unwrap_or("");
+    line.trim().is_empty()
+}
+
+lazy_static! {
+    /// Finds the fences of plantuml blocks, and of any other block
+    static ref FENCES: AhoCorasick = AhoCorasickBuilder::new()
+        .match_kind(MatchKind::LeftmostLongest)
+        .build([PLANTUML_FENCE, "```"]);
+}
+
+pub(crate) fn find_pumls(contents: &str) -> PumlIter<'_> {
+    PumlIter {
+        s: contents,
+        fences: FENCES.find_iter(contents).peekable(),
+        unterminated: vec![],
+    }
+}
+
+/// The ranges of the markdown that are code, and so not markdown at all: fenced
+/// blocks of any language, and inline code spans. An unclosed block runs to the end.
+pub(crate) fn find_code(contents: &str) -> Vec<Range<usize>> {
+    let mut code = vec![];
+    // where the text before the next block starts
+    let mut text = 0;
+    let mut fences = FENCES.find_iter(contents);
+    while let Some(open) = fences.next() {
+        if open.start() < text {
+            continue;
+        }
+        let info = contents[open.end()..].split('\n').next().unwrap_or("");
+        let line_end = open.end() + info.len();
+        // a block opens on its own line, and info strings can't contain backticks
+        let line_start = contents[..open.start()].rfind('\n').map_or(0, |i| i + 1);
+        if !contents[line_start..open.start()].trim().is_empty() || info.contains('`') {
+            // so the fence is inline code, which may hold other fences
+            text = text.max(line_end);
+            continue;
+        }
+
+        let end = fences
+            .by_ref()
+            .find(|m| {
+                m.start() > line_end && m.pattern() != 0 && closes_fence(&contents[m.end()..])
+            })
+            .map_or(contents.len(), |m| m.end());
+        code.extend(code_spans(contents, text..line_start));
+        code.push(open.start()..end);
+        text = end;
+    }
+    code.extend(code_spans(contents, text..contents.len()));
+    code
+}
+
+/// The inline code spans within `range` of the markdown. A span can't cross a blank line.
+fn code_spans(contents: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let bytes = &contents.as_bytes()[..range.end];
+    let backticks = |i: usize| bytes[i..].iter().take_while(|&&b| b == b'`').count();
+    let mut spans = vec![];
+    let mut i = range.start;
+    while i < bytes.len() {
+        if bytes[i] != b'`' {
+            i += 1;
+            continue;
+        }
+        // a span is closed by the next run of as many backticks
+        let run = backticks(i);
+        let mut j = i + run;
+        let close = loop {
+            match bytes[j..].iter().position(|&b| b == b'`' || b == b'\n') {
+                None => break None,
+                Some(k) if bytes[j + k] == b'\n' => {
+                    j += k + 1;
+                    let blank = bytes[j..].iter().take_while(|&&b| b != b'\n');
+                    if blank.clone().all(u8::is_ascii_whitespace) && j + blank.count() < bytes.len()
+                    {
+                        break None;
+                    }
+                }
+                Some(k) if backticks(j + k) == run => break Some(j + k),
+                Some(k) => j += k + backticks(j + k),
+            }
+        };
+        match close {
+            Some(close) => {
+                spans.push(i..close + run);
+                i = close + run;
+            }
+            None => i += run,
+        }
+    }
+    spans
+}
+
+/// The offsets of the `plantuml` fences in the markdown that are never closed.
+/// Their blocks are left as they are.
+pub(crate) fn find_unterminated(contents: &str) -> Vec<usize> {
+    let mut pumls = find_pumls(contents);
+    pumls.by_ref().for_each(drop);
+    pumls.unterminated
+}
+
+/// Replaces each diagram in the markdown with what `f` returns for it
+pub(crate) fn replace_pumls<'a>(s: &'a str, mut f: impl FnMut(&Puml<'a>) -> String) -> String {
+    // When replacing one thing in a string by something with a different length,
+    // the indices after that will not correspond,
+    // we therefore have to store the difference to correct this
+    let mut previous_end_index = 0;
+    let mut replaced = String::new();
+
+    for link in find_pumls(s) {
+        replaced.push_str(&s[previous_end_index..link.start]);
+        replaced.push_str(&f(&link));
+        previous_end_index = link.end;
+    }
+
+    replaced.push_str(&s[previous_end_index..]);
+    replaced
+}
+
+/// The attributes given in the info string of a fenced block,
+/// eg `plantuml,ignore` or `plantuml tags="auth login"`.
+///
+/// Attributes are separated by commas or whitespace, and are either flags or
+/// `key=value` pairs. Values can be quoted to include separators.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub(crate) struct Attributes<'a>(Vec<(&'a str, Option<&'a str>)>);
+
+impl<'a> Attributes<'a> {
+    pub fn parse(info: &'a str) -> Self {
+        let mut attributes = vec![];
+        let mut rest = info;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if rest.is_empty() {
+                break;
+            }
+
+            let key_end = rest
+                .find(|c: char| c == ',' || c == '=' || c.is_whitespace())
+                .unwrap_or(rest.len());
+            let key = &rest[..key_end];
+            rest = &rest[key_end..];
+
+            let value = match rest.strip_prefix('=') {
+                Some(value) => {
+                    let (value, remaining) = match value.strip_prefix('"') {
+                        Some(quoted) => match quoted.find('"') {
+                            Some(i) => (&quoted[..i], &quoted[i + 1..]),
+                            None => (quoted, ""),
+                        },
+                        None => {
+                            let end = value
+                                .find(|c: char| c == ',' || c.is_whitespace())
+                                .unwrap_or(value.len());
+                            value.split_at(end)
+                        }
+                    };
+                    rest = remaining;
+                    Some(value)
+                }
+                None => None,
+            };
+
+            attributes.push((key, value));
+        }
+        Attributes(attributes)
+    }
+
+    /// Whether a flag is set, either on its own (`ignore`) or as `ignore=true`
+    pub fn flag(&self, name: &str) -> bool {
+        self.0
+            .iter()
+            .any(|(key, value)| *key == name && matches!(value, None | Some("true")))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.0
+            .iter()
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| *value)
+    }
+}
+
+/// The source to give plantuml. Like GitLab, diagrams without an `@start` line
+/// are taken to be uml, and wrapped in `@startuml`/`@enduml`.
+pub(crate) fn wrap_diagram(contents: &str) -> Cow<'_, str> {
+    if contents
+        .lines()
+        .any(|line| line.trim_start().starts_with("@start"))
+    {
+        return Cow::Borrowed(contents);
+    }
+    let newline = if contents.ends_with('\n') { "" } else { "\n" };
+    Cow::Owned(format!("@startuml\n{}{}@enduml\n", contents, newline))
+}
+
+/// Adds a line to a diagram, after its `@start` line
+pub(crate) fn add_directive(source: &str, directive: &str) -> String {
+    let mut added = String::with_capacity(source.len() + directive.len() + 1);
+    let mut pending = true;
+    for line in source.split_inclusive('\n') {
+        added.push_str(line);
+        if pending && line.trim_start().starts_with("@start") {
+            if !line.ends_with('\n') {
+                added.push('\n');
+            }
+            added.push_str(directive);
+            added.push('\n');
+            pending = false;
+        }
+    }
+    if pending {
+        // not wrapped yet, so the directive goes first
+        return format!("{}\n{}", directive, source);
+    }
+    added
+}
+
+/// The `scale` directive limiting a diagram to the given size in pixels
+pub(crate) fn scale_directive(max_width: Option<u32>, max_height: Option<u32>) -> Option<String> {
+    match (max_width, max_height) {
+        (Some(width), Some(height)) => Some(format!("scale max {}x{}", width, height)),
+        (Some(width), None) => Some(format!("scale max {} width", width)),
+        (None, Some(height)) => Some(format!("scale max {} height", height)),
+        (None, None) => None,
+    }
+}
+
+/// A `scale` directive rendering the image at exactly the size
+pub(crate) fn size_directive(width: Option<u32>, height: Option<u32>) -> Option<String> {
+    match (width, height) {
+        (Some(width), Some(height)) => Some(format!("scale {}*{}", width, height)),
+        (Some(width), None) => Some(format!("scale {} width", width)),
+        (None, Some(height)) => Some(format!("scale {} height", height)),
+        (None, None) => None,
+    }
+}
+
+/// The name given by `@start<kind> <name>`, which plantuml names the image after
+pub(crate) fn find_name(contents: &str) -> Option<&str> {
+    let line = contents.lines().next()?;
+    let (start, name) = line.split_once(' ')?;
+    start.starts_with("@start").then_some(name)
+}
+
+/// The kind of diagram, from the `@start<kind>` line.
+///
+/// `@startuml` covers many kinds of diagram, so they are told apart by their
+/// keywords the same way plantuml does, falling back to `uml`.
+pub(crate) fn find_kind(contents: &str) -> String {
+    let first = contents.lines().map(str::trim).find(|l| !l.is_empty());
+    let kind = first
+        .and_then(|line| line.strip_prefix("@start"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .unwrap_or("uml");
+
+    if kind != "uml" {
+        return kind.to_owned();
+    }
+    uml_kind(contents).to_owned()
+}
+
+fn uml_kind(contents: &str) -> &'static str {
+    let mut arrows = false;
+    for line in contents.lines().map(str::trim) {
+        let keyword = line.split_whitespace().next().unwrap_or("");
+        let kind = match keyword {
+            "participant" | "boundary" | "control" | "entity" | "collections" | "queue"
+            | "activate" | "deactivate" | "autonumber" => "sequence",
+            "class" | "interface" | "abstract" | "enum" | "annotation" => "class",
+            "node" | "artifact" | "cloud" | "storage" | "frame" => "deployment",
+            "usecase" => "usecase",
+            "state" => "state",
+            "component" => "component",
+            "object" | "map" => "object",
+            "start" | "stop" => "activity",
+            _ if line.starts_with(':') && line.ends_with(';') => "activity",
+            _ if line.starts_with("[*]") => "state",
+            _ => {
+                arrows |= line.contains("->") || line.contains("<-");
+                continue;
+            }
+        };
+        return kind;
+    }
+
+    // plain messages between participants default to a sequence diagram
+    if arrows {
+        "sequence"
+    } else {
+        "uml"
+    }
+}
+
+/// Turns a diagram name into an anchor, eg `Login Sequence` into `login-sequence`
+pub(crate) fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_find_plantuml() {
+        let s = r#"Some random text with
+```plantuml
+@startuml Document Name
+
+UML <-> Document
+
+@enduml
+```
+
+and
+
+```rust
+let foo = "bar";
+```
+
+```plantuml
+@startuml Another Doc
+Foo
+@enduml
+```
+
+```plantuml,ignore
+@startuml
+Foo <-> Bar
+@enduml
+```
+"#;
+
+        let res = find_pumls(s).collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![
+                Puml {
+                    start: 22,
+                    end: 88,
+                    info: "",
+                    contents: "@startuml Document Name\n\nUML <-> Document\n\n@enduml\n",
+                    ignore: false,
+                },
+                Puml {
+                    start: 125,
+                    end: 174,
+                    info: "",
+                    contents: "@startuml Another Doc\nFoo\n@enduml\n",
+                    ignore: false,
+                },
+                Puml {
+                    start: 176,
+                    end: 228,
+                    info: ",ignore",
+                    contents: "@startuml\nFoo <-> Bar\n@enduml\n",
+                    ignore: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps() {
+        assert_eq!(wrap_diagram("A -> B\n"), "@startuml\nA -> B\n@enduml\n");
+        assert_eq!(wrap_diagram("A -> B"), "@startuml\nA -> B\n@enduml\n");
+        let full = "@startmindmap\n* a\n@endmindmap\n";
+        assert!(matches!(wrap_diagram(full), Cow::Borrowed(s) if s == full));
+    }
+
+    #[test]
+    fn scales() {
+        let scale = scale_directive(Some(900), None).unwrap();
+        assert_eq!(scale, "scale max 900 width");
+        assert_eq!(
+            scale_directive(Some(900), Some(600)).as_deref(),
+            Some("scale max 900x600")
+        );
+        assert_eq!(scale_directive(None, None), None);
+
+        assert_eq!(
+            add_directive("@startuml Login\nA -> B\n@enduml\n", &scale),
+            "@startuml Login\nscale max 900 width\nA -> B\n@enduml\n"
+        );
+        assert_eq!(
+            add_directive("A -> B\n", &scale),
+            "scale max 900 width\nA -> B\n"
+        );
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slugify("Login Sequence"), "login-sequence");
+        assert_eq!(slugify("  API -> DB (v2)  "), "api-db-v2");
+        assert_eq!(slugify("Ärger_über"), "ärger-über");
+        assert_eq!(slugify("--"), "");
+    }
+
+    #[test]
+    fn attributes() {
+        let attributes = Attributes::parse(r#",ignore tags="auth login", caption=Hello"#);
+        assert!(attributes.flag("ignore"));
+        assert!(!attributes.flag("caption"));
+        assert_eq!(attributes.get("tags"), Some("auth login"));
+        assert_eq!(attributes.get("caption"), Some("Hello"));
+        assert_eq!(attributes.get("ignore"), None);
+
+        assert_eq!(Attributes::parse(""), Attributes::default());
+    }
+
+    #[test]
+    fn info_strings() {
+        let s = "```plantuml tags=auth\nA -> B\n```\n```plantumlx\nA\n```\n";
+        let res = find_pumls(s).collect::<Vec<_>>();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].info, " tags=auth");
+        assert_eq!(res[0].contents, "A -> B\n");
+    }
+
+    #[test]
+    fn adversarial_fences() {
+        // inline code that looks like a fence doesn't swallow the diagrams after it
+        let s = "é ```plantuml ```\n😀\n```plantuml\nA -> é\n```\n";
+        let res = find_pumls(s).collect::<Vec<_>>();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].contents, "A -> é\n");
+        assert_eq!(&s[res[0].start..res[0].end], "```plantuml\nA -> é\n```");
+
+        let replaced = replace_pumls(s, |_| "[diagram]".to_owned());
+        assert_eq!(replaced, "é ```plantuml ```\n😀\n[diagram]\n");
+    }
+
+    #[test]
+    fn unterminated() {
+        // closed by the end of the chapter
+        let s = "# A\n```plantuml\nA -> B\n";
+        assert_eq!(find_pumls(s).count(), 0);
+        assert_eq!(find_unterminated(s), vec![4]);
+        assert_eq!(replace_pumls(s, |_| "[diagram]".to_owned()), s);
+
+        // not paired with the opening fence of the next block
+        let s = "```plantuml\nA -> B\n\n```rust\nlet a = 1;\n```\n\n```plantuml\nC -> D\n```\n";
+        let res = find_pumls(s).collect::<Vec<_>>();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].contents, "C -> D\n");
+        assert_eq!(find_unterminated(s), vec![0]);
+
+        // a closing fence has a line to itself
+        let s = "```plantuml\nA -> B\n``` \n";
+        assert_eq!(find_pumls(s).count(), 1);
+        assert!(find_unterminated(s).is_empty());
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(find_kind("@startuml\nA -> B\n@enduml\n"), "sequence");
+        assert_eq!(
+            find_kind("@startuml\nclass Foo\nFoo --> Bar\n@enduml\n"),
+            "class"
+        );
+        assert_eq!(find_kind("@startuml\nnode web\n@enduml\n"), "deployment");
+        assert_eq!(
+            find_kind("@startuml\nstart\n:step;\nstop\n@enduml\n"),
+            "activity"
+        );
+        assert_eq!(
+            find_kind("@startmindmap Ideas\n* root\n@endmindmap\n"),
+            "mindmap"
+        );
+        assert_eq!(find_kind("@startuml\n@enduml\n"), "uml");
+        assert_eq!(find_kind("@startebnf\nrule = \"a\";\n@endebnf\n"), "ebnf");
+        assert_eq!(find_kind("@startregex\n[a-z]+\n@endregex\n"), "regex");
+        assert_eq!(
+            find_kind("@startchen Movies\nentity Movie {\n}\n@endchen\n"),
+            "chen"
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            find_name("@startuml Login Flow\nA -> B\n"),
+            Some("Login Flow")
+        );
+        assert_eq!(
+            find_name("@startebnf Grammar\r\nrule = \"a\";\n"),
+            Some("Grammar")
+        );
+        assert_eq!(find_name("@startchen Movies"), Some("Movies"));
+        assert_eq!(find_name("@startregex\n[a-z]+\n"), None);
+        assert_eq!(find_name("A -> B: @startuml x\n"), None);
+    }
+
+    /// A generated chapter, of text with a plantuml block between each piece
+    #[derive(Debug, Clone)]
+    pub(crate) struct Generated {
+        pub text: Vec<String>,
+        /// the info string and contents of each block
+        pub blocks: Vec<(String, String)>,
+    }
+
+    impl Generated {
+        pub fn block(&self, i: usize) -> String {
+            let (info, contents) = &self.blocks[i];
+            format!("```plantuml{}\n{}```", info, contents)
+        }
+
+        pub fn ignored(&self, i: usize) -> bool {
+            Attributes::parse(&self.blocks[i].0).flag("ignore")
+        }
+
+        /// The chapter, with each block written as `block` gives it
+        pub fn markdown(&self, mut block: impl FnMut(usize) -> String) -> String {
+            let mut markdown = self.text[0].clone();
+            for (i, text) in self.text[1..].iter().enumerate() {
+                markdown.push('\n');
+                markdown.push_str(&block(i));
+                markdown.push('\n');
+                markdown.push_str(text);
+            }
+            markdown
+        }
+    }
+
+    pub(crate) fn chapter() -> impl Strategy<Value = Generated> {
+        let text = "[a-zé #*`\n]{0,30}".prop_filter("no fences", |s| !s.contains("```"));
+        let info = prop_oneof![
+            Just(""),
+            Just(",ignore"),
+            Just(" ignore"),
+            Just(" alt=\"a, b\""),
+            Just(",id=a")
+        ];
+        let contents = "([A-Za-zé😀<>@ -]{0,12}\n){0,4}";
+        (
+            text.clone(),
+            prop::collection::vec((info, contents, text), 0..4),
+        )
+            .prop_map(|(first, rest)| {
+                let mut chapter = Generated {
+                    text: vec![first],
+                    blocks: vec![],
+                };
+                for (info, contents, text) in rest {
+                    chapter.blocks.push((info.to_owned(), contents));
+                    chapter.text.push(text);
+                }
+                chapter
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(chapter in chapter()) {
+            let markdown = chapter.markdown(|i| chapter.block(i));
+
+            let found: Vec<_> = find_pumls(&markdown)
+                .map(|puml| (puml.info.to_owned(), puml.contents.to_owned()))
+                .collect();
+            prop_assert_eq!(&found, &chapter.blocks);
+
+            // replacing the blocks with themselves changes nothing
+            let same = replace_pumls(&markdown, |puml| markdown[puml.start..puml.end].to_owned());
+            prop_assert_eq!(&same, &markdown);
+
+            // and the text around the blocks is kept byte for byte
+            let marked = replace_pumls(&markdown, |_| "\0".to_owned());
+            prop_assert_eq!(marked, chapter.markdown(|_| "\0".to_owned()));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! The warnings and errors shown to authors, in the language of their book.
+//!
+//! Only English is built in. Other languages are added with catalogs in the config,
+//! mapping the keys of [`EN`] to templates, and any message a catalog leaves out
+//! is shown in English:
+//!
+//! ```toml
+//! [preprocessor.puml.messages.de]
+//! unterminated = "PlantUML-Block wird nie geschlossen und bleibt unverändert"
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// The built in messages, by their key. `{name}` is replaced by the argument of that name.
+const EN: &[(&str, &str)] = &[
+    (
+        "unterminated",
+        "plantuml block is never closed, so it is left as it is",
+    ),
+    (
+        "unknown-layout-engine",
+        "unknown layout engine {name:?}, expected graphviz, smetana or elk",
+    ),
+    (
+        "invalid-size",
+        "{key}={value:?} is not a positive number of pixels, ignoring it",
+    ),
+    (
+        "no-text-rendering",
+        "{kind} diagrams have no text rendering, so the source is shown",
+    ),
+    (
+        "too-wide",
+        "diagram is {width}px wide, more than the readable {max}px. \
+        Consider splitting it with `newpage`, or limiting it with `max-width-px`",
+    ),
+    (
+        "unknown-kind",
+        "@start{kind} isn't a kind of diagram mdbook-puml knows, so it is left to plantuml",
+    ),
+    (
+        "unsupported-format",
+        "plantuml cannot render {kind} diagrams to {format}",
+    ),
+];
+
+/// The messages in the chosen language
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Messages {
+    /// templates replacing the English ones
+    templates: HashMap<String, String>,
+}
+
+impl Messages {
+    /// The messages for a language such as `de` or `pt-BR`, from the catalog of the
+    /// language or else the catalog of its primary language
+    pub fn new(
+        language: Option<&str>,
+        catalogs: &HashMap<String, HashMap<String, String>>,
+    ) -> Self {
+        for (language, catalog) in catalogs {
+            for key in catalog.keys() {
+                if !EN.iter().any(|(k, _)| k == key) {
+                    warn!("unknown message {:?} in the {} catalog", key, language);
+                }
+            }
+        }
+
+        let language = match language {
+            Some(language) => language,
+            None => return Self::default(),
+        };
+        let primary = language.split(['-', '_']).next().unwrap_or(language);
+        let templates = catalogs
+            .get(language)
+            .or_else(|| catalogs.get(primary))
+            .cloned()
+            .unwrap_or_default();
+        Messages { templates }
+    }
+
+    /// The message with the key, with each `{name}` or `{name:?}` replaced by its argument
+    pub fn get(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let template = match self.templates.get(key) {
+            Some(template) => template.as_str(),
+            None => {
+                EN.iter()
+                    .find(|(k, _)| *k == key)
+                    .expect("message is in the catalog")
+                    .1
+            }
+        };
+
+        let mut message = template.to_owned();
+        for (name, value) in args {
+            message = message
+                .replace(&format!("{{{}}}", name), &value.to_string())
+                .replace(
+                    &format!("{{{}:?}}", name),
+                    &format!("{:?}", value.to_string()),
+                );
+        }
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs() {
+        let catalogs = HashMap::from([(
+            "de".to_owned(),
+            HashMap::from([(
+                "no-text-rendering".to_owned(),
+                "{kind}-Diagramme haben keine Textdarstellung".to_owned(),
+            )]),
+        )]);
+
+        let en = Messages::new(Some("en"), &catalogs);
+        assert_eq!(
+            en.get("no-text-rendering", &[("kind", &"class")]),
+            "class diagrams have no text rendering, so the source is shown"
+        );
+        assert_eq!(
+            en.get("unknown-layout-engine", &[("name", &"dott")]),
+            "unknown layout engine \"dott\", expected graphviz, smetana or elk"
+        );
+
+        let de = Messages::new(Some("de-AT"), &catalogs);
+        assert_eq!(
+            de.get("no-text-rendering", &[("kind", &"class")]),
+            "class-Diagramme haben keine Textdarstellung"
+        );
+        // falling back to English
+        assert_eq!(
+            de.get("unknown-kind", &[("kind", &"gnatt")]),
+            "@startgnatt isn't a kind of diagram mdbook-puml knows, so it is left to plantuml"
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Licensing and authorship metadata embedded in every rendered SVG, from the
+//! `svg-metadata` config, for organisations that require it inside distributed assets.
+//!
+//! Each key is written as a Dublin Core term, eg `license` or `rightsHolder`,
+//! in an RDF `<metadata>` element at the start of the SVG.
+
+use crate::compiler::escape_html;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+/// Keys that people reach for which aren't Dublin Core terms, and the terms they mean
+const ALIASES: &[(&str, &str)] = &[("author", "creator"), ("copyright", "rights")];
+
+/// The `<metadata>` element describing the image with the terms
+pub(crate) fn element(terms: &BTreeMap<String, String>) -> Result<String> {
+    let mut element = String::from(
+        r#"<metadata><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#" xmlns:dcterms="http://purl.org/dc/terms/"><rdf:Description rdf:about="">"#,
+    );
+    for (key, value) in terms {
+        let valid = key.starts_with(|c: char| c.is_ascii_alphabetic())
+            && key.chars().all(|c| c.is_ascii_alphanumeric());
+        if !valid {
+            bail!(
+                "invalid svg-metadata key {:?}, it must be a Dublin Core term such as `license`",
+                key
+            );
+        }
+        let term = ALIASES
+            .iter()
+            .find(|(alias, _)| alias == key)
+            .map_or(key.as_str(), |(_, term)| term);
+        element.push_str(&format!(
+            "<dcterms:{term}>{}</dcterms:{term}>",
+            escape_html(value),
+            term = term
+        ));
+    }
+    element.push_str("</rdf:Description></rdf:RDF></metadata>");
+    Ok(element)
+}
+
+/// Adds the element as the first child of the svg's root, if it has one
+pub(crate) fn embed(svg: &str, element: &str) -> Option<String> {
+    let start = svg.find("<svg")?;
+    let end = start + svg[start..].find('>')?;
+    let mut embedded = String::with_capacity(svg.len() + element.len() + 6);
+    if svg[..end].ends_with('/') {
+        // an empty `<svg/>` needs opening up
+        embedded.push_str(&svg[..end - 1]);
+        embedded.push('>');
+        embedded.push_str(element);
+        embedded.push_str("</svg>");
+    } else {
+        embedded.push_str(&svg[..=end]);
+        embedded.push_str(element);
+    }
+    embedded.push_str(&svg[end + 1..]);
+    Some(embedded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded() {
+        let terms = BTreeMap::from([
+            ("license".to_owned(), "CC-BY-4.0".to_owned()),
+            ("author".to_owned(), "Docs & Design".to_owned()),
+        ]);
+        let metadata = element(&terms).unwrap();
+        assert!(metadata.contains(
+            "<dcterms:creator>Docs &amp; Design</dcterms:creator><dcterms:license>CC-BY-4.0</dcterms:license>"
+        ));
+
+        assert_eq!(
+            embed(
+                r#"<?xml version="1.0"?><svg width="1"><g/></svg>"#,
+                "<metadata/>"
+            )
+            .unwrap(),
+            r#"<?xml version="1.0"?><svg width="1"><metadata/><g/></svg>"#
+        );
+        assert_eq!(
+            embed("<svg/>\n", "<metadata/>").unwrap(),
+            "<svg><metadata/></svg>\n"
+        );
+        assert_eq!(embed("not an svg", "<metadata/>"), None);
+
+        let invalid = BTreeMap::from([("dc:license".to_owned(), "MIT".to_owned())]);
+        assert!(element(&invalid).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Rewriting the `{{#plantuml file.puml}}` directives used by other plantuml
+//! preprocessors into fenced blocks, for `mdbook-puml migrate`
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+const DIRECTIVE: &str = "{{#plantuml";
+
+/// Replaces each directive with a fenced block of the file it includes.
+/// Paths are relative to `dir`, the directory of the chapter.
+/// Escaped directives (`\{{#plantuml ...}}`) are left as they are.
+pub fn migrate_markdown(markdown: &str, dir: &Path) -> Result<String> {
+    let mut migrated = String::with_capacity(markdown.len());
+    let mut rest = markdown;
+
+    while let Some(start) = rest.find(DIRECTIVE) {
+        let args_start = start + DIRECTIVE.len();
+        let end = match rest[args_start..].find("}}") {
+            Some(end) => args_start + end,
+            None => break,
+        };
+        let escaped = rest[..start].ends_with('\\');
+        let path = rest[args_start..end].trim();
+        if escaped || path.is_empty() {
+            migrated.push_str(&rest[..end + 2]);
+            rest = &rest[end + 2..];
+            continue;
+        }
+
+        let file = dir.join(path);
+        let mut source = std::fs::read_to_string(&file)
+            .with_context(|| format!("could not read {}", file.display()))?;
+        if !source.ends_with('\n') {
+            source.push('\n');
+        }
+
+        migrated.push_str(&rest[..start]);
+        migrated.push_str("```plantuml\n");
+        migrated.push_str(&source);
+        migrated.push_str("```");
+        rest = &rest[end + 2..];
+    }
+
+    migrated.push_str(rest);
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directives() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("diagrams")).unwrap();
+        std::fs::write(
+            dir.path().join("diagrams/login.puml"),
+            "@startuml\nA -> B\n@enduml",
+        )
+        .unwrap();
+
+        let s = "# A\n\n{{#plantuml diagrams/login.puml}}\n\nescaped \\{{#plantuml x.puml}}\n";
+        assert_eq!(
+            migrate_markdown(s, dir.path()).unwrap(),
+            "# A\n\n```plantuml\n@startuml\nA -> B\n@enduml\n```\n\nescaped \\{{#plantuml x.puml}}\n"
+        );
+
+        assert!(migrate_markdown("{{#plantuml missing.puml}}", dir.path()).is_err());
+    }
+}
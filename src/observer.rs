@@ -0,0 +1,29 @@
+//! Hooks into the render lifecycle, so embedders can record metrics, upload images
+//! or send notifications without reimplementing the phases of a [`Compiler`].
+
+#[cfg(doc)]
+use crate::Compiler;
+use crate::{RenderError, Target};
+use std::time::Duration;
+
+/// Notified as a [`Compiler`] works through a book, once registered with
+/// [`Compiler::add_observer`].
+///
+/// Every method does nothing by default. With `jobs`, diagrams render on several
+/// threads at once, so observers are shared between them and should return quickly.
+pub trait Observer: Send + Sync {
+    /// The diagrams found in the book, before any are rendered
+    fn on_scan(&self, _targets: &[Target]) {}
+
+    /// A diagram whose image is reused from the outdir or the diff base
+    fn on_cache_hit(&self, _target: &Target) {}
+
+    /// A diagram is about to be rendered
+    fn on_render_start(&self, _target: &Target) {}
+
+    /// A diagram rendered. Diagrams rendered by one plantuml invocation share its time evenly.
+    fn on_render_finish(&self, _target: &Target, _elapsed: Duration) {}
+
+    /// A diagram could not be rendered
+    fn on_error(&self, _error: &RenderError) {}
+}
@@ -0,0 +1,55 @@
+//! Pages of their own for diagrams too large to load with their chapter.
+//!
+//! The chapter shows a thumbnail linking to the page, which embeds the SVG in an
+//! `<object>`, so it stays interactive and is only loaded by those who open it.
+
+use crate::compiler::{escape_html, url_path};
+use crate::Target;
+use mdbook::book::Chapter;
+use std::path::{Path, PathBuf};
+
+/// Directory of the pages, relative to the book src
+pub(crate) const PAGES_DIR: &str = "plantuml_pages";
+
+/// Path of the diagram's page, relative to the book src
+fn page_path(target: &Target) -> PathBuf {
+    Path::new(PAGES_DIR).join(format!("{}.md", target.output))
+}
+
+/// The thumbnail linking to the diagram's page, from a chapter `depth` folders deep
+pub(crate) fn thumbnail(target: &Target, depth: usize) -> String {
+    let up = "../".repeat(depth);
+    format!(
+        r#"<a href="{up}{page}"><img src="{up}{image}" alt="{alt}" loading="lazy" style="max-width: 16em"></a>"#,
+        up = up,
+        page = escape_html(&url_path(&page_path(target).with_extension("html"))),
+        image = escape_html(&url_path(&target.image())),
+        alt = escape_html(target.alt_text().unwrap_or("")),
+    )
+}
+
+/// The page of a diagram in the chapter, to be one of its sub chapters
+pub(crate) fn page(target: &Target, chapter: &Chapter) -> Chapter {
+    let name = target.name.as_deref().unwrap_or("Untitled");
+    let mut back = url_path(&target.chapter.with_extension("html"));
+    if let Some(id) = &target.id {
+        back = format!("{}#{}", back, id);
+    }
+    let content = format!(
+        r#"# {name}
+
+<object data="../{image}" type="image/svg+xml" aria-label="{alt}" style="max-width: none"></object>
+
+[Back to {chapter}](../{back})
+"#,
+        name = name,
+        image = escape_html(&url_path(&target.image())),
+        alt = escape_html(target.alt_text().unwrap_or("")),
+        chapter = chapter.name,
+        back = back,
+    );
+
+    let mut parents = chapter.parent_names.clone();
+    parents.push(chapter.name.clone());
+    Chapter::new(name, content, page_path(target), parents)
+}
@@ -0,0 +1,142 @@
+//! Finding and replacing diagrams in markdown, without rendering them.
+//!
+//! None of this needs plantuml, a filesystem or mdbook, so it is all that is
+//! built with `default-features = false`, which compiles to wasm32. In-browser
+//! previewers can then find diagrams exactly the way the preprocessor does,
+//! and show them from a plantuml server.
+
+use crate::encoding::encode_plantuml;
+use crate::markdown::{find_kind, find_name, find_pumls, replace_pumls, Puml};
+
+/// The public plantuml server
+pub const PLANTUML_SERVER: &str = "https://www.plantuml.com/plantuml";
+
+/// An image format plantuml can render to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Svg,
+    Png,
+    /// Ascii art, only supported by sequence diagrams
+    Txt,
+}
+
+impl Format {
+    /// The name plantuml uses for the format, in its `-t` flag and server urls
+    pub(crate) fn output_type(self) -> &'static str {
+        match self {
+            Format::Svg => "svg",
+            Format::Png => "png",
+            Format::Txt => "txt",
+        }
+    }
+}
+
+/// A plantuml block found in markdown
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagram<'a> {
+    /// Byte range of the whole fenced block
+    pub start: usize,
+    pub end: usize,
+    /// Line the block starts on
+    pub line: usize,
+    /// The diagram source, between the fences
+    pub contents: &'a str,
+    /// The rest of the opening fence line, eg `,ignore`
+    pub info: &'a str,
+    pub name: Option<&'a str>,
+    pub kind: String,
+    /// Whether the block is marked to be left as it is
+    pub ignore: bool,
+}
+
+impl<'a> Diagram<'a> {
+    fn new(markdown: &str, puml: &Puml<'a>) -> Self {
+        Diagram {
+            start: puml.start,
+            end: puml.end,
+            line: markdown[..puml.start].matches('\n').count() + 1,
+            contents: puml.contents,
+            info: puml.info,
+            name: find_name(puml.contents),
+            kind: find_kind(puml.contents),
+            ignore: puml.ignore,
+        }
+    }
+}
+
+/// Every plantuml block in the markdown, in order
+pub fn diagrams(markdown: &str) -> Vec<Diagram<'_>> {
+    find_pumls(markdown)
+        .map(|puml| Diagram::new(markdown, &puml))
+        .collect()
+}
+
+/// Replaces each plantuml block with what `f` returns for it.
+/// Blocks that `f` returns `None` for are left as they are.
+pub fn replace_diagrams<'a>(
+    markdown: &'a str,
+    mut f: impl FnMut(&Diagram<'a>) -> Option<String>,
+) -> String {
+    replace_pumls(markdown, |puml| {
+        f(&Diagram::new(markdown, puml))
+            .unwrap_or_else(|| markdown[puml.start..puml.end].to_owned())
+    })
+}
+
+/// The url of the diagram rendered by a plantuml server, eg [`PLANTUML_SERVER`]
+pub fn server_url(server: &str, format: Format, source: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        server.trim_end_matches('/'),
+        format.output_type(),
+        encode_plantuml(source)
+    )
+}
+
+/// The url opening the diagram in a plantuml server's online editor
+pub fn editor_url(server: &str, source: &str) -> String {
+    format!(
+        "{}/uml/{}",
+        server.trim_end_matches('/'),
+        encode_plantuml(source)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews() {
+        let s = "# Title\n\n```plantuml\n@startuml Hello\nA -> B\n@enduml\n```\n\n```plantuml,ignore\nA\n```\n";
+        let found = diagrams(s);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].line, 3);
+        assert_eq!(found[0].name, Some("Hello"));
+        assert_eq!(found[0].kind, "sequence");
+        assert!(found[1].ignore);
+
+        let replaced = replace_diagrams(s, |diagram| {
+            (!diagram.ignore).then(|| {
+                format!(
+                    "![{}]({})",
+                    diagram.name.unwrap_or_default(),
+                    server_url(PLANTUML_SERVER, Format::Svg, diagram.contents)
+                )
+            })
+        });
+        let url = server_url(
+            "https://www.plantuml.com/plantuml/",
+            Format::Svg,
+            found[0].contents,
+        );
+        assert!(url.starts_with("https://www.plantuml.com/plantuml/svg/"));
+        assert_eq!(
+            replaced,
+            format!(
+                "# Title\n\n![Hello]({})\n\n```plantuml,ignore\nA\n```\n",
+                url
+            )
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Probing the installed plantuml, with the results cached between runs.
+//!
+//! `mdbook serve` restarts the preprocessor on every rebuild, and starting the
+//! JVM just to ask for a version would dominate the rebuild time. Results are
+//! kept in the book's `cache-dir` for `probe-ttl` seconds, or until plantuml changes.
+
+use crate::resolve;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default time to trust a cached probe for
+pub(crate) const DEFAULT_TTL: u64 = 60 * 60;
+
+/// Default directory to cache probes in, relative to the book root
+pub(crate) const DEFAULT_CACHE_DIR: &str = ".mdbook-puml";
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct Probe {
+    command: String,
+    /// when the file the command runs was last modified, so upgrades are noticed
+    modified: Option<SystemTime>,
+    version: String,
+    /// unix timestamp of when the probe ran
+    checked: u64,
+}
+
+/// The version reported by `plantuml -version`, from the cache in `cache_dir`
+/// if it's fresh enough and plantuml hasn't changed since
+pub(crate) fn plantuml_version(command: &str, cache_dir: &Path, ttl: Duration) -> Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let modified = resolve::program(command)
+        .and_then(|program| std::fs::metadata(program).ok())
+        .and_then(|metadata| metadata.modified().ok());
+    let cache = cache_path(cache_dir, command, modified);
+
+    if let Some(probe) = std::fs::read(&cache)
+        .ok()
+        .and_then(|file| serde_json::from_slice::<Probe>(&file).ok())
+    {
+        let unchanged = probe.command == command && probe.modified == modified;
+        if unchanged && now.saturating_sub(probe.checked) < ttl.as_secs() {
+            debug!("using cached probe of {}", command);
+            return Ok(probe.version);
+        }
+    }
+
+    let version = probe_version(command)?;
+    if !ttl.is_zero() {
+        let probe = Probe {
+            command: command.to_owned(),
+            modified,
+            version: version.clone(),
+            checked: now,
+        };
+        // caching is only an optimisation, so failing to write is fine
+        let written = std::fs::create_dir_all(cache.parent().unwrap())
+            .and_then(|_| std::fs::write(&cache, serde_json::to_vec(&probe)?));
+        if let Err(err) = written {
+            debug!("could not cache probe: {}", err);
+        }
+    }
+    Ok(version)
+}
+
+/// The version reported by `plantuml -version`, without caching it
+pub(crate) fn probe_version(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} -version", command))
+        .output()
+        .with_context(|| "could not invoke plantuml")?;
+    if !output.status.success() {
+        bail!("`{} -version` failed", command);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().map(str::trim).find(|l| !l.is_empty()) {
+        Some(version) => Ok(version.to_owned()),
+        None => bail!("`{} -version` printed no version", command),
+    }
+}
+
+fn cache_path(cache_dir: &Path, command: &str, modified: Option<SystemTime>) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    command.hash(&mut hasher);
+    modified.hash(&mut hasher);
+    cache_dir.join(format!("probe-{:016x}.json", hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn cached() {
+        // a command which reports a different version each time
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = dir.path().join("cache");
+        let counter = dir.path().join("count");
+        let command = format!("echo x >> {0}; wc -l < {0}; true", counter.display());
+        let ttl = Duration::from_secs(60);
+
+        let first = plantuml_version(&command, &cache, ttl).unwrap();
+        let second = plantuml_version(&command, &cache, ttl).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+
+        let uncached = plantuml_version(&command, &cache, Duration::ZERO).unwrap();
+        assert_ne!(first, uncached);
+
+        // changing the program invalidates the cache
+        let program = dir.path().join("plantuml");
+        std::fs::write(&program, "#!/bin/sh\necho PlantUML version 1\n").unwrap();
+        std::fs::set_permissions(&program, PermissionsExt::from_mode(0o755)).unwrap();
+        let command = program.display().to_string();
+        assert_eq!(
+            plantuml_version(&command, &cache, ttl).unwrap(),
+            "PlantUML version 1"
+        );
+        std::fs::write(&program, "#!/bin/sh\necho PlantUML version 2\n").unwrap();
+        let file = std::fs::File::options().write(true).open(&program).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        drop(file);
+        assert_eq!(
+            plantuml_version(&command, &cache, ttl).unwrap(),
+            "PlantUML version 2"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Checks for `pure = true` mode, where renders must be fully reproducible
+//! and may only read the files declared in the config.
+
+use anyhow::{bail, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// The directives that include other files
+pub(crate) const INCLUDE_DIRECTIVES: &[&str] = &[
+    "!include",
+    "!include_many",
+    "!include_once",
+    "!includesub",
+    "!import",
+];
+
+/// Builtin functions that read the wall-clock
+const CLOCK_FUNCTIONS: &[&str] = &["%date", "%now"];
+
+/// Validates that a diagram only depends on its own source and the declared includes.
+///
+/// Includes are resolved relative to the book src directory, which is where
+/// plantuml resolves them from too, as every render worker's directory is directly in it.
+pub(crate) fn check(source: &str, declared: &[PathBuf]) -> Result<()> {
+    for line in source.lines() {
+        let line = line.trim();
+
+        if let Some(function) = CLOCK_FUNCTIONS.iter().find(|f| line.contains(*f)) {
+            bail!(
+                "{} reads the clock, which is not allowed in pure mode",
+                function
+            );
+        }
+
+        let (directive, arg) = match line.split_once(char::is_whitespace) {
+            Some((directive, arg)) => (directive, arg.trim()),
+            None => continue,
+        };
+
+        if directive == "!includeurl" {
+            bail!("{} is not allowed in pure mode", directive);
+        }
+        if !INCLUDE_DIRECTIVES.contains(&directive) {
+            continue;
+        }
+
+        // `<...>` includes come from the stdlib, bundled into plantuml or pinned
+        if arg.starts_with('<') {
+            continue;
+        }
+
+        if arg.contains("://") {
+            bail!(
+                "{} {} accesses the network, which is not allowed in pure mode",
+                directive,
+                arg
+            );
+        }
+
+        // `!includesub file!BLOCK` and `!include file!2` select part of a file
+        let file = arg.split('!').next().unwrap_or(arg);
+        match resolve(file) {
+            Some(path) if declared.contains(&path) => {}
+            _ => bail!(
+                "{} {} is not declared in `includes`, which is required in pure mode",
+                directive,
+                file
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Validates that a diagram doesn't include from the stdlib bundled into plantuml,
+/// for `pinned` builds without a vendored one
+pub(crate) fn check_pinned(source: &str) -> Result<()> {
+    for line in source.lines() {
+        if let Some((directive, arg)) = line.trim().split_once(char::is_whitespace) {
+            let arg = arg.trim();
+            if INCLUDE_DIRECTIVES.contains(&directive) && arg.starts_with('<') {
+                bail!(
+                    "{} {} reads the stdlib bundled into plantuml, which changes between releases, \
+                     so it must be vendored with `stdlib` when pinned",
+                    directive,
+                    arg
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves an include path, as written relative to the temporary directory
+/// inside the book src, into a path relative to the book src
+fn resolve(include: &str) -> Option<PathBuf> {
+    let mut in_tmp = true;
+    let mut resolved = PathBuf::new();
+    for component in Path::new(include).components() {
+        match component {
+            Component::Normal(c) => resolved.push(c),
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    // escaping the book src is never allowed
+                    if !in_tmp {
+                        return None;
+                    }
+                    in_tmp = false;
+                }
+            }
+            Component::CurDir => {}
+            // absolute paths could be anywhere
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    // files within the temporary directory can never exist
+    (!in_tmp).then_some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes() {
+        let declared = vec![PathBuf::from("shared/style.puml")];
+
+        check(
+            "@startuml\n!include ../shared/style.puml\n@enduml\n",
+            &declared,
+        )
+        .unwrap();
+        check(
+            "@startuml\n!include <C4/C4_Container>\n@enduml\n",
+            &declared,
+        )
+        .unwrap();
+        check(
+            "@startuml\n!includesub ../shared/style.puml!BASIC\n@enduml\n",
+            &declared,
+        )
+        .unwrap();
+
+        check(
+            "@startuml\n!include ../shared/other.puml\n@enduml\n",
+            &declared,
+        )
+        .unwrap_err();
+        check("@startuml\n!include /etc/passwd\n@enduml\n", &declared).unwrap_err();
+        check(
+            "@startuml\n!include https://example.com/x.puml\n@enduml\n",
+            &declared,
+        )
+        .unwrap_err();
+        check(
+            "@startuml\n!includeurl https://example.com/x.puml\n@enduml\n",
+            &declared,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn pinned() {
+        check_pinned("@startuml\n!include ../shared/style.puml\n@enduml\n").unwrap();
+        check_pinned("@startuml\n!include <C4/C4_Container>\n@enduml\n").unwrap_err();
+    }
+
+    #[test]
+    fn clock() {
+        check("@startuml\nfooter %date(\"yyyy\")\n@enduml\n", &[]).unwrap_err();
+        check("@startuml\nFoo -> Bar\n@enduml\n", &[]).unwrap();
+    }
+}
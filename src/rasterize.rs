@@ -0,0 +1,60 @@
+//! Rasterising SVG images to PNG locally, so books with both formats only run
+//! plantuml once per diagram
+
+use anyhow::{Context, Result};
+use resvg::{tiny_skia, usvg};
+use std::sync::{Arc, OnceLock};
+
+/// The fonts installed on the system, loaded once as it is slow
+fn fonts() -> &'static Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS.get_or_init(|| {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    })
+}
+
+/// The resolution SVG sizes are given at
+pub(crate) const SVG_DPI: u32 = 96;
+
+/// Rasterises the SVG at a resolution, where [`SVG_DPI`] is the SVG's own size
+pub(crate) fn svg_to_png(svg: &[u8], dpi: u32) -> Result<Vec<u8>> {
+    let options = usvg::Options {
+        fontdb: fonts().clone(),
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_data(svg, &options).context("could not parse the svg")?;
+    let scale = dpi as f32 / SVG_DPI as f32;
+    let size = tree
+        .size()
+        .to_int_size()
+        .scale_by(scale)
+        .context("the svg has no area to rasterise")?;
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .context("the svg has no area to rasterise")?;
+    let transform = tiny_skia::Transform::from_scale(scale, scale);
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+    pixmap.encode_png().context("could not encode the png")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The width in a png's header
+    pub(crate) fn width(png: &[u8]) -> u32 {
+        u32::from_be_bytes(png[16..20].try_into().unwrap())
+    }
+
+    #[test]
+    fn rasterise() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2" fill="red"/></svg>"#;
+        let png = svg_to_png(svg, SVG_DPI).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+        assert_eq!(width(&png), 4);
+        assert_eq!(width(&svg_to_png(svg, 3 * SVG_DPI).unwrap()), 12);
+
+        assert!(svg_to_png(b"not an svg", SVG_DPI).is_err());
+    }
+}
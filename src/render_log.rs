@@ -0,0 +1,260 @@
+//! The `render-log`, an append-only record of every diagram rendered into the book,
+//! so maintainers can audit when and how a published image was last regenerated.
+
+use crate::{ErrorClass, Observer, RenderError, Target};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// The format of the render log, and so its file in `plantuml_images`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderLogFormat {
+    /// `render.log`, a line of space separated fields per render
+    Text,
+    /// `render.jsonl`, a JSON object per render
+    Jsonl,
+}
+
+impl RenderLogFormat {
+    pub(crate) fn file_name(self) -> &'static str {
+        match self {
+            Self::Text => "render.log",
+            Self::Jsonl => "render.jsonl",
+        }
+    }
+}
+
+/// One render of a diagram
+#[derive(Debug, PartialEq, Serialize)]
+struct Entry<'a> {
+    /// RFC 3339, in UTC
+    time: String,
+    hash: Uuid,
+    chapter: &'a Path,
+    line: usize,
+    backend: &'static str,
+    /// milliseconds
+    duration: u64,
+    /// `ok`, or the class of error that failed it
+    status: &'a str,
+}
+
+/// Appends an entry to the log as each diagram finishes rendering
+pub(crate) struct RenderLog {
+    path: PathBuf,
+    format: RenderLogFormat,
+    file: Mutex<Option<File>>,
+    /// the diagrams being rendered, by where they are, as errors only say that
+    started: Mutex<HashMap<(PathBuf, usize), (Uuid, Instant)>>,
+}
+
+impl RenderLog {
+    pub fn new(outdir: &Path, format: RenderLogFormat) -> Self {
+        RenderLog {
+            path: outdir.join(format.file_name()),
+            format,
+            file: Mutex::default(),
+            started: Mutex::default(),
+        }
+    }
+
+    fn append(&self, entry: &Entry) {
+        let line = match self.format {
+            RenderLogFormat::Text => format!(
+                "{} {} {}:{} {} {}ms {}\n",
+                entry.time,
+                entry.hash,
+                entry.chapter.display(),
+                entry.line,
+                entry.backend,
+                entry.duration,
+                entry.status
+            ),
+            RenderLogFormat::Jsonl => {
+                let mut line = serde_json::to_string(entry).expect("entries serialize");
+                line.push('\n');
+                line
+            }
+        };
+
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            let opened = self
+                .path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.path)
+                });
+            match opened {
+                Ok(opened) => *file = Some(opened),
+                Err(err) => {
+                    warn!("could not open {}: {}", self.path.display(), err);
+                    return;
+                }
+            }
+        }
+        let written = file.as_mut().map(|file| file.write_all(line.as_bytes()));
+        if let Some(Err(err)) = written {
+            warn!("could not write to {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+impl Observer for RenderLog {
+    fn on_render_start(&self, target: &Target) {
+        let key = (target.chapter.clone(), target.line);
+        let started = (target.output, Instant::now());
+        self.started.lock().unwrap().insert(key, started);
+    }
+
+    fn on_render_finish(&self, target: &Target, elapsed: Duration) {
+        let key = (target.chapter.clone(), target.line);
+        self.started.lock().unwrap().remove(&key);
+        self.append(&Entry {
+            time: timestamp(SystemTime::now()),
+            hash: target.output,
+            chapter: &target.chapter,
+            line: target.line,
+            backend: "plantuml",
+            duration: elapsed.as_millis() as u64,
+            status: "ok",
+        });
+    }
+
+    fn on_error(&self, error: &RenderError) {
+        let key = (error.chapter.clone(), error.line);
+        let (hash, started) = match self.started.lock().unwrap().remove(&key) {
+            Some(started) => started,
+            // it failed before rendering started, eg a capability check
+            None => return,
+        };
+        self.append(&Entry {
+            time: timestamp(SystemTime::now()),
+            hash,
+            chapter: &error.chapter,
+            line: error.line,
+            backend: "plantuml",
+            duration: started.elapsed().as_millis() as u64,
+            status: match ErrorClass::of(&error.error) {
+                ErrorClass::SyntaxError => "syntax-error",
+                ErrorClass::Unsupported => "unsupported",
+                ErrorClass::BackendMissing => "backend-missing",
+                ErrorClass::InvalidOutput => "invalid-output",
+                ErrorClass::IoError => "io-error",
+            },
+        });
+    }
+}
+
+/// The time as RFC 3339 in UTC, to the second
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // days since the epoch to the proleptic gregorian calendar, from Howard Hinnant's
+    // `civil_from_days`, with eras of 400 years starting on the 1st of March
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Compiler, Config};
+    use anyhow::anyhow;
+    use mdbook::book::{Book, Chapter};
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_827_696);
+        assert_eq!(timestamp(leap_day), "2000-02-29T12:34:56Z");
+        let new_year = UNIX_EPOCH + Duration::from_secs(1_767_225_599);
+        assert_eq!(timestamp(new_year), "2025-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn not_pure() {
+        let root = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            pure: true,
+            render_log: Some(RenderLogFormat::Jsonl),
+            ..Config::default()
+        };
+        let err = Compiler::new(root.path(), Path::new("src"), config)
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("render-log can't be used in pure mode"));
+    }
+
+    #[test]
+    fn appended() {
+        let root = tempfile::TempDir::new().unwrap();
+        let compiler = Compiler::new(root.path(), Path::new("src"), Config::default()).unwrap();
+        let s = "```plantuml\nA -> B\n```\n\n```plantuml\nA -> C\n```\n";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("A", s.to_owned(), "a.md", vec![]));
+        let targets = compiler.scan(&book);
+
+        for format in [RenderLogFormat::Text, RenderLogFormat::Jsonl] {
+            let log = RenderLog::new(compiler.outdir(), format);
+            log.on_render_start(&targets[0]);
+            log.on_render_start(&targets[1]);
+            log.on_render_finish(&targets[0], Duration::from_millis(1500));
+            log.on_error(&RenderError {
+                chapter: targets[1].chapter.clone(),
+                line: targets[1].line,
+                error: anyhow!("Syntax Error?"),
+            });
+        }
+
+        let text = std::fs::read_to_string(compiler.outdir().join("render.log")).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&format!(" {} a.md:1 plantuml 1500ms ok", targets[0].output)));
+        assert!(lines[1].ends_with(" syntax-error"));
+
+        let jsonl = std::fs::read_to_string(compiler.outdir().join("render.jsonl")).unwrap();
+        let entries: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries[0]["hash"], targets[0].output.to_string());
+        assert_eq!(entries[0]["duration"], 1500);
+        assert_eq!(entries[1]["line"], targets[1].line);
+        assert_eq!(entries[1]["status"], "syntax-error");
+    }
+}
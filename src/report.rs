@@ -0,0 +1,140 @@
+//! Parsing the errors plantuml reports on stderr, so they can be attributed to
+//! the input files they came from.
+//!
+//! Both the `-stdrpt:2` format (`<file>:<line>:<severity>:<message>`) and the
+//! default `Error line <line> in file: <file>` format are understood. Warnings
+//! are reported even when plantuml succeeds, e.g. when graphviz is missing.
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem plantuml reported with one of its input files
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Diagnostic {
+    /// Index of the input file the problem is in
+    pub input: usize,
+    /// Line within the diagram source
+    pub line: Option<usize>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+pub(crate) fn parse(stderr: &str, inputs: &[PathBuf]) -> Vec<Diagnostic> {
+    stderr
+        .lines()
+        .filter_map(|line| parse_line(line.trim(), inputs))
+        .collect()
+}
+
+fn parse_line(line: &str, inputs: &[PathBuf]) -> Option<Diagnostic> {
+    if let Some(rest) = line.strip_prefix("Error line ") {
+        let (number, file) = rest.split_once(" in file: ")?;
+        return Some(Diagnostic {
+            input: find_input(file, inputs)?,
+            line: number.trim().parse().ok(),
+            severity: Severity::Error,
+            message: "syntax error".to_owned(),
+        });
+    }
+
+    // `<file>:<line>:<severity>:<message>`, where the file may itself contain colons
+    let (location, severity, message) = if let Some((l, m)) = line.rsplit_once(":error:") {
+        (l, Severity::Error, m)
+    } else {
+        let (l, m) = line.rsplit_once(":warning:")?;
+        (l, Severity::Warning, m)
+    };
+    let (file, number) = location.rsplit_once(':')?;
+    Some(Diagnostic {
+        input: find_input(file, inputs)?,
+        line: number.trim().parse().ok(),
+        severity,
+        message: message.trim().to_owned(),
+    })
+}
+
+/// Whether an svg is one of plantuml's error images, which it can write
+/// without failing, e.g. for some syntax errors.
+pub(crate) fn is_error_image(svg: &str) -> bool {
+    ERROR_IMAGE_TEXT
+        .iter()
+        .any(|text| svg.contains(&format!(">{}</text>", text)))
+}
+
+/// Text that only appears in plantuml's error images
+const ERROR_IMAGE_TEXT: &[&str] = &["Syntax Error?", "An error has occured!"];
+
+fn find_input(file: &str, inputs: &[PathBuf]) -> Option<usize> {
+    let file = Path::new(file.trim());
+    // reports may only give the file name, which are unique within a batch
+    inputs.iter().position(|input| input == file).or_else(|| {
+        inputs
+            .iter()
+            .position(|input| input.file_name() == file.file_name())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_errors() {
+        let inputs = vec![
+            PathBuf::from("/tmp/x/a.puml"),
+            PathBuf::from("/tmp/x/b.puml"),
+        ];
+
+        let stderr = "/tmp/x/b.puml:3:error:Syntax Error?\nsome other output\n";
+        assert_eq!(
+            parse(stderr, &inputs),
+            vec![Diagnostic {
+                input: 1,
+                line: Some(3),
+                severity: Severity::Error,
+                message: "Syntax Error?".to_owned(),
+            }]
+        );
+
+        let stderr =
+            "Error line 2 in file: /tmp/x/a.puml\nSome diagram description contains errors\n";
+        assert_eq!(
+            parse(stderr, &inputs),
+            vec![Diagnostic {
+                input: 0,
+                line: Some(2),
+                severity: Severity::Error,
+                message: "syntax error".to_owned(),
+            }]
+        );
+
+        let stderr = "b.puml:1:warning:Cannot find Graphviz\n";
+        assert_eq!(
+            parse(stderr, &inputs),
+            vec![Diagnostic {
+                input: 1,
+                line: Some(1),
+                severity: Severity::Warning,
+                message: "Cannot find Graphviz".to_owned(),
+            }]
+        );
+
+        assert_eq!(parse("c.puml:1:error:Nope", &inputs), vec![]);
+    }
+
+    #[test]
+    fn error_images() {
+        assert!(is_error_image(
+            r##"<svg><text x="5" y="20">A -> </text><text fill="#FF0000" x="5" y="40">Syntax Error?</text></svg>"##
+        ));
+        // diagrams can still talk about syntax errors
+        assert!(!is_error_image(
+            r#"<svg><text x="5" y="20">Syntax Error? handling</text></svg>"#
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+//! Choosing the plantuml command to run from those in `plantuml-command`, and
+//! finding where plantuml might be installed when none of them are
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Places package managers install plantuml outside of the usual `PATH`
+const INSTALL_LOCATIONS: &[&str] = &[
+    "/opt/homebrew/bin/plantuml",
+    "/opt/homebrew/opt/plantuml/libexec/plantuml.jar",
+    "/usr/local/bin/plantuml",
+    "/usr/local/opt/plantuml/libexec/plantuml.jar",
+    "/home/linuxbrew/.linuxbrew/bin/plantuml",
+    "/snap/bin/plantuml",
+    "/usr/share/plantuml/plantuml.jar",
+    "/usr/share/java/plantuml.jar",
+    "C:\\ProgramData\\chocolatey\\bin\\plantuml.exe",
+    "C:\\ProgramData\\chocolatey\\lib\\plantuml\\tools\\plantuml.jar",
+];
+
+/// The first of the commands whose program can be found, with jars (relative to
+/// `root`) run by java. `None` if none of them can.
+pub(crate) fn resolve(commands: &[String], root: &Path) -> Option<String> {
+    resolve_in(commands, root, std::env::var_os("PATH"))
+}
+
+fn resolve_in(commands: &[String], root: &Path, path: Option<OsString>) -> Option<String> {
+    commands.iter().find_map(|command| {
+        let command = command.trim();
+        if command.ends_with(".jar") && !command.contains(char::is_whitespace) {
+            let jar = root.join(command);
+            return jar
+                .is_file()
+                .then(|| format!("java -jar {}", quote(&jar.to_string_lossy())));
+        }
+        // skip over environment variables set for the command
+        let program = command
+            .split_whitespace()
+            .find(|word| !word.contains('='))?;
+        find_program(program, path.as_deref()).map(|_| command.to_owned())
+    })
+}
+
+/// The file a command runs, or the jar for those run by java, to tell when it changes
+pub(crate) fn program(command: &str) -> Option<PathBuf> {
+    if let Some((_, jar)) = command.split_once("-jar ") {
+        let jar = jar.trim_start();
+        let jar = match jar.strip_prefix('\'') {
+            Some(quoted) => quoted.split('\'').next()?,
+            None => jar.split_whitespace().next()?,
+        };
+        return Some(jar.into());
+    }
+    let program = command
+        .split_whitespace()
+        .find(|word| !word.contains('='))?;
+    find_program(program, std::env::var_os("PATH").as_deref())
+}
+
+/// Why none of the commands could be run, listing where plantuml was found instead
+pub(crate) fn not_found(commands: &[String]) -> String {
+    not_found_in(commands, std::env::var_os("PATH"))
+}
+
+fn not_found_in(commands: &[String], path: Option<OsString>) -> String {
+    let mut message = String::new();
+    if !commands.is_empty() {
+        let tried: Vec<_> = commands.iter().map(|c| format!("`{}`", c)).collect();
+        message = format!("tried {}; ", tried.join(", "));
+    }
+    let candidates = candidates(path.as_deref());
+    if candidates.is_empty() {
+        message.push_str("install plantuml, or set `plantuml-command` to where it is");
+    } else {
+        let found: Vec<_> = candidates.iter().map(|c| c.display().to_string()).collect();
+        message.push_str(&format!(
+            "plantuml may be installed at {}, which `plantuml-command` can be set to",
+            found.join(", ")
+        ));
+    }
+    message
+}
+
+/// Files in `PATH` and the install locations that look like plantuml
+fn candidates(path: Option<&OsStr>) -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = path
+        .into_iter()
+        .flat_map(std::env::split_paths)
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("plantuml"))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    for location in INSTALL_LOCATIONS.iter().map(PathBuf::from) {
+        if location.is_file() && !candidates.contains(&location) {
+            candidates.push(location);
+        }
+    }
+    candidates
+}
+
+/// Where the program is, if it's a path to a file or in `PATH`
+fn find_program(program: &str, path: Option<&OsStr>) -> Option<PathBuf> {
+    if program.contains('/') || program.contains('\\') {
+        return Path::new(program).is_file().then(|| program.into());
+    }
+    path.into_iter()
+        .flat_map(std::env::split_paths)
+        .flat_map(|dir| [dir.join(program), dir.join(program).with_extension("exe")])
+        .find(|file| file.is_file())
+}
+
+/// Quotes a path for the shell the commands are run by
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolved() {
+        let root = tempfile::TempDir::new().unwrap();
+        let bin = root.path().join("bin");
+        std::fs::create_dir(&bin).unwrap();
+        std::fs::write(bin.join("render-diagrams"), "").unwrap();
+        std::fs::write(bin.join("plantuml-1.2024.3.jar"), "").unwrap();
+        std::fs::write(root.path().join("plantuml.jar"), "").unwrap();
+        let path = Some(bin.clone().into_os_string());
+
+        let commands =
+            |commands: &[&str]| -> Vec<String> { commands.iter().map(|c| c.to_string()).collect() };
+        let resolved = resolve_in(
+            &commands(&["missing-plantuml", "JAVA_OPTS=-Xmx1g render-diagrams -v"]),
+            root.path(),
+            path.clone(),
+        );
+        assert_eq!(
+            resolved.as_deref(),
+            Some("JAVA_OPTS=-Xmx1g render-diagrams -v")
+        );
+        let resolved = resolve_in(
+            &commands(&["missing-plantuml", "plantuml.jar"]),
+            root.path(),
+            path.clone(),
+        );
+        let jar = root.path().join("plantuml.jar");
+        let resolved = resolved.unwrap();
+        assert_eq!(resolved, format!("java -jar '{}'", jar.display()));
+        assert_eq!(program(&resolved), Some(jar));
+        assert_eq!(
+            program("JAVA_OPTS=-Xmx1g sh -c true"),
+            find_program("sh", std::env::var_os("PATH").as_deref())
+        );
+
+        let missing = commands(&["missing-plantuml", "missing.jar"]);
+        assert_eq!(resolve_in(&missing, root.path(), path.clone()), None);
+        let message = not_found_in(&missing, path);
+        assert!(message.starts_with("tried `missing-plantuml`, `missing.jar`; "));
+        assert!(message.contains(&bin.join("plantuml-1.2024.3.jar").display().to_string()));
+    }
+}
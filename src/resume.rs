@@ -0,0 +1,75 @@
+//! Resuming renders that were interrupted.
+//!
+//! With `resumable` set, rendered images are staged in the book's src dir
+//! rather than a temporary one, along with the plan of what is left to render.
+//! A render that was killed, or failed in transactional mode, picks up from the
+//! images already staged.
+
+use crate::manifest::write_json;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Directory in the book src holding the progress of a render
+pub(crate) const RESUME_DIR: &str = ".plantuml-resume";
+
+const PLAN: &str = "plan.json";
+
+/// The diagrams of the render, by their status
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Progress {
+    pub rendered: BTreeSet<Uuid>,
+    pub pending: BTreeSet<Uuid>,
+    pub failed: BTreeSet<Uuid>,
+}
+
+impl Progress {
+    /// The progress left by an earlier render, if any
+    pub fn read(dir: &Path) -> Option<Self> {
+        let file = std::fs::read(dir.join(PLAN)).ok()?;
+        serde_json::from_slice(&file).ok()
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        // renamed into place, so an interruption never leaves half a plan
+        let partial = dir.join(format!("{}.partial", PLAN));
+        write_json(&partial, self)?;
+        std::fs::rename(partial, dir.join(PLAN))?;
+        Ok(())
+    }
+
+    pub fn finish(&mut self, output: Uuid, rendered: bool) {
+        self.pending.remove(&output);
+        if rendered {
+            self.rendered.insert(output);
+        } else {
+            self.failed.insert(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(Progress::read(dir.path()), None);
+
+        let mut progress = Progress::default();
+        progress
+            .pending
+            .extend([Uuid::from_u128(1), Uuid::from_u128(2)]);
+        progress.finish(Uuid::from_u128(1), true);
+        progress.finish(Uuid::from_u128(2), false);
+        progress.write(dir.path()).unwrap();
+
+        let read = Progress::read(dir.path()).unwrap();
+        assert_eq!(read, progress);
+        assert!(read.pending.is_empty());
+        assert_eq!(read.failed.len(), 1);
+    }
+}
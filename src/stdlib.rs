@@ -0,0 +1,237 @@
+//! Pinning the plantuml stdlib to a vendored copy.
+//!
+//! `!include <aws/common>` reads from the stdlib bundled into plantuml, which
+//! changes between releases. With `stdlib` set, those includes read from a vendored
+//! copy instead, such as a checkout of plantuml-stdlib at a tag. Plantuml always
+//! resolves `<...>` from its own copy, so the files a diagram includes are staged
+//! next to it, with their `<...>` includes rewritten to point at the staged copies.
+
+use crate::pure::INCLUDE_DIRECTIVES;
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+
+/// Directory the included stdlib files are staged in, within the work dir
+const STAGED_STDLIB: &str = "stdlib";
+
+/// A vendored copy of the plantuml stdlib
+#[derive(Debug)]
+pub(crate) struct Stdlib {
+    dir: PathBuf,
+}
+
+impl Stdlib {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        ensure!(
+            dir.is_dir(),
+            "the pinned stdlib {} is not a directory",
+            dir.display()
+        );
+        Ok(Stdlib { dir })
+    }
+
+    /// The stdlib files the source includes, directly or through other stdlib files,
+    /// by their path within the stdlib
+    fn files(&self, source: &str) -> Result<BTreeMap<PathBuf, String>> {
+        let mut files = BTreeMap::new();
+        let mut queue: Vec<_> = source
+            .lines()
+            .filter_map(|line| stdlib_include(include(line)?.1))
+            .map(|(name, _)| stdlib_file(name))
+            .collect();
+        while let Some(path) = queue.pop() {
+            if files.contains_key(&path) {
+                continue;
+            }
+            let file = self.dir.join(&path);
+            let contents = std::fs::read_to_string(&file).with_context(|| {
+                format!(
+                    "{} is not in the pinned stdlib at {}",
+                    path.display(),
+                    self.dir.display()
+                )
+            })?;
+            for (_, arg) in contents.lines().filter_map(include) {
+                match stdlib_include(arg) {
+                    Some((name, _)) => queue.push(stdlib_file(name)),
+                    // the stdlib's own files include each other by relative paths too
+                    None if !arg.contains("://") => {
+                        let relative = arg.split('!').next().unwrap_or(arg).trim_matches('"');
+                        let dir = path.parent().unwrap_or(Path::new(""));
+                        queue.push(resolve(dir, relative)?);
+                    }
+                    None => {}
+                }
+            }
+            files.insert(path, contents);
+        }
+        Ok(files)
+    }
+
+    /// Identifies a diagram's image by the stdlib files it includes as well, so it is
+    /// rendered again when the pin moves. Diagrams without stdlib includes keep their id.
+    pub fn pin(&self, output: Uuid, source: &str) -> Uuid {
+        let files = match self.files(source) {
+            Ok(files) => files,
+            Err(err) => {
+                // rendering the diagram fails with the same error
+                debug!(
+                    "could not read the stdlib includes of {}: {:#}",
+                    output, err
+                );
+                return output;
+            }
+        };
+        if files.is_empty() {
+            return output;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(output.as_bytes());
+        for (path, contents) in &files {
+            hasher.write_u8(0xff);
+            hasher.write(path.to_string_lossy().as_bytes());
+            hasher.write_u8(0);
+            hasher.write(contents.as_bytes());
+        }
+
+        let lhs = hasher.finish() as u128;
+        hasher.write_u8(0);
+        let rhs = hasher.finish() as u128;
+        Uuid::from_u128(lhs << 64 | rhs)
+    }
+
+    /// Stages the stdlib files the source includes in `dir`, returning the source
+    /// with its `<...>` includes pointed at them
+    pub fn stage(&self, source: &str, dir: &Path) -> Result<String> {
+        let files = self.files(source)?;
+        if files.is_empty() {
+            return Ok(source.to_owned());
+        }
+        let staged = dir.join(STAGED_STDLIB);
+        for (path, contents) in &files {
+            let file = staged.join(path);
+            std::fs::create_dir_all(file.parent().unwrap_or(&staged))?;
+            std::fs::write(&file, rewrite(contents, &staged))
+                .with_context(|| format!("could not stage {}", file.display()))?;
+        }
+        Ok(rewrite(source, &staged))
+    }
+}
+
+/// The directive and argument of an include line
+fn include(line: &str) -> Option<(&str, &str)> {
+    let (directive, arg) = line.trim().split_once(char::is_whitespace)?;
+    INCLUDE_DIRECTIVES
+        .contains(&directive)
+        .then(|| (directive, arg.trim()))
+}
+
+/// The name of a `<...>` include, and whatever follows it, eg `!BLOCK`
+fn stdlib_include(arg: &str) -> Option<(&str, &str)> {
+    let rest = arg.strip_prefix('<')?;
+    let end = rest.find('>')?;
+    Some((&rest[..end], &rest[end + 1..]))
+}
+
+/// The file of a stdlib include, which usually leaves off the extension
+fn stdlib_file(name: &str) -> PathBuf {
+    if name.ends_with(".puml") {
+        PathBuf::from(name)
+    } else {
+        PathBuf::from(format!("{}.puml", name))
+    }
+}
+
+/// Resolves a relative include within the stdlib
+fn resolve(dir: &Path, include: &str) -> Result<PathBuf> {
+    let mut resolved = dir.to_owned();
+    for component in Path::new(include).components() {
+        match component {
+            Component::Normal(c) => resolved.push(c),
+            Component::ParentDir if resolved.pop() => {}
+            Component::CurDir => {}
+            _ => bail!("{} is outside of the pinned stdlib", include),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Points the `<...>` includes of the source at the staged stdlib files
+fn rewrite(source: &str, staged: &Path) -> String {
+    let mut rewritten = String::with_capacity(source.len());
+    for line in source.split_inclusive('\n') {
+        let stdlib =
+            include(line).and_then(|(directive, arg)| Some((directive, stdlib_include(arg)?)));
+        match stdlib {
+            Some((directive, (name, rest))) => {
+                let file = staged.join(stdlib_file(name));
+                rewritten.push_str(&format!("{} {}{}", directive, file.display(), rest));
+                if line.ends_with('\n') {
+                    rewritten.push('\n');
+                }
+            }
+            None => rewritten.push_str(line),
+        }
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned() {
+        let vendored = tempfile::TempDir::new().unwrap();
+        let aws = vendored.path().join("aws");
+        std::fs::create_dir_all(aws.join("icons")).unwrap();
+        std::fs::write(
+            aws.join("common.puml"),
+            "!include <aws/base>\n!include icons/server.puml\n",
+        )
+        .unwrap();
+        std::fs::write(aws.join("base.puml"), "skinparam shadowing false\n").unwrap();
+        std::fs::write(
+            aws.join("icons/server.puml"),
+            "sprite $server [1x1/16] {\n0\n}\n",
+        )
+        .unwrap();
+        let stdlib = Stdlib::new(vendored.path().to_owned()).unwrap();
+
+        let source = "@startuml\n!include <aws/common>\nA -> B\n@enduml\n";
+        let work = tempfile::TempDir::new().unwrap();
+        let staged = work.path().join(STAGED_STDLIB);
+        assert_eq!(
+            stdlib.stage(source, work.path()).unwrap(),
+            format!(
+                "@startuml\n!include {}\nA -> B\n@enduml\n",
+                staged.join("aws/common.puml").display()
+            )
+        );
+        assert_eq!(
+            std::fs::read_to_string(staged.join("aws/common.puml")).unwrap(),
+            format!(
+                "!include {}\n!include icons/server.puml\n",
+                staged.join("aws/base.puml").display()
+            )
+        );
+        assert!(staged.join("aws/icons/server.puml").exists());
+
+        // the id only changes for diagrams with stdlib includes, when the files do
+        let output = Uuid::from_u128(1);
+        assert_eq!(stdlib.pin(output, "@startuml\nA -> B\n@enduml\n"), output);
+        let pinned = stdlib.pin(output, source);
+        assert_ne!(pinned, output);
+        assert_eq!(stdlib.pin(output, source), pinned);
+        std::fs::write(aws.join("base.puml"), "skinparam shadowing true\n").unwrap();
+        assert_ne!(stdlib.pin(output, source), pinned);
+
+        assert!(stdlib
+            .stage("!include <gcp/common>\n", work.path())
+            .is_err());
+    }
+}
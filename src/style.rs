@@ -0,0 +1,206 @@
+//! Normalising the style of diagram sources, for `mdbook-puml fmt`.
+//!
+//! Blocks are indented by their nesting, arrows get a space either side, and
+//! directives are lowercased. Formatting is idempotent, and anything it doesn't
+//! understand is left as written.
+
+use crate::markdown::replace_pumls;
+
+const INDENT: &str = "  ";
+
+/// Keywords opening a block
+const OPENERS: &[&str] = &[
+    "alt", "opt", "loop", "par", "par2", "break", "critical", "group", "box", "if", "while",
+    "fork", "split",
+];
+
+/// Keywords closing a block
+const CLOSERS: &[&str] = &["end", "endif", "endwhile"];
+
+/// Lines that separate the branches of a block, dedented to its level
+const BRANCHES: &[&str] = &["else", "elseif", "fork again", "split again"];
+
+/// Formats every diagram in a markdown file. Ignored blocks are left alone.
+pub fn format_markdown(markdown: &str) -> String {
+    replace_pumls(markdown, |puml| {
+        if puml.ignore {
+            return markdown[puml.start..puml.end].to_owned();
+        }
+        format!(
+            "```plantuml{}\n{}```",
+            puml.info,
+            format_diagram(puml.contents)
+        )
+    })
+}
+
+/// Formats the source of a diagram
+pub fn format_diagram(source: &str) -> String {
+    let mut formatted = String::with_capacity(source.len());
+    let mut depth = 0usize;
+    // inside a multi-line note, whose lines are only reindented
+    let mut in_note = false;
+
+    for line in source.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            formatted.push('\n');
+            continue;
+        }
+
+        let line = lowercase_directive(line);
+        // messages are never block keywords, even from a participant called `if`
+        let message = if in_note { None } else { space_arrow(&line) };
+        let is_message = message.is_some();
+        let line = message.unwrap_or(line);
+        let keyword = line.split_whitespace().next().unwrap_or("");
+
+        let closes = if in_note {
+            line == "end note" || line == "endnote"
+        } else {
+            !is_message && (line.starts_with('}') || CLOSERS.contains(&keyword))
+        };
+        let branch = !in_note
+            && !is_message
+            && BRANCHES
+                .iter()
+                .any(|b| line == *b || line.starts_with(&format!("{} ", b)));
+        if closes {
+            depth = depth.saturating_sub(1);
+            in_note = false;
+        }
+
+        let level = if branch {
+            depth.saturating_sub(1)
+        } else {
+            depth
+        };
+        for _ in 0..level {
+            formatted.push_str(INDENT);
+        }
+        formatted.push_str(&line);
+        formatted.push('\n');
+
+        if !in_note && !is_message && !closes && !branch {
+            let opens_note = matches!(keyword, "note" | "hnote" | "rnote") && !line.contains(':');
+            if opens_note {
+                in_note = true;
+                depth += 1;
+            } else if line.ends_with('{') || OPENERS.contains(&keyword) {
+                depth += 1;
+            }
+        }
+    }
+    formatted
+}
+
+/// Lowercases `!include`, `@startuml` and the like
+fn lowercase_directive(line: &str) -> String {
+    if !line.starts_with(['!', '@']) {
+        return line.to_owned();
+    }
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    format!("{}{}", line[..end].to_lowercase(), &line[end..])
+}
+
+/// Formats `A->B:hello` as `A -> B : hello`, if the line is a message
+fn space_arrow(line: &str) -> Option<String> {
+    let (message, label) = match line.split_once(':') {
+        Some((message, label)) => (message, Some(label.trim())),
+        None => (line, None),
+    };
+    if message.contains('"') {
+        return None;
+    }
+
+    let is_arrow = |c: char| "-<>.\\/|*".contains(c);
+    let start = message.find(is_arrow)?;
+    let end = message[start..]
+        .find(|c: char| !is_arrow(c))
+        .map_or(message.len(), |i| start + i);
+    let arrow = &message[start..end];
+    let (from, to) = (message[..start].trim(), message[end..].trim());
+
+    // only sequence style messages, between two participants
+    let valid = (arrow.contains('>') || arrow.contains('<'))
+        && (arrow.contains('-') || arrow.contains('.'))
+        && !from.is_empty()
+        && !to.is_empty()
+        && !from.contains(char::is_whitespace)
+        && !to.contains(char::is_whitespace);
+    if !valid {
+        return None;
+    }
+
+    Some(match label {
+        Some(label) => format!("{} {} {} : {}", from, arrow, to, label),
+        None => format!("{} {} {}", from, arrow, to),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let source = "@StartUml Login
+Participant Browser
+!INCLUDE style.puml
+Browser->Server:login
+    alt ok
+Server-->Browser : cookie
+  else failed
+Server-->>Browser
+end
+note over Server
+   keeps a log
+end note
+package a {
+class Foo
+}
+@enduml
+";
+        let expected = "@startuml Login
+Participant Browser
+!include style.puml
+Browser -> Server : login
+alt ok
+  Server --> Browser : cookie
+else failed
+  Server -->> Browser
+end
+note over Server
+  keeps a log
+end note
+package a {
+  class Foo
+}
+@enduml
+";
+        assert_eq!(format_diagram(source), expected);
+        assert_eq!(format_diagram(expected), expected);
+
+        let activity = "@startuml\nstart\nif (ok?) then (yes)\n:work;\nelse (no)\nfork\n:a;\nfork again\n:b;\nend fork\nendif\nstop\n@enduml\n";
+        let expected = "@startuml\nstart\nif (ok?) then (yes)\n  :work;\nelse (no)\n  fork\n    :a;\n  fork again\n    :b;\n  end fork\nendif\nstop\n@enduml\n";
+        assert_eq!(format_diagram(activity), expected);
+    }
+
+    #[test]
+    fn messages_are_not_keywords() {
+        // found by fuzzing, the spaced message was an opener when formatted again
+        let source = "if->B\nA -> B\nend->A\n";
+        let expected = "if -> B\nA -> B\nend -> A\n";
+        assert_eq!(format_diagram(source), expected);
+        assert_eq!(format_diagram(expected), expected);
+    }
+
+    #[test]
+    fn markdown() {
+        let s = "# A\n\n```plantuml\nA->B\n```\n\n```plantuml,ignore\nA->B\n```\n";
+        assert_eq!(
+            format_markdown(s),
+            "# A\n\n```plantuml\nA -> B\n```\n\n```plantuml,ignore\nA->B\n```\n"
+        );
+    }
+}
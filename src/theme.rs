@@ -0,0 +1,89 @@
+//! Diagram metadata for mdbook themes.
+//!
+//! The files are written next to the images in the src dir, which mdbook copies
+//! into the book output, so themes can load them to build figure navigation.
+
+use crate::compiler::url_path;
+use crate::Target;
+use serde::{Deserialize, Serialize};
+
+pub(crate) const DIAGRAMS_JSON: &str = "diagrams.json";
+pub(crate) const DIAGRAMS_JS: &str = "diagrams.js";
+pub(crate) const HIGHLIGHT_JS: &str = "highlight-plantuml.js";
+
+/// A highlight.js grammar for plantuml sources. mdbook has already highlighted the
+/// page by the time `additional-js` runs, so it highlights the blocks again itself.
+pub(crate) const HIGHLIGHT_GRAMMAR: &str = r#"// highlight.js grammar for plantuml, generated by mdbook-puml
+(function () {
+  if (typeof hljs === "undefined") return;
+  hljs.registerLanguage("plantuml", function (hljs) {
+    return {
+      aliases: ["puml"],
+      keywords: {
+        keyword:
+          "participant actor boundary control entity database collections queue " +
+          "class interface enum abstract package namespace node component usecase state object " +
+          "note over of left right top bottom end alt else opt loop par break critical group box " +
+          "if then elseif endif while endwhile repeat fork again split start stop " +
+          "title legend endlegend header footer skinparam as activate deactivate return ref " +
+          "autonumber newpage",
+        literal: "true false",
+      },
+      contains: [
+        { className: "meta", begin: /^\s*@(start|end)\w+/ },
+        { className: "meta", begin: /^\s*!\w+/ },
+        hljs.COMMENT("/'", "'/"),
+        hljs.COMMENT("^\\s*'", "$"),
+        hljs.QUOTE_STRING_MODE,
+        { className: "symbol", begin: /<{1,2}[-.]+>{0,2}|[-.]+(\[[^\]]*\][-.]+)?>{1,2}/ },
+        { className: "string", begin: /:/, end: /$/, excludeBegin: true },
+      ],
+    };
+  });
+  var highlight = hljs.highlightElement || hljs.highlightBlock;
+  document
+    .querySelectorAll("code.language-plantuml, code.language-puml")
+    .forEach(function (block) {
+      highlight.call(hljs, block);
+    });
+})();
+"#;
+
+/// A figure, as seen by the theme. Paths are urls relative to the book root.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ThemeFigure {
+    /// Anchor of the figure within its chapter
+    pub id: Option<String>,
+    /// Url of the chapter page
+    pub chapter: String,
+    pub caption: Option<String>,
+    /// Url of the image
+    pub image: String,
+    pub kind: String,
+    pub tags: Vec<String>,
+}
+
+impl From<&Target> for ThemeFigure {
+    fn from(target: &Target) -> Self {
+        ThemeFigure {
+            id: target.id.clone(),
+            chapter: url_path(&target.chapter.with_extension("html")),
+            caption: target.name.clone().filter(|_| !target.decorative),
+            image: url_path(&target.image()),
+            kind: target.kind.clone(),
+            tags: target.tags.clone(),
+        }
+    }
+}
+
+pub(crate) fn figures(results: &[Target]) -> Vec<ThemeFigure> {
+    results.iter().map(ThemeFigure::from).collect()
+}
+
+/// A script defining `window.plantumlDiagrams`, for themes to add to `additional-js`
+pub(crate) fn script(figures: &[ThemeFigure]) -> serde_json::Result<String> {
+    Ok(format!(
+        "// metadata of every plantuml diagram in the book, generated by mdbook-puml\nwindow.plantumlDiagrams = {};\n",
+        serde_json::to_string(figures)?
+    ))
+}
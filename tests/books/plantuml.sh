@@ -0,0 +1,11 @@
+# A stand-in for the plantuml cli, which writes an empty image
+# using the same output naming rules
+[ "$1" = -version ] && { echo "PlantUML version stub"; exit 0; }
+for arg; do [ "$arg" = -pipe ] && { echo '<svg/>'; exit 0; }; done
+for arg; do case "$arg" in -ttxt) ext=atxt;; -t*) ext="${arg#-t}";; esac; done
+for input; do
+    case "$input" in -*) continue;; esac
+    name=$(sed -n '1s/^@start[a-z]* //p' "$input")
+    [ -n "$name" ] || name=$(basename "$input" .puml)
+    echo '<svg/>' > "$(dirname "$input")/$name.$ext"
+done
@@ -0,0 +1,143 @@
+//! Builds the books in `tests/books` end to end, with the preprocessor binary and a
+//! stub plantuml, and compares the output with each book's `expected` directory.
+//!
+//! Set `UPDATE_GOLDEN=1` to write the output as the new expectation.
+
+use mdbook::{Config, MDBook};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+fn books() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/books")
+}
+
+/// Every file under `dir`, by its path relative to `root`
+fn walk(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            walk(root, &path, files);
+        } else {
+            files.push(path.strip_prefix(root).unwrap().to_owned());
+        }
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    let mut files = vec![];
+    walk(from, from, &mut files);
+    for file in files {
+        let to = to.join(&file);
+        std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+        std::fs::copy(from.join(&file), to).unwrap();
+    }
+}
+
+fn slash_path(file: &Path) -> String {
+    file.to_str().unwrap().replace('\\', "/")
+}
+
+/// The parts of the output that are compared: chapters from the markdown renderer,
+/// the chapters of the html renderer's print page, and the names of the images
+fn output(build_dir: &Path, src_dir: &Path) -> BTreeMap<String, String> {
+    let mut output = BTreeMap::new();
+
+    let mut images = vec![];
+    let outdir = src_dir.join("plantuml_images");
+    walk(&outdir, &outdir, &mut images);
+    images.sort();
+    let images: String = images.iter().map(|file| slash_path(file) + "\n").collect();
+    output.insert("plantuml_images.txt".to_owned(), images);
+
+    let mut files = vec![];
+    walk(build_dir, build_dir, &mut files);
+    for file in files {
+        let name = slash_path(&file);
+        if name.ends_with(".md") {
+            let contents = std::fs::read_to_string(build_dir.join(&file)).unwrap();
+            output.insert(name, contents);
+        } else if name == "print.html" {
+            let contents = std::fs::read_to_string(build_dir.join(&file)).unwrap();
+            let start = contents.find("<main>").unwrap();
+            let end = contents.find("</main>").unwrap() + "</main>".len();
+            output.insert(name, format!("{}\n", &contents[start..end]));
+        }
+    }
+    output
+}
+
+fn check(name: &str) {
+    let fixture = books().join(name);
+    let root = TempDir::new().unwrap();
+    copy_dir(&fixture.join("src"), &root.path().join("src"));
+    std::fs::copy(fixture.join("book.toml"), root.path().join("book.toml")).unwrap();
+
+    let mut config = Config::from_disk(root.path().join("book.toml")).unwrap();
+    config
+        .set(
+            "preprocessor.puml.command",
+            env!("CARGO_BIN_EXE_mdbook-puml"),
+        )
+        .unwrap();
+    let stub = format!("sh {}", books().join("plantuml.sh").display());
+    config
+        .set("preprocessor.puml.plantuml-command", stub)
+        .unwrap();
+    let book = MDBook::load_with_config(root.path(), config).unwrap();
+    book.build().unwrap();
+
+    let actual = output(&root.path().join("book"), &book.source_dir());
+    let expected_dir = fixture.join("expected");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        let _ = std::fs::remove_dir_all(&expected_dir);
+        for (file, contents) in &actual {
+            let path = expected_dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        return;
+    }
+
+    let mut files = vec![];
+    walk(&expected_dir, &expected_dir, &mut files);
+    let expected: BTreeMap<_, _> = files
+        .into_iter()
+        .map(|file| {
+            let contents = std::fs::read_to_string(expected_dir.join(&file)).unwrap();
+            (slash_path(&file), contents)
+        })
+        .collect();
+    assert_eq!(
+        actual.keys().collect::<Vec<_>>(),
+        expected.keys().collect::<Vec<_>>(),
+        "files of {}",
+        name
+    );
+    for (file, contents) in &expected {
+        assert_eq!(&actual[file], contents, "{}/{}", name, file);
+    }
+}
+
+#[test]
+fn nested() {
+    check("nested");
+}
+
+#[test]
+fn drafts() {
+    check("drafts");
+}
+
+#[test]
+fn i18n() {
+    check("i18n");
+}
+
+#[test]
+fn print() {
+    check("print");
+}